@@ -1,5 +1,6 @@
 # module
-from collections.abc import AsyncGenerator, Iterable
+import datetime
+from collections.abc import AsyncGenerator, Iterable, Sequence
 from typing import Any, Self
 
 from zarr.abc.store import ByteRangeRequest, Store
@@ -15,6 +16,7 @@ from ._icechunk_python import (
     StorageConfig,
     StoreConfig,
     VirtualRefConfig,
+    VirtualRefIntegrity,
     __version__,
     pyicechunk_store_create,
     pyicechunk_store_exists,
@@ -30,6 +32,7 @@ __all__ = [
     "SnapshotMetadata",
     "StoreConfig",
     "VirtualRefConfig",
+    "VirtualRefIntegrity",
 ]
 
 
@@ -373,6 +376,14 @@ class IcechunkStore(Store, SyncMixin):
         """Return True if there are uncommitted changes to the store"""
         return self._store.has_uncommitted_changes
 
+    def cache_stats(self) -> dict[str, dict[str, int]]:
+        """Return the number of hits and misses of the in memory caches of the store.
+
+        The result maps each cache ("snapshots", "manifests", "attributes" and "chunks")
+        to a dictionary with its "hits" and "misses" counts.
+        """
+        return self._store.cache_stats()
+
     async def async_reset(self) -> None:
         """Discard any uncommitted changes and reset to the previous snapshot state."""
         return await self._store.async_reset()
@@ -553,7 +564,14 @@ class IcechunkStore(Store, SyncMixin):
         return await self._store.set_if_not_exists(key, value.to_bytes())
 
     async def async_set_virtual_ref(
-        self, key: str, location: str, *, offset: int, length: int
+        self,
+        key: str,
+        location: str,
+        *,
+        offset: int,
+        length: int,
+        etag: str | None = None,
+        last_modified: datetime.datetime | None = None,
     ) -> None:
         """Store a virtual reference to a chunk.
 
@@ -567,11 +585,24 @@ class IcechunkStore(Store, SyncMixin):
             The offset in bytes from the start of the file location in storage the chunk starts at
         length : int
             The length of the chunk in bytes, measured from the given offset
+        etag : str | None
+            The ETag of the object at location, reads fail if the object changes
+        last_modified : datetime.datetime | None
+            Reads fail if the object at location is modified after this time
         """
-        return await self._store.async_set_virtual_ref(key, location, offset, length)
+        return await self._store.async_set_virtual_ref(
+            key, location, offset, length, etag, last_modified
+        )
 
     def set_virtual_ref(
-        self, key: str, location: str, *, offset: int, length: int
+        self,
+        key: str,
+        location: str,
+        *,
+        offset: int,
+        length: int,
+        etag: str | None = None,
+        last_modified: datetime.datetime | None = None,
     ) -> None:
         """Store a virtual reference to a chunk.
 
@@ -585,8 +616,102 @@ class IcechunkStore(Store, SyncMixin):
             The offset in bytes from the start of the file location in storage the chunk starts at
         length : int
             The length of the chunk in bytes, measured from the given offset
+        etag : str | None
+            The ETag of the object at location, reads fail if the object changes
+        last_modified : datetime.datetime | None
+            Reads fail if the object at location is modified after this time
+        """
+        return self._store.set_virtual_ref(
+            key, location, offset, length, etag, last_modified
+        )
+
+    async def async_set_virtual_refs(
+        self,
+        array_path: str,
+        chunk_coords: Sequence[Sequence[int]],
+        locations: Sequence[str],
+        offsets: Sequence[int],
+        lengths: Sequence[int],
+        *,
+        etags: Sequence[str | None] | None = None,
+        last_modified: Sequence[datetime.datetime | None] | None = None,
+    ) -> int:
+        """Store many virtual references to chunks of the same array.
+
+        All the references are validated before any is stored, and the store is locked
+        only once, which is much faster than calling `set_virtual_ref` for each chunk.
+        The arguments are columns of the same length, the i-th reference points chunk
+        `chunk_coords[i]` to `lengths[i]` bytes of `locations[i]` starting at `offsets[i]`.
+
+        Parameters
+        ----------
+        array_path : str
+            The path of the array in the store eg: 'group/array'
+        chunk_coords : Sequence[Sequence[int]]
+            The coordinates of each chunk in the chunk grid, eg: [(0, 0), (0, 1)]
+        locations : Sequence[str]
+            The absolute location of each chunk in storage eg: 's3://bucket/path/to/file.nc'
+        offsets : Sequence[int]
+            The offset in bytes from the start of the file each chunk starts at
+        lengths : Sequence[int]
+            The length of each chunk in bytes, measured from the given offset
+        etags : Sequence[str | None] | None
+            The ETag each object must still have when the chunk is read, None to not pin it
+        last_modified : Sequence[datetime.datetime | None] | None
+            Reads fail if the object is modified after this time, None to not pin it
+
+        Returns
+        -------
+        int
+            The number of references stored
+        """
+        return await self._store.async_set_virtual_refs(
+            array_path, chunk_coords, locations, offsets, lengths, etags, last_modified
+        )
+
+    def set_virtual_refs(
+        self,
+        array_path: str,
+        chunk_coords: Sequence[Sequence[int]],
+        locations: Sequence[str],
+        offsets: Sequence[int],
+        lengths: Sequence[int],
+        *,
+        etags: Sequence[str | None] | None = None,
+        last_modified: Sequence[datetime.datetime | None] | None = None,
+    ) -> int:
+        """Store many virtual references to chunks of the same array.
+
+        All the references are validated before any is stored, and the store is locked
+        only once, which is much faster than calling `set_virtual_ref` for each chunk.
+        The arguments are columns of the same length, the i-th reference points chunk
+        `chunk_coords[i]` to `lengths[i]` bytes of `locations[i]` starting at `offsets[i]`.
+
+        Parameters
+        ----------
+        array_path : str
+            The path of the array in the store eg: 'group/array'
+        chunk_coords : Sequence[Sequence[int]]
+            The coordinates of each chunk in the chunk grid, eg: [(0, 0), (0, 1)]
+        locations : Sequence[str]
+            The absolute location of each chunk in storage eg: 's3://bucket/path/to/file.nc'
+        offsets : Sequence[int]
+            The offset in bytes from the start of the file each chunk starts at
+        lengths : Sequence[int]
+            The length of each chunk in bytes, measured from the given offset
+        etags : Sequence[str | None] | None
+            The ETag each object must still have when the chunk is read, None to not pin it
+        last_modified : Sequence[datetime.datetime | None] | None
+            Reads fail if the object is modified after this time, None to not pin it
+
+        Returns
+        -------
+        int
+            The number of references stored
         """
-        return self._store.set_virtual_ref(key, location, offset, length)
+        return self._store.set_virtual_refs(
+            array_path, chunk_coords, locations, offsets, lengths, etags, last_modified
+        )
 
     async def delete(self, key: str) -> None:
         """Remove a key from the store
@@ -1,6 +1,6 @@
 import abc
 import datetime
-from collections.abc import AsyncGenerator
+from collections.abc import AsyncGenerator, Sequence
 from typing import Any
 
 class PyIcechunkStore:
@@ -28,6 +28,7 @@ class PyIcechunkStore:
     async def async_commit(self, message: str) -> str: ...
     @property
     def has_uncommitted_changes(self) -> bool: ...
+    def cache_stats(self) -> dict[str, dict[str, int]]: ...
     def reset(self) -> None: ...
     async def async_reset(self) -> None: ...
     def new_branch(self, branch_name: str) -> str: ...
@@ -55,11 +56,43 @@ class PyIcechunkStore:
     async def set(self, key: str, value: bytes) -> None: ...
     async def set_if_not_exists(self, key: str, value: bytes) -> None: ...
     def set_virtual_ref(
-        self, key: str, location: str, offset: int, length: int
+        self,
+        key: str,
+        location: str,
+        offset: int,
+        length: int,
+        etag: str | None = None,
+        last_modified: datetime.datetime | None = None,
     ) -> None: ...
     async def async_set_virtual_ref(
-        self, key: str, location: str, offset: int, length: int
+        self,
+        key: str,
+        location: str,
+        offset: int,
+        length: int,
+        etag: str | None = None,
+        last_modified: datetime.datetime | None = None,
     ) -> None: ...
+    def set_virtual_refs(
+        self,
+        array_path: str,
+        chunk_coords: Sequence[Sequence[int]],
+        locations: Sequence[str],
+        offsets: Sequence[int],
+        lengths: Sequence[int],
+        etags: Sequence[str | None] | None = None,
+        last_modified: Sequence[datetime.datetime | None] | None = None,
+    ) -> int: ...
+    async def async_set_virtual_refs(
+        self,
+        array_path: str,
+        chunk_coords: Sequence[Sequence[int]],
+        locations: Sequence[str],
+        offsets: Sequence[int],
+        lengths: Sequence[int],
+        etags: Sequence[str | None] | None = None,
+        last_modified: Sequence[datetime.datetime | None] | None = None,
+    ) -> int: ...
     async def delete(self, key: str) -> None: ...
     @property
     def supports_partial_writes(self) -> bool: ...
@@ -204,6 +237,11 @@ class VirtualRefConfig:
         allow_http: bool | None
         region: str | None
 
+    class S3PerPrefix:
+        """A different S3 config for the virtual references under each url prefix"""
+
+        prefixes: dict[str, VirtualRefConfig]
+
     @classmethod
     def s3_from_env(cls) -> VirtualRefConfig:
         """Create a VirtualReferenceConfig object for an S3 Object Storage compatible storage backend
@@ -249,6 +287,31 @@ class VirtualRefConfig:
         """
         ...
 
+    @classmethod
+    def s3_per_prefix(
+        cls,
+        prefixes: dict[str, VirtualRefConfig],
+    ) -> VirtualRefConfig:
+        """Create a VirtualReferenceConfig object that uses a different S3 configuration for
+        the virtual references under each url prefix
+
+        Keys are url prefixes like `s3://bucket/path/`, or bucket names. The longest
+        matching prefix is used. An empty prefix matches every location, references that
+        don't match any prefix use the configuration from the environment.
+        """
+        ...
+
+class VirtualRefIntegrity:
+    """What to do when the object of a virtual reference was modified after the
+    reference was set, according to its `etag` or `last_modified`"""
+
+    # Fail the read
+    Strict: VirtualRefIntegrity
+    # Log a warning and return the current contents of the object
+    Warn: VirtualRefIntegrity
+    # Don't check the etag or last modification time
+    Off: VirtualRefIntegrity
+
 class KeyNotFound(Exception):
     def __init__(
         self,
@@ -268,6 +331,21 @@ class StoreConfig:
     unsafe_overwrite_refs: bool | None
     # Configurations for virtual references such as credentials and endpoints
     virtual_ref_config: VirtualRefConfig | None
+    # Maximum size in bytes of the in memory caches of snapshots, manifests, attributes
+    # and chunks. Chunks are measured by their length, other objects by the length of
+    # their serialized form.
+    cache_snapshots_bytes: int | None
+    cache_manifests_bytes: int | None
+    cache_attributes_bytes: int | None
+    cache_chunks_bytes: int | None
+    # Reads of the same object at most this many bytes apart are merged into a single
+    # request when fetching partial values
+    get_partial_values_coalesce_gap_bytes: int | None
+    # Reads are never merged into a request larger than this many bytes
+    get_partial_values_coalesce_max_bytes: int | None
+    # What to do when the object of a virtual reference was modified after the
+    # reference was set. Default is VirtualRefIntegrity.Strict
+    virtual_ref_integrity: VirtualRefIntegrity | None
 
     def __init__(
         self,
@@ -275,6 +353,13 @@ class StoreConfig:
         inline_chunk_threshold_bytes: int | None = None,
         unsafe_overwrite_refs: bool | None = None,
         virtual_ref_config: VirtualRefConfig | None = None,
+        cache_snapshots_bytes: int | None = None,
+        cache_manifests_bytes: int | None = None,
+        cache_attributes_bytes: int | None = None,
+        cache_chunks_bytes: int | None = None,
+        get_partial_values_coalesce_gap_bytes: int | None = None,
+        get_partial_values_coalesce_max_bytes: int | None = None,
+        virtual_ref_integrity: VirtualRefIntegrity | None = None,
     ): 
         """Create a StoreConfig object with the given configuration options
 
@@ -290,6 +375,23 @@ class StoreConfig:
             Whether to allow overwriting refs in the store. Default is False. Experimental.
         virtual_ref_config: VirtualRefConfig | None
             Configurations for virtual references such as credentials and endpoints
+        cache_snapshots_bytes: int | None
+            Maximum size of the in memory snapshot cache. Default is 32 MiB.
+        cache_manifests_bytes: int | None
+            Maximum size of the in memory manifest cache. Default is 256 MiB.
+        cache_attributes_bytes: int | None
+            Maximum size of the in memory attributes cache. Default is 32 MiB.
+        cache_chunks_bytes: int | None
+            Maximum size of the in memory chunk cache. Default is 0, chunks are not cached.
+        get_partial_values_coalesce_gap_bytes: int | None
+            Reads of the same object at most this many bytes apart are merged into a
+            single request when fetching partial values. Default is 1 MiB.
+        get_partial_values_coalesce_max_bytes: int | None
+            Reads are never merged into a request larger than this many bytes. Default
+            is 8 MiB.
+        virtual_ref_integrity: VirtualRefIntegrity | None
+            What to do when the object of a virtual reference doesn't match the etag or
+            last modification time of the reference. Default is VirtualRefIntegrity.Strict.
         
         Returns
         -------
@@ -2,7 +2,7 @@ mod errors;
 mod storage;
 mod streams;
 
-use std::{borrow::Cow, sync::Arc};
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
 use ::icechunk::{format::ChunkOffset, Store};
 use bytes::Bytes;
@@ -10,10 +10,13 @@ use chrono::{DateTime, Utc};
 use errors::{PyIcechunkStoreError, PyIcechunkStoreResult};
 use futures::{StreamExt, TryStreamExt};
 use icechunk::{
-    format::{manifest::VirtualChunkRef, ChunkLength},
+    format::{manifest::VirtualChunkRef, ChunkIndices, ChunkLength},
     refs::Ref,
-    repository::VirtualChunkLocation,
-    storage::virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+    repository::{VirtualChunkLocation, VirtualRefIntegrity},
+    storage::{
+        caching::CacheCounters, virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+        CachingConfig,
+    },
     zarr::{
         ConsolidatedStore, ObjectId, RepositoryConfig, StorageConfig, StoreError,
         StoreOptions, VersionInfo,
@@ -51,6 +54,63 @@ struct PyStoreConfig {
     pub unsafe_overwrite_refs: Option<bool>,
     #[pyo3(get, set)]
     pub virtual_ref_config: Option<PyVirtualRefConfig>,
+    #[pyo3(get, set)]
+    pub cache_snapshots_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub cache_manifests_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub cache_attributes_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub cache_chunks_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub get_partial_values_coalesce_gap_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub get_partial_values_coalesce_max_bytes: Option<u64>,
+    #[pyo3(get, set)]
+    pub virtual_ref_integrity: Option<PyVirtualRefIntegrity>,
+}
+
+#[pyclass(name = "VirtualRefIntegrity")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PyVirtualRefIntegrity {
+    Strict,
+    Warn,
+    Off,
+}
+
+impl From<PyVirtualRefIntegrity> for VirtualRefIntegrity {
+    fn from(value: PyVirtualRefIntegrity) -> Self {
+        match value {
+            PyVirtualRefIntegrity::Strict => VirtualRefIntegrity::Strict,
+            PyVirtualRefIntegrity::Warn => VirtualRefIntegrity::Warn,
+            PyVirtualRefIntegrity::Off => VirtualRefIntegrity::Off,
+        }
+    }
+}
+
+impl PyStoreConfig {
+    fn caching(&self) -> Option<CachingConfig> {
+        if self.cache_snapshots_bytes.is_none()
+            && self.cache_manifests_bytes.is_none()
+            && self.cache_attributes_bytes.is_none()
+            && self.cache_chunks_bytes.is_none()
+        {
+            return None;
+        }
+        let default = CachingConfig::default();
+        Some(CachingConfig {
+            snapshots_bytes: self
+                .cache_snapshots_bytes
+                .unwrap_or(default.snapshots_bytes),
+            manifests_bytes: self
+                .cache_manifests_bytes
+                .unwrap_or(default.manifests_bytes),
+            attributes_bytes: self
+                .cache_attributes_bytes
+                .unwrap_or(default.attributes_bytes),
+            chunks_bytes: self.cache_chunks_bytes.unwrap_or(default.chunks_bytes),
+        })
+    }
 }
 
 impl From<&PyStoreConfig> for RepositoryConfig {
@@ -64,18 +124,28 @@ impl From<&PyStoreConfig> for RepositoryConfig {
                 .virtual_ref_config
                 .as_ref()
                 .map(ObjectStoreVirtualChunkResolverConfig::from),
+            virtual_http_config: None,
+            commit_rebase_attempts: None,
+            caching: None,
+            promote_partial_reads_max_bytes: None,
+            virtual_ref_integrity: config.virtual_ref_integrity.map(|i| i.into()),
         }
     }
 }
 
 impl From<&PyStoreConfig> for StoreOptions {
     fn from(config: &PyStoreConfig) -> Self {
-        if let Some(get_partial_values_concurrency) =
-            config.get_partial_values_concurrency
-        {
-            StoreOptions { get_partial_values_concurrency }
-        } else {
-            StoreOptions::default()
+        let default = StoreOptions::default();
+        StoreOptions {
+            get_partial_values_concurrency: config
+                .get_partial_values_concurrency
+                .unwrap_or(default.get_partial_values_concurrency),
+            caching: config.caching(),
+            get_partial_values_coalesce_gap_bytes: config
+                .get_partial_values_coalesce_gap_bytes,
+            get_partial_values_coalesce_max_bytes: config
+                .get_partial_values_coalesce_max_bytes,
+            ..default
         }
     }
 }
@@ -83,17 +153,32 @@ impl From<&PyStoreConfig> for StoreOptions {
 #[pymethods]
 impl PyStoreConfig {
     #[new]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         get_partial_values_concurrency: Option<u16>,
         inline_chunk_threshold_bytes: Option<u16>,
         unsafe_overwrite_refs: Option<bool>,
         virtual_ref_config: Option<PyVirtualRefConfig>,
+        cache_snapshots_bytes: Option<u64>,
+        cache_manifests_bytes: Option<u64>,
+        cache_attributes_bytes: Option<u64>,
+        cache_chunks_bytes: Option<u64>,
+        get_partial_values_coalesce_gap_bytes: Option<u64>,
+        get_partial_values_coalesce_max_bytes: Option<u64>,
+        virtual_ref_integrity: Option<PyVirtualRefIntegrity>,
     ) -> Self {
         PyStoreConfig {
             get_partial_values_concurrency,
             inline_chunk_threshold_bytes,
             unsafe_overwrite_refs,
             virtual_ref_config,
+            cache_snapshots_bytes,
+            cache_manifests_bytes,
+            cache_attributes_bytes,
+            cache_chunks_bytes,
+            get_partial_values_coalesce_gap_bytes,
+            get_partial_values_coalesce_max_bytes,
+            virtual_ref_integrity,
         }
     }
 }
@@ -506,9 +591,36 @@ impl PyIcechunkStore {
         Ok(has_uncommitted_changes)
     }
 
+    fn cache_stats(
+        &self,
+    ) -> PyIcechunkStoreResult<HashMap<String, HashMap<String, u64>>> {
+        let store = self.store.blocking_read();
+        let stats =
+            pyo3_async_runtimes::tokio::get_runtime().block_on(store.cache_stats());
+        let counters = |c: CacheCounters| {
+            HashMap::from([
+                ("hits".to_string(), c.hits),
+                ("misses".to_string(), c.misses),
+            ])
+        };
+        Ok(stats
+            .map(|stats| {
+                HashMap::from([
+                    ("snapshots".to_string(), counters(stats.snapshots)),
+                    ("manifests".to_string(), counters(stats.manifests)),
+                    ("attributes".to_string(), counters(stats.attributes)),
+                    ("chunks".to_string(), counters(stats.chunks)),
+                ])
+            })
+            .unwrap_or_default())
+    }
+
     fn async_reset<'py>(&'py self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
         let store = Arc::clone(&self.store);
-        pyo3_async_runtimes::tokio::future_into_py(py, async move { do_reset(store).await })
+        pyo3_async_runtimes::tokio::future_into_py(
+            py,
+            async move { do_reset(store).await },
+        )
     }
 
     fn reset<'py>(&'py self, py: Python<'py>) -> PyResult<Bound<'py, PyNone>> {
@@ -784,6 +896,7 @@ impl PyIcechunkStore {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn async_set_virtual_ref<'py>(
         &'py self,
         py: Python<'py>,
@@ -791,13 +904,17 @@ impl PyIcechunkStore {
         location: String,
         offset: ChunkOffset,
         length: ChunkLength,
+        etag: Option<String>,
+        last_modified: Option<DateTime<Utc>>,
     ) -> PyResult<Bound<'py, PyAny>> {
         let store = Arc::clone(&self.store);
         pyo3_async_runtimes::tokio::future_into_py(py, async move {
-            do_set_virtual_ref(store, key, location, offset, length).await
+            do_set_virtual_ref(store, key, location, offset, length, etag, last_modified)
+                .await
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn set_virtual_ref<'py>(
         &'py self,
         py: Python<'py>,
@@ -805,14 +922,72 @@ impl PyIcechunkStore {
         location: String,
         offset: ChunkOffset,
         length: ChunkLength,
+        etag: Option<String>,
+        last_modified: Option<DateTime<Utc>>,
     ) -> PyResult<Bound<'py, PyNone>> {
         let store = Arc::clone(&self.store);
         pyo3_async_runtimes::tokio::get_runtime().block_on(async move {
-            do_set_virtual_ref(store, key, location, offset, length).await?;
+            do_set_virtual_ref(store, key, location, offset, length, etag, last_modified)
+                .await?;
             Ok(PyNone::get_bound(py).to_owned())
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn async_set_virtual_refs<'py>(
+        &'py self,
+        py: Python<'py>,
+        array_path: String,
+        chunk_coords: Vec<Vec<u32>>,
+        locations: Vec<String>,
+        offsets: Vec<ChunkOffset>,
+        lengths: Vec<ChunkLength>,
+        etags: Option<Vec<Option<String>>>,
+        last_modified: Option<Vec<Option<DateTime<Utc>>>>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let store = Arc::clone(&self.store);
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            do_set_virtual_refs(
+                store,
+                array_path,
+                chunk_coords,
+                locations,
+                offsets,
+                lengths,
+                etags,
+                last_modified,
+            )
+            .await
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn set_virtual_refs(
+        &self,
+        array_path: String,
+        chunk_coords: Vec<Vec<u32>>,
+        locations: Vec<String>,
+        offsets: Vec<ChunkOffset>,
+        lengths: Vec<ChunkLength>,
+        etags: Option<Vec<Option<String>>>,
+        last_modified: Option<Vec<Option<DateTime<Utc>>>>,
+    ) -> PyResult<usize> {
+        let store = Arc::clone(&self.store);
+        pyo3_async_runtimes::tokio::get_runtime().block_on(async move {
+            do_set_virtual_refs(
+                store,
+                array_path,
+                chunk_coords,
+                locations,
+                offsets,
+                lengths,
+                etags,
+                last_modified,
+            )
+            .await
+        })
+    }
+
     fn delete<'py>(
         &'py self,
         py: Python<'py>,
@@ -1022,17 +1197,66 @@ async fn do_set_virtual_ref(
     location: String,
     offset: ChunkOffset,
     length: ChunkLength,
+    etag: Option<String>,
+    last_modified: Option<DateTime<Utc>>,
 ) -> PyResult<()> {
     let virtual_ref = VirtualChunkRef {
         location: VirtualChunkLocation::Absolute(location),
         offset,
         length,
+        etag,
+        last_modified,
     };
     let mut store = store.write().await;
     store.set_virtual_ref(&key, virtual_ref).await.map_err(PyIcechunkStoreError::from)?;
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+async fn do_set_virtual_refs(
+    store: Arc<RwLock<Store>>,
+    array_path: String,
+    chunk_coords: Vec<Vec<u32>>,
+    locations: Vec<String>,
+    offsets: Vec<ChunkOffset>,
+    lengths: Vec<ChunkLength>,
+    etags: Option<Vec<Option<String>>>,
+    last_modified: Option<Vec<Option<DateTime<Utc>>>>,
+) -> PyResult<usize> {
+    let count = chunk_coords.len();
+    let etags = etags.unwrap_or_else(|| vec![None; count]);
+    let last_modified = last_modified.unwrap_or_else(|| vec![None; count]);
+    if locations.len() != count
+        || offsets.len() != count
+        || lengths.len() != count
+        || etags.len() != count
+        || last_modified.len() != count
+    {
+        return Err(PyValueError::new_err(
+            "chunk_coords, locations, offsets, lengths, etags and last_modified must have the same length",
+        ));
+    }
+    let refs = chunk_coords
+        .into_iter()
+        .zip(locations)
+        .zip(offsets.into_iter().zip(lengths))
+        .zip(etags.into_iter().zip(last_modified))
+        .map(|(((coords, location), (offset, length)), (etag, last_modified))| {
+            let location = VirtualChunkLocation::from_absolute_path(&location)
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            let virtual_ref =
+                VirtualChunkRef { location, offset, length, etag, last_modified };
+            Ok((ChunkIndices(coords), virtual_ref))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let mut store = store.write().await;
+    let count = store
+        .set_virtual_refs(&array_path, refs)
+        .await
+        .map_err(PyIcechunkStoreError::from)?;
+    Ok(count)
+}
+
 /// The icechunk Python module implemented in Rust.
 #[pymodule]
 fn _icechunk_python(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
@@ -1044,6 +1268,7 @@ fn _icechunk_python(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PyStoreConfig>()?;
     m.add_class::<PySnapshotMetadata>()?;
     m.add_class::<PyVirtualRefConfig>()?;
+    m.add_class::<PyVirtualRefIntegrity>()?;
     m.add_function(wrap_pyfunction!(pyicechunk_store_exists, m)?)?;
     m.add_function(wrap_pyfunction!(async_pyicechunk_store_exists, m)?)?;
     m.add_function(wrap_pyfunction!(pyicechunk_store_create, m)?)?;
@@ -1,7 +1,10 @@
 #![allow(clippy::too_many_arguments)]
 // TODO: we only need that allow for PyStorageConfig, but i don't know how to set it
 
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use icechunk::{
     storage::{
@@ -194,6 +197,9 @@ pub enum PyVirtualRefConfig {
         region: Option<String>,
         anon: bool,
     },
+    S3PerPrefix {
+        prefixes: HashMap<String, PyVirtualRefConfig>,
+    },
 }
 
 #[pymethods]
@@ -242,6 +248,32 @@ impl PyVirtualRefConfig {
             anon: true,
         }
     }
+
+    #[classmethod]
+    fn s3_per_prefix(
+        _cls: &Bound<'_, PyType>,
+        prefixes: HashMap<String, PyVirtualRefConfig>,
+    ) -> Self {
+        PyVirtualRefConfig::S3PerPrefix { prefixes }
+    }
+}
+
+impl PyVirtualRefConfig {
+    fn s3_prefixes(&self, prefix: &str, res: &mut BTreeMap<String, S3Config>) {
+        match self {
+            PyVirtualRefConfig::S3 { .. } => {
+                if let ObjectStoreVirtualChunkResolverConfig::S3(config) = self.into() {
+                    res.insert(prefix.to_string(), config);
+                }
+            }
+            // nested prefixes are full prefixes themselves
+            PyVirtualRefConfig::S3PerPrefix { prefixes } => {
+                for (prefix, config) in prefixes {
+                    config.s3_prefixes(prefix, res);
+                }
+            }
+        }
+    }
 }
 
 impl From<&PyVirtualRefConfig> for ObjectStoreVirtualChunkResolverConfig {
@@ -259,6 +291,11 @@ impl From<&PyVirtualRefConfig> for ObjectStoreVirtualChunkResolverConfig {
                 credentials: mk_credentials(credentials.as_ref(), *anon),
                 allow_http: allow_http.unwrap_or(false),
             }),
+            PyVirtualRefConfig::S3PerPrefix { .. } => {
+                let mut prefixes = BTreeMap::new();
+                config.s3_prefixes("", &mut prefixes);
+                ObjectStoreVirtualChunkResolverConfig::S3PerPrefix(prefixes)
+            }
         }
     }
 }
@@ -77,3 +77,33 @@ async def test_inline_chunks(tmp_store):
     # inline_chunk_threshold is 40, we should have 10 chunks in the chunks directory
     assert os.path.isdir(f"{store_path}/chunks")
     assert len(os.listdir(f"/{store_path}/chunks")) == 10
+
+
+async def test_cache_config_and_stats(tmpdir):
+    store = icechunk.IcechunkStore.open_or_create(
+        storage=icechunk.StorageConfig.filesystem(f"{tmpdir}"),
+        mode="a",
+        config=icechunk.StoreConfig(
+            inline_chunk_threshold_bytes=0, cache_chunks_bytes=1024 * 1024
+        ),
+    )
+    array = zarr.open_array(
+        store=store,
+        mode="a",
+        shape=(10),
+        dtype="int64",
+        zarr_format=3,
+        chunk_shape=(1),
+        fill_value=-1,
+    )
+    array[:] = 42
+    store.commit("write")
+
+    assert (array[:] == 42).all()
+    assert (array[:] == 42).all()
+
+    stats = store.cache_stats()
+    assert set(stats) == {"snapshots", "manifests", "attributes", "chunks"}
+    assert stats["chunks"]["misses"] == 10
+    assert stats["chunks"]["hits"] == 10
+    store.close()
@@ -125,3 +125,98 @@ async def test_from_s3_public_virtual_refs(tmpdir):
 
 
 
+
+
+async def test_virtual_refs_per_prefix_config(tmpdir):
+    write_chunks_to_minio([("path/to/python/per-prefix-chunk", b"private")])
+
+    store = IcechunkStore.open_or_create(
+        storage=StorageConfig.filesystem(f"{tmpdir}/virtual"),
+        mode="w",
+        config=StoreConfig(
+            virtual_ref_config=VirtualRefConfig.s3_per_prefix(
+                {
+                    "testbucket": VirtualRefConfig.s3_from_config(
+                        credentials=S3Credentials(
+                            access_key_id="minio123",
+                            secret_access_key="minio123",
+                        ),
+                        endpoint_url="http://localhost:9000",
+                        allow_http=True,
+                        region="us-east-1",
+                    ),
+                    "s3://noaa-nos-ofs-pds/": VirtualRefConfig.s3_anonymous(
+                        region="us-east-1"
+                    ),
+                }
+            )
+        ),
+    )
+    root = zarr.Group.from_store(store=store, zarr_format=3)
+    depth = root.require_array(
+        name="depth", shape=((10,)), chunk_shape=((10,)), dtype="float64"
+    )
+    store.set_virtual_ref(
+        "depth/c/0",
+        "s3://noaa-nos-ofs-pds/dbofs/netcdf/202410/dbofs.t00z.20241009.fields.f030.nc",
+        offset=119339,
+        length=80,
+    )
+    root.require_array(name="private", shape=((7,)), chunk_shape=((7,)), dtype="uint8")
+    store.set_virtual_ref(
+        "private/c/0",
+        "s3://testbucket/path/to/python/per-prefix-chunk",
+        offset=0,
+        length=7,
+    )
+
+    # each bucket is read with its own credentials and endpoint
+    assert np.allclose(depth[:], np.arange(-0.95, 0, 0.1))
+    buffer_prototype = zarr.core.buffer.default_buffer_prototype()
+    private = await store.get("private/c/0", prototype=buffer_prototype)
+    assert private is not None
+    assert private.to_bytes() == b"private"
+
+
+async def test_set_virtual_refs_batch(tmpdir):
+    data_path = f"{tmpdir}/data.bin"
+    with open(data_path, "wb") as f:
+        f.write(bytes(range(8)))
+
+    store = IcechunkStore.open_or_create(
+        storage=StorageConfig.filesystem(f"{tmpdir}/virtual"),
+        mode="w",
+    )
+    root = zarr.Group.from_store(store=store, zarr_format=3)
+    array = root.require_array(
+        name="array", shape=((8,)), chunk_shape=((2,)), dtype="uint8"
+    )
+
+    count = store.set_virtual_refs(
+        "array",
+        chunk_coords=[(i,) for i in range(4)],
+        locations=[f"file://{data_path}"] * 4,
+        offsets=np.arange(0, 8, 2),
+        lengths=[2] * 4,
+    )
+    assert count == 4
+    assert np.array_equal(array[:], np.arange(8, dtype="uint8"))
+
+    with pytest.raises(ValueError):
+        store.set_virtual_refs(
+            "array",
+            chunk_coords=[(0,), (1,)],
+            locations=[f"file://{data_path}"],
+            offsets=[0, 2],
+            lengths=[2, 2],
+        )
+
+    with pytest.raises(ValueError):
+        store.set_virtual_refs(
+            "array",
+            chunk_coords=[(0,), (1,)],
+            locations=[f"file://{data_path}"] * 2,
+            offsets=[0, 2],
+            lengths=[2, 2],
+            etags=["abc"],
+        )
@@ -6,7 +6,7 @@ use icechunk::{
         ChunkIndices, ChunkKeyEncoding, ChunkPayload, ChunkShape, Codec, DataType,
         FillValue, Path, StorageTransformer, UserAttributes, ZarrArrayMetadata,
     },
-    storage::{MemCachingStorage, ObjectStorage},
+    storage::{CachingConfig, MemCachingStorage, ObjectStorage},
     zarr::StoreError,
     Repository, Storage,
 };
@@ -21,7 +21,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 ```
 let storage: Arc<dyn Storage + Send + Sync> = Arc::new(InMemoryStorage::new());
 let storage: Arc<dyn Storage + Send + Sync> =
-    Arc::new(MemCachingStorage::new(storage, 100_000_000));
+    Arc::new(MemCachingStorage::new(storage, &CachingConfig::default()));
 let mut ds = Repository::create(Arc::clone(&storage));
 ```
 "#,
@@ -30,7 +30,7 @@ let mut ds = Repository::create(Arc::clone(&storage));
     let storage: Arc<dyn Storage + Send + Sync> =
         Arc::new(ObjectStorage::new_in_memory_store(None));
     let mut ds = Repository::init(
-        Arc::new(MemCachingStorage::new(Arc::clone(&storage), 2, 2, 0, 0)),
+        Arc::new(MemCachingStorage::new(Arc::clone(&storage), &CachingConfig::default())),
         false,
     )
     .await?
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     iter,
     mem::take,
 };
@@ -9,9 +9,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
-        manifest::{ChunkInfo, ManifestExtents, ManifestRef},
+        manifest::{ChunkInfo, ManifestRef},
         snapshot::{NodeData, NodeSnapshot, UserAttributesSnapshot},
-        ManifestId, NodeId,
+        NodeId, SnapshotId,
     },
     metadata::UserAttributes,
     repository::{ChunkIndices, ChunkPayload, Path, RepositoryResult, ZarrArrayMetadata},
@@ -29,6 +29,10 @@ pub struct ChangeSet {
     set_chunks: HashMap<NodeId, HashMap<ChunkIndices, Option<ChunkPayload>>>,
     deleted_groups: HashSet<Path>,
     deleted_arrays: HashSet<Path>,
+    // None for deleted prefixes
+    virtual_prefixes: HashMap<String, Option<String>>,
+    // snapshots merged into the session, they become parents of the next commit
+    merged_snapshots: BTreeSet<SnapshotId>,
 }
 
 impl ChangeSet {
@@ -59,6 +63,7 @@ impl ChangeSet {
             }
             None => {
                 // it's an old group, we need to flag it as deleted
+                self.delete_children(&path);
                 self.deleted_groups.insert(path);
             }
         }
@@ -124,6 +129,17 @@ impl ChangeSet {
             || path.ancestors().skip(1).any(|parent| self.is_deleted(&parent))
     }
 
+    /// New nodes are deleted with their parent groups, unless the parent was added again
+    ///
+    /// A new node can take the path of a deleted node, for example to change its type.
+    pub fn is_new_node_deleted(&self, path: &Path) -> bool {
+        path.ancestors().skip(1).any(|parent| {
+            (self.deleted_groups.contains(&parent)
+                || self.deleted_arrays.contains(&parent))
+                && !self.new_groups.contains_key(&parent)
+        })
+    }
+
     pub fn has_updated_attributes(&self, node_id: &NodeId) -> bool {
         self.updated_attributes.contains_key(node_id)
     }
@@ -150,6 +166,28 @@ impl ChangeSet {
         self.updated_attributes.get(node_id)
     }
 
+    pub fn set_virtual_prefix(&mut self, name: String, prefix: Option<String>) {
+        self.virtual_prefixes.insert(name, prefix);
+    }
+
+    /// Apply the virtual prefixes set or deleted in this session to `prefixes`
+    pub fn update_virtual_prefixes(&self, prefixes: &mut BTreeMap<String, String>) {
+        for (name, prefix) in self.virtual_prefixes.iter() {
+            match prefix {
+                Some(prefix) => prefixes.insert(name.clone(), prefix.clone()),
+                None => prefixes.remove(name),
+            };
+        }
+    }
+
+    pub fn add_merged_snapshot(&mut self, snapshot_id: SnapshotId) {
+        self.merged_snapshots.insert(snapshot_id);
+    }
+
+    pub fn merged_snapshots(&self) -> impl Iterator<Item = &SnapshotId> {
+        self.merged_snapshots.iter()
+    }
+
     pub fn set_chunk_ref(
         &mut self,
         node_id: NodeId,
@@ -166,6 +204,15 @@ impl ChangeSet {
             .or_insert(HashMap::from([(coord, data)]));
     }
 
+    /// Record many chunk payloads of the same node at once
+    pub fn set_chunk_refs(
+        &mut self,
+        node_id: NodeId,
+        chunks: impl IntoIterator<Item = (ChunkIndices, Option<ChunkPayload>)>,
+    ) {
+        self.set_chunks.entry(node_id).or_default().extend(chunks);
+    }
+
     pub fn get_chunk_ref(
         &self,
         node_id: &NodeId,
@@ -179,7 +226,11 @@ impl ChangeSet {
         node_id: &NodeId,
         node_path: &Path,
     ) -> impl Iterator<Item = (&ChunkIndices, &Option<ChunkPayload>)> {
-        if self.is_deleted(node_path) {
+        let deleted = match self.new_arrays.get(node_path) {
+            Some((new_id, _)) if new_id == node_id => self.is_new_node_deleted(node_path),
+            _ => self.is_deleted(node_path),
+        };
+        if deleted {
             return Either::Left(iter::empty());
         }
         match self.set_chunks.get(node_id) {
@@ -238,6 +289,8 @@ impl ChangeSet {
         self.updated_attributes.extend(other.updated_attributes);
         self.deleted_groups.extend(other.deleted_groups);
         self.deleted_arrays.extend(other.deleted_arrays);
+        self.virtual_prefixes.extend(other.virtual_prefixes);
+        self.merged_snapshots.extend(other.merged_snapshots);
 
         for (node, other_chunks) in other.set_chunks.into_iter() {
             match self.set_chunks.remove(&node) {
@@ -319,10 +372,10 @@ impl ChangeSet {
 
     pub fn new_nodes_iterator<'a>(
         &'a self,
-        manifest_id: Option<&'a ManifestId>,
+        manifests: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
     ) -> impl Iterator<Item = NodeSnapshot> + 'a {
         self.new_nodes().filter_map(move |path| {
-            if self.is_deleted(path) {
+            if self.is_new_node_deleted(path) {
                 return None;
             }
             // we should be able to create the full node because we
@@ -332,13 +385,8 @@ impl ChangeSet {
             match node.node_data {
                 NodeData::Group => Some(node),
                 NodeData::Array(meta, _no_manifests_yet) => {
-                    let new_manifests = manifest_id
-                        .map(|mid| {
-                            vec![ManifestRef {
-                                object_id: mid.clone(),
-                                extents: ManifestExtents(vec![]),
-                            }]
-                        })
+                    let new_manifests = manifests
+                        .and_then(|m| m.get(&node.id).cloned())
                         .unwrap_or_default();
                     Some(NodeSnapshot {
                         node_data: NodeData::Array(meta, new_manifests),
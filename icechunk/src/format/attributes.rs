@@ -1,2 +1,46 @@
-#[derive(Debug, PartialEq)]
-pub struct AttributesTable {}
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::UserAttributes;
+
+use super::{
+    format_constants, IcechunkFormatError, IcechunkFormatVersion, IcechunkResult,
+    TableOffset,
+};
+
+/// A file with the user attributes too large to be stored inline in the snapshot
+///
+/// Nodes point to their attributes with a [`super::snapshot::UserAttributesRef`].
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct AttributesTable {
+    pub icechunk_attributes_format_version: IcechunkFormatVersion,
+    pub icechunk_attributes_format_flags: BTreeMap<String, rmpv::Value>,
+    attributes: Vec<UserAttributes>,
+}
+
+impl AttributesTable {
+    pub fn new(attributes: Vec<UserAttributes>) -> Self {
+        Self {
+            icechunk_attributes_format_version:
+                format_constants::LATEST_ICECHUNK_ATTRIBUTES_FORMAT,
+            icechunk_attributes_format_flags: Default::default(),
+            attributes,
+        }
+    }
+
+    pub fn get(&self, location: TableOffset) -> IcechunkResult<&UserAttributes> {
+        self.attributes
+            .get(location as usize)
+            .ok_or(IcechunkFormatError::AttributesLocationNotFound { location })
+    }
+
+    pub fn len(&self) -> usize {
+        self.attributes.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
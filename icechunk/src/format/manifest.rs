@@ -4,6 +4,7 @@ use std::{collections::BTreeMap, ops::Bound, sync::Arc};
 use thiserror::Error;
 
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -11,9 +12,51 @@ use super::{
     IcechunkFormatError, IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId,
 };
 
+/// The region of the chunk grid covered by a manifest
+///
+/// Holds the smallest and largest coordinates along every dimension, both inclusive.
+/// Empty extents are unknown, the manifest can contain any coordinate.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestExtents(pub Vec<ChunkIndices>);
 
+impl ManifestExtents {
+    pub fn unknown() -> Self {
+        Self(vec![])
+    }
+
+    pub fn from_coords<'a>(coords: impl IntoIterator<Item = &'a ChunkIndices>) -> Self {
+        let mut coords = coords.into_iter();
+        let Some(first) = coords.next() else { return Self::unknown() };
+        let (min, max) =
+            coords.fold((first.0.clone(), first.0.clone()), |(min, max), c| {
+                (
+                    min.iter().zip(c.0.iter()).map(|(a, b)| *a.min(b)).collect(),
+                    max.iter().zip(c.0.iter()).map(|(a, b)| *a.max(b)).collect(),
+                )
+            });
+        Self(vec![ChunkIndices(min), ChunkIndices(max)])
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, coord: &ChunkIndices) -> bool {
+        match self.0.as_slice() {
+            [min, max] => {
+                min.0.len() == coord.0.len()
+                    && min
+                        .0
+                        .iter()
+                        .zip(max.0.iter())
+                        .zip(coord.0.iter())
+                        .all(|((min, max), c)| min <= c && c <= max)
+            }
+            _ => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestRef {
     pub object_id: ManifestId,
@@ -35,13 +78,18 @@ pub enum VirtualReferenceError {
     FetchError(Box<dyn std::error::Error + Send + Sync>),
     #[error("error parsing virtual reference {0}")]
     OtherError(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error("unknown virtual chunk location prefix {0}")]
+    UnknownPrefix(String),
+    #[error("virtual reference object at {0} was modified after the reference was set")]
+    ObjectModified(String),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum VirtualChunkLocation {
     Absolute(String),
-    // Relative(prefix_id, String)
+    /// A path relative to one of the named prefixes of the snapshot
+    Relative(String, String),
 }
 
 impl VirtualChunkLocation {
@@ -67,10 +115,34 @@ impl VirtualChunkLocation {
             return Err(VirtualReferenceError::CannotParseBucketName(path.into()));
         };
 
-        let location = format!("{}://{}/{}", scheme, host, new_path,);
+        let mut location = match url.port() {
+            Some(port) => format!("{}://{}:{}/{}", scheme, host, port, new_path),
+            None => format!("{}://{}/{}", scheme, host, new_path,),
+        };
+        // http servers can need the query, for example for signed urls
+        if let ("http" | "https", Some(query)) = (scheme, url.query()) {
+            location.push('?');
+            location.push_str(query);
+        }
 
         Ok(VirtualChunkLocation::Absolute(location))
     }
+
+    /// The absolute location, looking up the prefix of relative locations in `prefixes`
+    pub fn resolve(
+        &self,
+        prefixes: &BTreeMap<String, String>,
+    ) -> Result<VirtualChunkLocation, VirtualReferenceError> {
+        match self {
+            VirtualChunkLocation::Absolute(_) => Ok(self.clone()),
+            VirtualChunkLocation::Relative(name, path) => {
+                let prefix = prefixes
+                    .get(name)
+                    .ok_or_else(|| VirtualReferenceError::UnknownPrefix(name.clone()))?;
+                VirtualChunkLocation::from_absolute_path(&format!("{prefix}{path}"))
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -78,6 +150,31 @@ pub struct VirtualChunkRef {
     pub location: VirtualChunkLocation,
     pub offset: ChunkOffset,
     pub length: ChunkLength,
+    /// The ETag of the object when the reference was set
+    #[serde(default)]
+    pub etag: Option<String>,
+    /// The object must not have been modified after this time
+    #[serde(default)]
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl VirtualChunkRef {
+    pub fn pin(&self) -> VirtualChunkPin {
+        VirtualChunkPin { etag: self.etag.clone(), last_modified: self.last_modified }
+    }
+}
+
+/// The expected state of the object holding a virtual chunk, checked when fetching it
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtualChunkPin {
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl VirtualChunkPin {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -182,36 +279,28 @@ impl Iterator for PayloadIterator {
     type Item = (ChunkIndices, ChunkPayload);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.last_key {
-            None => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((
-                        Bound::Included((self.for_node.clone(), ChunkIndices(vec![]))),
-                        Bound::Unbounded,
-                    ))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
-            }
-            Some(last_key) => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((Bound::Excluded(last_key), Bound::Unbounded))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
+        let next = match &self.last_key {
+            None => self
+                .manifest
+                .chunks
+                .range((
+                    Bound::Included((self.for_node.clone(), ChunkIndices(vec![]))),
+                    Bound::Unbounded,
+                ))
+                .next(),
+            Some(last_key) => self
+                .manifest
+                .chunks
+                .range((Bound::Excluded(last_key), Bound::Unbounded))
+                .next(),
+        };
+        match next {
+            // chunks are sorted by node first, so we are done once we see a different node
+            Some((k @ (node, coord), payload)) if node == &self.for_node => {
+                self.last_key = Some(k.clone());
+                Some((coord.clone(), payload.clone()))
             }
+            _ => None,
         }
     }
 }
@@ -213,6 +213,8 @@ pub enum IcechunkFormatError {
     NodeNotFound { path: Path },
     #[error("chunk coordinates not found `{coords:?}`")]
     ChunkCoordinatesNotFound { coords: ChunkIndices },
+    #[error("user attributes not found at location `{location}`")]
+    AttributesLocationNotFound { location: TableOffset },
 }
 
 pub type IcechunkResult<T> = Result<T, IcechunkFormatError>;
@@ -229,6 +231,10 @@ pub mod format_constants {
     pub const LATEST_ICECHUNK_SNAPSHOT_FORMAT: IcechunkFormatVersion = 0;
     pub const LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE: &str = "application/msgpack";
     pub const LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY: &str = "ic-sna-fmt-ver";
+
+    pub const LATEST_ICECHUNK_ATTRIBUTES_FORMAT: IcechunkFormatVersion = 0;
+    pub const LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE: &str = "application/msgpack";
+    pub const LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY: &str = "ic-att-fmt-ver";
 }
 
 impl Display for Path {
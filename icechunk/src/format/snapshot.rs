@@ -77,6 +77,10 @@ pub struct SnapshotMetadata {
     pub id: SnapshotId,
     pub written_at: DateTime<Utc>,
     pub message: String,
+    /// Snapshots merged into this one, besides its parent. This field is last, and has a
+    /// default, so snapshots written before it existed can still be read
+    #[serde(default)]
+    pub merged_parents: Vec<SnapshotId>,
 }
 
 pub type SnapshotProperties = HashMap<String, Value>;
@@ -110,6 +114,10 @@ pub struct Snapshot {
     pub started_at: DateTime<Utc>,
     pub properties: SnapshotProperties,
     nodes: BTreeMap<Path, NodeSnapshot>,
+    /// Url prefixes of the relative virtual chunk locations, by name. This field is last, and
+    /// has a default, so snapshots written before it existed can still be read
+    #[serde(default)]
+    pub virtual_prefixes: BTreeMap<String, String>,
 }
 
 impl Default for SnapshotMetadata {
@@ -118,6 +126,7 @@ impl Default for SnapshotMetadata {
             id: ObjectId::random(),
             written_at: Utc::now(),
             message: Default::default(),
+            merged_parents: Vec::new(),
         }
     }
 }
@@ -130,6 +139,8 @@ impl SnapshotMetadata {
 
 impl Snapshot {
     pub const INITIAL_COMMIT_MESSAGE: &'static str = "Repository initialized";
+    /// How many ancestors are stored inline in each snapshot
+    pub const MAX_SHORT_TERM_HISTORY: usize = 10;
 
     fn new(
         short_term_history: VecDeque<SnapshotMetadata>,
@@ -156,6 +167,7 @@ impl Snapshot {
             started_at,
             properties,
             nodes,
+            virtual_prefixes: BTreeMap::new(),
         }
     }
 
@@ -169,15 +181,19 @@ impl Snapshot {
         let nodes = iter.into_iter().map(|node| (node.path.clone(), node)).collect();
         let mut history = parent.short_term_history.clone();
         history.push_front(parent.metadata.clone());
+        history.truncate(Self::MAX_SHORT_TERM_HISTORY);
 
-        Self::new(
-            history,
-            parent.total_parents + 1,
-            properties,
-            nodes,
-            manifest_files,
-            attribute_files,
-        )
+        Self {
+            virtual_prefixes: parent.virtual_prefixes.clone(),
+            ..Self::new(
+                history,
+                parent.total_parents + 1,
+                properties,
+                nodes,
+                manifest_files,
+                attribute_files,
+            )
+        }
     }
 
     pub fn empty() -> Self {
@@ -450,4 +466,24 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_read_snapshot_without_virtual_prefixes(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut snapshot = Snapshot::empty();
+        snapshot.virtual_prefixes.insert("archive".into(), "s3://bucket/v3/".into());
+        let bytes = rmp_serde::to_vec(&snapshot)?;
+        assert_eq!(rmp_serde::from_slice::<Snapshot>(&bytes)?, snapshot);
+
+        // snapshots written before the prefixes existed lack the last field
+        let mut value: rmpv::Value = rmp_serde::from_slice(&bytes)?;
+        if let rmpv::Value::Array(fields) = &mut value {
+            fields.pop();
+        }
+        let old_bytes = rmp_serde::to_vec(&value)?;
+        let old: Snapshot = rmp_serde::from_slice(&old_bytes)?;
+        assert!(old.virtual_prefixes.is_empty());
+        assert_eq!(old.metadata, snapshot.metadata);
+        Ok(())
+    }
 }
@@ -0,0 +1,974 @@
+//! Import kerchunk reference files as virtual chunks.
+//!
+//! [kerchunk](https://fsspec.github.io/kerchunk/spec.html) describes a Zarr v2 hierarchy as
+//! a set of references: the `.zgroup`, `.zarray` and `.zattrs` documents, and for every chunk
+//! either its bytes inline or the url, offset and length of the bytes in another file. Both
+//! the JSON format (versions 0 and 1) and the Parquet format are supported.
+//!
+//! The importer creates the groups and arrays in a [`Repository`] session, translating the
+//! Zarr v2 metadata into [`ZarrArrayMetadata`]. Chunk references become
+//! [`ChunkPayload::Virtual`] and inline data becomes [`ChunkPayload::Inline`]. Nothing is
+//! committed, that is left to the caller.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    num::NonZeroU64,
+};
+
+use base64::Engine;
+use bytes::Bytes;
+use parquet::{
+    errors::ParquetError,
+    file::reader::{FileReader, SerializedFileReader},
+    record::Field,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{VirtualChunkLocation, VirtualChunkRef, VirtualReferenceError},
+        ChunkIndices, Path,
+    },
+    metadata::{
+        ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, UserAttributes,
+    },
+    repository::{ChunkPayload, RepositoryError, ZarrArrayMetadata},
+    Repository,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum KerchunkError {
+    #[error("error reading references {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid json in references {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("error reading parquet references {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("invalid base64 inline data {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("invalid virtual reference {0}")]
+    VirtualReference(#[from] VirtualReferenceError),
+    #[error("invalid metadata for `{key}`: {message}")]
+    InvalidMetadata { key: String, message: String },
+    #[error("invalid reference for `{key}`: {message}")]
+    InvalidReference { key: String, message: String },
+    #[error("unsupported kerchunk feature: {0}")]
+    Unsupported(String),
+}
+
+pub type KerchunkResult<A> = Result<A, KerchunkError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KerchunkImportReport {
+    pub groups: usize,
+    pub arrays: usize,
+    pub virtual_chunks: usize,
+    pub inline_chunks: usize,
+}
+
+/// The target of a kerchunk reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reference {
+    Inline(Bytes),
+    Virtual { url: String, offset: u64, length: u64 },
+}
+
+/// Import kerchunk references in the JSON format, versions 0 and 1
+///
+/// Templates of version 1 are expanded, references using `gen` are not supported.
+pub async fn import_kerchunk_json(
+    repo: &mut Repository,
+    json: &[u8],
+) -> KerchunkResult<KerchunkImportReport> {
+    let mut document: serde_json::Map<String, Value> = serde_json::from_slice(json)?;
+    let (refs, templates) = match document.get("version") {
+        None => (document, serde_json::Map::new()),
+        Some(version) if version == 1 => {
+            if document
+                .get("gen")
+                .and_then(Value::as_array)
+                .is_some_and(|g| !g.is_empty())
+            {
+                return Err(KerchunkError::Unsupported("generated references".into()));
+            }
+            let refs = match document.remove("refs") {
+                Some(Value::Object(refs)) => refs,
+                _ => serde_json::Map::new(),
+            };
+            let templates = match document.remove("templates") {
+                Some(Value::Object(templates)) => templates,
+                _ => serde_json::Map::new(),
+            };
+            (refs, templates)
+        }
+        Some(version) => {
+            return Err(KerchunkError::Unsupported(format!("version {version}")))
+        }
+    };
+
+    let mut metadata = BTreeMap::new();
+    let mut chunks = BTreeMap::new();
+    for (key, value) in refs {
+        let reference = parse_json_reference(&key, value, &templates)?;
+        if is_metadata_key(&key) {
+            let bytes = match reference {
+                Reference::Inline(bytes) => bytes,
+                Reference::Virtual { .. } => {
+                    return Err(KerchunkError::InvalidMetadata {
+                        key,
+                        message: "metadata must be inline".to_string(),
+                    })
+                }
+            };
+            metadata.insert(key, serde_json::from_slice(&bytes)?);
+        } else {
+            chunks.insert(key, reference);
+        }
+    }
+
+    let mut report = KerchunkImportReport::default();
+    let arrays = create_nodes(repo, &metadata, &mut report).await?;
+
+    let mut array_chunks: HashMap<&str, Vec<(ChunkIndices, Reference)>> = HashMap::new();
+    for (key, reference) in chunks {
+        // chunk keys can contain `/` too, the array is the longest prefix that matches
+        let split = key
+            .rmatch_indices('/')
+            .map(|(i, _)| (&key[..i], &key[i + 1..]))
+            .chain(std::iter::once(("", key.as_str())))
+            .find_map(|(array_key, chunk_key)| {
+                arrays.get_key_value(array_key).map(|array| (array, chunk_key))
+            });
+        let Some(((array_key, array), chunk_key)) = split else {
+            return Err(KerchunkError::InvalidReference {
+                key,
+                message: "chunk of an unknown array".to_string(),
+            });
+        };
+        let coords = array.parse_chunk_key(chunk_key).ok_or_else(|| {
+            KerchunkError::InvalidReference {
+                key: key.clone(),
+                message: "invalid chunk key".to_string(),
+            }
+        })?;
+        array_chunks.entry(array_key).or_default().push((coords, reference));
+    }
+    for (array_key, refs) in array_chunks {
+        set_chunks(repo, array_key, refs, &mut report).await?;
+    }
+    Ok(report)
+}
+
+/// Import kerchunk references in the Parquet format, from the local directory `dir`
+///
+/// The directory holds the consolidated metadata in `.zmetadata`, and the references of
+/// every array in `<array>/refs.<n>.parq` files of `record_size` chunks each.
+pub async fn import_kerchunk_parquet(
+    repo: &mut Repository,
+    dir: &std::path::Path,
+) -> KerchunkResult<KerchunkImportReport> {
+    #[derive(Deserialize)]
+    struct ParquetMetadata {
+        metadata: BTreeMap<String, Value>,
+        record_size: u64,
+    }
+
+    let zmetadata: ParquetMetadata =
+        serde_json::from_slice(&std::fs::read(dir.join(".zmetadata"))?)?;
+    if zmetadata.record_size == 0 {
+        return Err(KerchunkError::InvalidMetadata {
+            key: ".zmetadata".to_string(),
+            message: "record_size must be positive".to_string(),
+        });
+    }
+    // metadata can be stored as json documents or as strings holding them
+    let metadata = zmetadata
+        .metadata
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(s) => Ok((key, serde_json::from_str(&s)?)),
+            value => Ok((key, value)),
+        })
+        .collect::<KerchunkResult<BTreeMap<_, _>>>()?;
+
+    let mut report = KerchunkImportReport::default();
+    let arrays = create_nodes(repo, &metadata, &mut report).await?;
+    for (array_key, array) in arrays.iter() {
+        let num_chunks = array.num_chunks();
+        let mut refs = Vec::new();
+        for record in 0..num_chunks.div_ceil(zmetadata.record_size) {
+            let file = dir.join(array_key).join(format!("refs.{record}.parq"));
+            if !file.exists() {
+                continue;
+            }
+            let reader = SerializedFileReader::new(File::open(&file)?)?;
+            for (row, fields) in reader.get_row_iter(None)?.enumerate() {
+                let index = record * zmetadata.record_size + row as u64;
+                if index >= num_chunks {
+                    break;
+                }
+                let key = format!("{array_key}/{index}");
+                if let Some(reference) = parse_parquet_reference(&key, &fields?)? {
+                    refs.push((array.unravel_index(index), reference));
+                }
+            }
+        }
+        set_chunks(repo, array_key, refs, &mut report).await?;
+    }
+    Ok(report)
+}
+
+fn is_metadata_key(key: &str) -> bool {
+    let name = key.rsplit_once('/').map(|(_, name)| name).unwrap_or(key);
+    matches!(name, ".zgroup" | ".zarray" | ".zattrs" | ".zmetadata")
+}
+
+fn parse_json_reference(
+    key: &str,
+    value: Value,
+    templates: &serde_json::Map<String, Value>,
+) -> KerchunkResult<Reference> {
+    let invalid = |message: &str| KerchunkError::InvalidReference {
+        key: key.to_string(),
+        message: message.to_string(),
+    };
+    match value {
+        Value::String(s) => match s.strip_prefix("base64:") {
+            Some(encoded) => Ok(Reference::Inline(
+                base64::engine::general_purpose::STANDARD.decode(encoded)?.into(),
+            )),
+            None => Ok(Reference::Inline(s.into())),
+        },
+        // some writers embed the metadata documents directly
+        Value::Object(_) => Ok(Reference::Inline(serde_json::to_vec(&value)?.into())),
+        Value::Array(items) => {
+            let url = items
+                .first()
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid("missing url"))?;
+            let url = expand_templates(url, templates);
+            match items.as_slice() {
+                [_, offset, length] => {
+                    let offset =
+                        offset.as_u64().ok_or_else(|| invalid("invalid offset"))?;
+                    let length =
+                        length.as_u64().ok_or_else(|| invalid("invalid length"))?;
+                    Ok(Reference::Virtual { url, offset, length })
+                }
+                [_] => Err(KerchunkError::Unsupported(format!(
+                    "reference to a whole file for `{key}`"
+                ))),
+                _ => Err(invalid("expected [url, offset, length]")),
+            }
+        }
+        _ => Err(invalid("unknown reference type")),
+    }
+}
+
+fn parse_parquet_reference(
+    key: &str,
+    fields: &parquet::record::Row,
+) -> KerchunkResult<Option<Reference>> {
+    let mut url = None;
+    let mut offset = 0;
+    let mut length = 0;
+    let mut raw = None;
+    for (name, field) in fields.get_column_iter() {
+        match (name.as_str(), field) {
+            ("path", Field::Str(s)) => url = Some(s.clone()),
+            ("path", Field::Bytes(b)) => {
+                url = Some(String::from_utf8_lossy(b.data()).into_owned())
+            }
+            ("offset", Field::Long(n)) => offset = *n as u64,
+            ("offset", Field::Int(n)) => offset = *n as u64,
+            ("size", Field::Long(n)) => length = *n as u64,
+            ("size", Field::Int(n)) => length = *n as u64,
+            ("raw", Field::Bytes(b)) => raw = Some(Bytes::copy_from_slice(b.data())),
+            ("raw", Field::Str(s)) => raw = Some(Bytes::from(s.clone())),
+            _ => {}
+        }
+    }
+    match (raw, url) {
+        (Some(raw), _) => Ok(Some(Reference::Inline(raw))),
+        (None, Some(_)) if length == 0 => Err(KerchunkError::Unsupported(format!(
+            "reference to a whole file for `{key}`"
+        ))),
+        (None, Some(url)) => Ok(Some(Reference::Virtual { url, offset, length })),
+        // missing chunk
+        (None, None) => Ok(None),
+    }
+}
+
+/// Replace the `{{name}}` placeholders in `url` with the values of `templates`
+fn expand_templates(url: &str, templates: &serde_json::Map<String, Value>) -> String {
+    if !url.contains("{{") {
+        return url.to_string();
+    }
+    templates.iter().fold(url.to_string(), |url, (name, value)| {
+        let value =
+            value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+        url.replace(&format!("{{{{{name}}}}}"), &value)
+    })
+}
+
+/// What the importer needs to know about an array to place its chunks
+#[derive(Debug)]
+struct ArrayInfo {
+    /// Number of chunks along each dimension
+    grid: Vec<u64>,
+    separator: char,
+}
+
+impl ArrayInfo {
+    fn num_chunks(&self) -> u64 {
+        self.grid.iter().product()
+    }
+
+    fn parse_chunk_key(&self, key: &str) -> Option<ChunkIndices> {
+        let coords = key
+            .split(self.separator)
+            .map(|c| c.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match (coords.as_slice(), self.grid.len()) {
+            // scalar arrays have a single chunk with key `0`
+            ([0], 0) => Some(ChunkIndices(vec![])),
+            (coords, ndim) if coords.len() == ndim => Some(ChunkIndices(coords.to_vec())),
+            _ => None,
+        }
+    }
+
+    /// The coordinates of the chunk at `index` in the C ordered chunk grid
+    fn unravel_index(&self, mut index: u64) -> ChunkIndices {
+        let mut coords = vec![0; self.grid.len()];
+        for (coord, size) in coords.iter_mut().zip(self.grid.iter()).rev() {
+            *coord = (index % size) as u32;
+            index /= size;
+        }
+        ChunkIndices(coords)
+    }
+}
+
+/// The Zarr v2 `.zarray` document
+#[derive(Debug, Deserialize)]
+struct ZArray {
+    shape: Vec<u64>,
+    chunks: Vec<u64>,
+    dtype: Value,
+    fill_value: Value,
+    #[serde(default)]
+    order: Option<String>,
+    #[serde(default)]
+    compressor: Option<serde_json::Map<String, Value>>,
+    #[serde(default)]
+    filters: Option<Vec<serde_json::Map<String, Value>>>,
+    #[serde(default)]
+    dimension_separator: Option<String>,
+}
+
+/// Create the groups and arrays described by the `metadata` documents
+///
+/// Returns the arrays that were created, by their kerchunk key.
+async fn create_nodes(
+    repo: &mut Repository,
+    metadata: &BTreeMap<String, Value>,
+    report: &mut KerchunkImportReport,
+) -> KerchunkResult<BTreeMap<String, ArrayInfo>> {
+    let node_key = |key: &str, suffix: &str| {
+        key.strip_suffix(suffix).map(|k| k.trim_end_matches('/').to_string())
+    };
+    let attributes = |node_key: &str| {
+        let key = if node_key.is_empty() {
+            ".zattrs".to_string()
+        } else {
+            format!("{node_key}/.zattrs")
+        };
+        metadata.get(&key).and_then(Value::as_object).cloned()
+    };
+
+    // zarr v2 allows nodes without a .zgroup in their parents, we create those groups
+    let node_keys: Vec<_> = metadata
+        .keys()
+        .filter_map(|key| node_key(key, ".zgroup").or_else(|| node_key(key, ".zarray")))
+        .collect();
+    let mut implicit_groups = std::collections::BTreeSet::new();
+    for key in node_keys.iter() {
+        let ancestors = key.rmatch_indices('/').map(|(i, _)| &key[..i]);
+        for ancestor in ancestors.chain((!key.is_empty()).then_some("")) {
+            if !node_keys.iter().any(|k| k == ancestor) {
+                implicit_groups.insert(ancestor.to_string());
+            }
+        }
+    }
+    for group_key in implicit_groups {
+        add_group_if_missing(repo, node_path(&group_key)?, report).await?;
+    }
+
+    let mut arrays = BTreeMap::new();
+    for (key, value) in metadata.iter() {
+        if let Some(group_key) = node_key(key, ".zgroup") {
+            let path = node_path(&group_key)?;
+            add_group_if_missing(repo, path.clone(), report).await?;
+            if let Some(attrs) = attributes(&group_key).filter(|a| !a.is_empty()) {
+                let attrs = UserAttributes { parsed: Value::Object(attrs) };
+                repo.set_user_attributes(path, Some(attrs)).await?;
+            }
+        } else if let Some(array_key) = node_key(key, ".zarray") {
+            let mut attrs = attributes(&array_key).unwrap_or_default();
+            let zarray: ZArray = serde_json::from_value(value.clone())?;
+            let info = ArrayInfo {
+                grid: zarray
+                    .shape
+                    .iter()
+                    .zip(zarray.chunks.iter())
+                    .map(|(size, chunk)| size.div_ceil((*chunk).max(1)))
+                    .collect(),
+                separator: match zarray.dimension_separator.as_deref() {
+                    Some("/") => '/',
+                    _ => '.',
+                },
+            };
+            let metadata = array_metadata(key, zarray, &mut attrs)?;
+            let path = node_path(&array_key)?;
+            repo.add_array(path.clone(), metadata).await?;
+            report.arrays += 1;
+            if !attrs.is_empty() {
+                let attrs = UserAttributes { parsed: Value::Object(attrs) };
+                repo.set_user_attributes(path, Some(attrs)).await?;
+            }
+            arrays.insert(array_key, info);
+        }
+    }
+    Ok(arrays)
+}
+
+/// Groups can already exist when importing into a repository with data
+async fn add_group_if_missing(
+    repo: &mut Repository,
+    path: Path,
+    report: &mut KerchunkImportReport,
+) -> KerchunkResult<()> {
+    match repo.get_group(&path).await {
+        Ok(_) => Ok(()),
+        Err(RepositoryError::NodeNotFound { .. }) => {
+            repo.add_group(path).await?;
+            report.groups += 1;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn node_path(key: &str) -> KerchunkResult<Path> {
+    format!("/{key}").try_into().map_err(|_| KerchunkError::InvalidMetadata {
+        key: key.to_string(),
+        message: "invalid node path".to_string(),
+    })
+}
+
+async fn set_chunks(
+    repo: &mut Repository,
+    array_key: &str,
+    refs: Vec<(ChunkIndices, Reference)>,
+    report: &mut KerchunkImportReport,
+) -> KerchunkResult<()> {
+    let path = node_path(array_key)?;
+    let mut virtual_refs = Vec::with_capacity(refs.len());
+    for (coords, reference) in refs {
+        match reference {
+            Reference::Inline(bytes) => {
+                repo.set_chunk_ref(
+                    path.clone(),
+                    coords,
+                    Some(ChunkPayload::Inline(bytes)),
+                )
+                .await?;
+                report.inline_chunks += 1;
+            }
+            Reference::Virtual { url, offset, length } => {
+                // local files are referenced by their absolute path
+                let url =
+                    if url.starts_with('/') { format!("file://{url}") } else { url };
+                let location = VirtualChunkLocation::from_absolute_path(&url)?;
+                virtual_refs.push((
+                    coords,
+                    VirtualChunkRef {
+                        location,
+                        offset,
+                        length,
+                        etag: None,
+                        last_modified: None,
+                    },
+                ));
+            }
+        }
+    }
+    report.virtual_chunks += repo.set_virtual_refs(path, virtual_refs).await?;
+    Ok(())
+}
+
+/// Translate a Zarr v2 `.zarray` into [`ZarrArrayMetadata`]
+///
+/// The xarray `_ARRAY_DIMENSIONS` attribute is removed from `attrs` and becomes the
+/// dimension names. Filters and compressors become `numcodecs.<id>` codecs, placed before
+/// or after the `bytes` codec depending on whether they work on arrays or on bytes.
+fn array_metadata(
+    key: &str,
+    zarray: ZArray,
+    attrs: &mut serde_json::Map<String, Value>,
+) -> KerchunkResult<ZarrArrayMetadata> {
+    let invalid = |message: String| KerchunkError::InvalidMetadata {
+        key: key.to_string(),
+        message,
+    };
+    let dtype = zarray
+        .dtype
+        .as_str()
+        .ok_or_else(|| invalid(format!("unsupported dtype {}", zarray.dtype)))?;
+    let (endian, data_type) = parse_dtype(dtype)
+        .ok_or_else(|| invalid(format!("unsupported dtype {dtype}")))?;
+    let fill_value = match &zarray.fill_value {
+        Value::Null => default_fill_value(&data_type),
+        value => FillValue::from_data_type_and_json(&data_type, value)
+            .map_err(|e| invalid(format!("invalid fill_value: {e}")))?,
+    };
+    let chunk_shape = zarray
+        .chunks
+        .iter()
+        .map(|c| NonZeroU64::new(*c))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("chunk sizes must be positive".to_string()))?;
+    if chunk_shape.len() != zarray.shape.len() {
+        return Err(invalid("chunks and shape have different lengths".to_string()));
+    }
+
+    let codec = |mut config: serde_json::Map<String, Value>| {
+        let id = config.remove("id").and_then(|id| id.as_str().map(str::to_string));
+        id.map(|id| Codec {
+            name: format!("numcodecs.{id}"),
+            configuration: Some(config.into_iter().collect()),
+        })
+        .ok_or_else(|| invalid("codec without id".to_string()))
+    };
+    let mut array_codecs = Vec::new();
+    let mut bytes_codecs = Vec::new();
+    if zarray.order.as_deref() == Some("F") {
+        let order: Vec<_> = (0..zarray.shape.len()).rev().collect();
+        array_codecs.push(Codec {
+            name: "transpose".to_string(),
+            configuration: Some(HashMap::from([("order".to_string(), order.into())])),
+        });
+    }
+    let mut array_to_bytes = Codec {
+        name: "bytes".to_string(),
+        configuration: endian
+            .map(|endian| HashMap::from([("endian".to_string(), Value::from(endian))])),
+    };
+    for filter in zarray.filters.unwrap_or_default() {
+        match filter.get("id").and_then(Value::as_str) {
+            Some(id @ ("vlen-utf8" | "vlen-bytes")) => {
+                array_to_bytes = Codec { name: id.to_string(), configuration: None }
+            }
+            Some(id) if BYTES_TO_BYTES_CODECS.contains(&id) => {
+                bytes_codecs.push(codec(filter)?)
+            }
+            _ => array_codecs.push(codec(filter)?),
+        }
+    }
+    if let Some(compressor) = zarray.compressor {
+        bytes_codecs.push(codec(compressor)?);
+    }
+    let codecs = array_codecs
+        .into_iter()
+        .chain(std::iter::once(array_to_bytes))
+        .chain(bytes_codecs)
+        .collect();
+
+    let dimension_names = match attrs.remove("_ARRAY_DIMENSIONS") {
+        Some(Value::Array(names)) => {
+            Some(names.iter().map(|n| n.as_str().map(str::to_string)).collect())
+        }
+        _ => None,
+    };
+
+    Ok(ZarrArrayMetadata {
+        shape: zarray.shape,
+        data_type,
+        chunk_shape: ChunkShape(chunk_shape),
+        chunk_key_encoding: ChunkKeyEncoding::Slash,
+        fill_value,
+        codecs,
+        storage_transformers: None,
+        dimension_names,
+    })
+}
+
+/// Numcodecs codecs that transform bytes into bytes, used after the `bytes` codec
+const BYTES_TO_BYTES_CODECS: &[&str] = &[
+    "adler32",
+    "blosc",
+    "bz2",
+    "crc32",
+    "fletcher32",
+    "gzip",
+    "jenkins_lookup3",
+    "lz4",
+    "lzma",
+    "shuffle",
+    "zlib",
+    "zstd",
+];
+
+/// Parse a numpy dtype string like `<f8`, returning the endianness and the data type
+fn parse_dtype(dtype: &str) -> Option<(Option<&'static str>, DataType)> {
+    let (endian, typestr) = match dtype.split_at_checked(1)? {
+        ("<", rest) => (Some("little"), rest),
+        (">", rest) => (Some("big"), rest),
+        ("|" | "=", rest) => (None, rest),
+        _ => (None, dtype),
+    };
+    let data_type = match typestr {
+        "b1" => DataType::Bool,
+        "i1" => DataType::Int8,
+        "i2" => DataType::Int16,
+        "i4" => DataType::Int32,
+        "i8" => DataType::Int64,
+        "u1" => DataType::UInt8,
+        "u2" => DataType::UInt16,
+        "u4" => DataType::UInt32,
+        "u8" => DataType::UInt64,
+        "f2" => DataType::Float16,
+        "f4" => DataType::Float32,
+        "f8" => DataType::Float64,
+        "c8" => DataType::Complex64,
+        "c16" => DataType::Complex128,
+        "O" => DataType::String,
+        _ => return None,
+    };
+    // the byte order doesn't matter for single byte types
+    let endian = match data_type {
+        DataType::Bool | DataType::Int8 | DataType::UInt8 | DataType::String => None,
+        _ => endian.or(Some("little")),
+    };
+    Some((endian, data_type))
+}
+
+/// Zarr v3 requires a fill value, Zarr v2 arrays without one get zero
+fn default_fill_value(data_type: &DataType) -> FillValue {
+    match data_type {
+        DataType::Bool => FillValue::Bool(false),
+        DataType::Int8 => FillValue::Int8(0),
+        DataType::Int16 => FillValue::Int16(0),
+        DataType::Int32 => FillValue::Int32(0),
+        DataType::Int64 => FillValue::Int64(0),
+        DataType::UInt8 => FillValue::UInt8(0),
+        DataType::UInt16 => FillValue::UInt16(0),
+        DataType::UInt32 => FillValue::UInt32(0),
+        DataType::UInt64 => FillValue::UInt64(0),
+        DataType::Float16 => FillValue::Float16(0.0),
+        DataType::Float32 => FillValue::Float32(0.0),
+        DataType::Float64 => FillValue::Float64(0.0),
+        DataType::Complex64 => FillValue::Complex64(0.0, 0.0),
+        DataType::Complex128 => FillValue::Complex128(0.0, 0.0),
+        DataType::String => FillValue::String(String::new()),
+        DataType::Bytes => FillValue::Bytes(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType, Int64Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        format::{snapshot::NodeData, ByteRange},
+        repository::get_chunk,
+        ObjectStorage, Storage,
+    };
+
+    async fn new_repo() -> Result<Repository, Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        Ok(Repository::init(storage, false).await?.build())
+    }
+
+    async fn read(
+        repo: &Repository,
+        path: &str,
+        coords: Vec<u32>,
+    ) -> Result<Option<Bytes>, Box<dyn Error>> {
+        let reader = repo
+            .get_chunk_reader(&path.try_into()?, &ChunkIndices(coords), &ByteRange::ALL)
+            .await?;
+        Ok(get_chunk(reader).await?)
+    }
+
+    fn zarray() -> Value {
+        json!({
+            "zarr_format": 2,
+            "shape": [4, 4],
+            "chunks": [2, 2],
+            "dtype": "<i2",
+            "fill_value": null,
+            "order": "C",
+            "compressor": null,
+            "filters": null,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_import_json_v0() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let file = dir.path().join("data.nc");
+        std::fs::write(&file, (0u8..32).collect::<Vec<_>>())?;
+        let url = format!("file://{}", file.display());
+
+        let refs = json!({
+            ".zgroup": r#"{"zarr_format": 2}"#,
+            ".zattrs": r#"{"title": "test"}"#,
+            "data/.zarray": zarray().to_string(),
+            "data/.zattrs": r#"{"_ARRAY_DIMENSIONS": ["x", "y"], "units": "m"}"#,
+            "data/0.0": [url, 0, 8],
+            "data/0.1": [file.display().to_string(), 8, 8],
+            "data/1.1": format!(
+                "base64:{}",
+                base64::engine::general_purpose::STANDARD.encode(b"inline!!")
+            ),
+        });
+        let mut repo = new_repo().await?;
+        let report = import_kerchunk_json(&mut repo, refs.to_string().as_bytes()).await?;
+        assert_eq!(
+            report,
+            KerchunkImportReport {
+                groups: 1,
+                arrays: 1,
+                virtual_chunks: 2,
+                inline_chunks: 1
+            }
+        );
+
+        let root = repo.get_group(&Path::root()).await?;
+        assert_eq!(
+            repo.load_user_attributes(root.user_attributes).await?,
+            Some(UserAttributes { parsed: json!({"title": "test"}) })
+        );
+        let array = repo.get_array(&"/data".try_into()?).await?;
+        assert_eq!(
+            repo.load_user_attributes(array.user_attributes.clone()).await?,
+            Some(UserAttributes { parsed: json!({"units": "m"}) })
+        );
+        let NodeData::Array(metadata, _) = array.node_data else { panic!() };
+        assert_eq!(metadata.data_type, DataType::Int16);
+        assert_eq!(metadata.fill_value, FillValue::Int16(0));
+        assert_eq!(
+            metadata.dimension_names,
+            Some(vec![Some("x".to_string()), Some("y".to_string())])
+        );
+
+        assert_eq!(read(&repo, "/data", vec![0, 0]).await?, Some((0u8..8).collect()));
+        assert_eq!(read(&repo, "/data", vec![0, 1]).await?, Some((8u8..16).collect()));
+        assert_eq!(read(&repo, "/data", vec![1, 1]).await?, Some("inline!!".into()));
+        assert_eq!(read(&repo, "/data", vec![1, 0]).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_json_v1() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("data.nc"), (0u8..32).collect::<Vec<_>>())?;
+
+        let mut zarray = zarray();
+        zarray["dimension_separator"] = "/".into();
+        let refs = json!({
+            "version": 1,
+            "templates": {"u": format!("file://{}", dir.path().display())},
+            "refs": {
+                ".zgroup": {"zarr_format": 2},
+                "group/data/.zarray": zarray.to_string(),
+                "group/data/1/0": ["{{u}}/data.nc", 16, 8],
+            },
+        });
+        // the repository already has a root group
+        let mut repo = new_repo().await?;
+        repo.add_group(Path::root()).await?;
+        let report = import_kerchunk_json(&mut repo, refs.to_string().as_bytes()).await?;
+        // the intermediate group is created without a .zgroup
+        assert_eq!(report.groups, 1);
+        repo.get_group(&"/group".try_into()?).await?;
+        assert_eq!(
+            read(&repo, "/group/data", vec![1, 0]).await?,
+            Some((16u8..24).collect())
+        );
+
+        let refs = json!({"version": 1, "gen": [{"key": "a/{{i}}"}], "refs": {}});
+        assert!(matches!(
+            import_kerchunk_json(&mut new_repo().await?, refs.to_string().as_bytes())
+                .await,
+            Err(KerchunkError::Unsupported(_))
+        ));
+        let refs = json!({"data/0.0": ["s3://bucket/whole-file.nc"]});
+        assert!(matches!(
+            import_kerchunk_json(&mut new_repo().await?, refs.to_string().as_bytes())
+                .await,
+            Err(KerchunkError::Unsupported(_))
+        ));
+        Ok(())
+    }
+
+    /// A parquet reference row: path, offset, size, raw
+    type ParquetRef<'a> = (Option<&'a str>, i64, i64, Option<&'a [u8]>);
+
+    fn write_parquet_refs(
+        file: &std::path::Path,
+        refs: &[ParquetRef<'_>],
+    ) -> Result<(), Box<dyn Error>> {
+        let schema = parse_message_type(
+            "message refs {
+                OPTIONAL BYTE_ARRAY path (UTF8);
+                REQUIRED INT64 offset;
+                REQUIRED INT64 size;
+                OPTIONAL BYTE_ARRAY raw;
+            }",
+        )?;
+        let mut writer = SerializedFileWriter::new(
+            File::create(file)?,
+            Arc::new(schema),
+            Arc::new(WriterProperties::builder().build()),
+        )?;
+        let mut row_group = writer.next_row_group()?;
+        let optional = |values: Vec<Option<&[u8]>>| {
+            let levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+            let values: Vec<ByteArray> =
+                values.into_iter().flatten().map(|v| v.to_vec().into()).collect();
+            (values, levels)
+        };
+        let (paths, levels) =
+            optional(refs.iter().map(|r| r.0.map(str::as_bytes)).collect());
+        let mut column = row_group.next_column()?.unwrap();
+        column.typed::<ByteArrayType>().write_batch(&paths, Some(&levels), None)?;
+        column.close()?;
+        let offsets: Vec<i64> = refs.iter().map(|r| r.1).collect();
+        let sizes: Vec<i64> = refs.iter().map(|r| r.2).collect();
+        for values in [offsets, sizes] {
+            let mut column = row_group.next_column()?.unwrap();
+            column.typed::<Int64Type>().write_batch(&values, None, None)?;
+            column.close()?;
+        }
+        let (raws, levels) = optional(refs.iter().map(|r| r.3).collect());
+        let mut column = row_group.next_column()?.unwrap();
+        column.typed::<ByteArrayType>().write_batch(&raws, Some(&levels), None)?;
+        column.close()?;
+        row_group.close()?;
+        writer.close()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_parquet() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let file = dir.path().join("data.nc");
+        std::fs::write(&file, (0u8..32).collect::<Vec<_>>())?;
+        let url = format!("file://{}", file.display());
+
+        let refs = dir.path().join("refs.parq");
+        std::fs::create_dir_all(refs.join("data"))?;
+        std::fs::write(
+            refs.join(".zmetadata"),
+            json!({
+                "metadata": {
+                    ".zgroup": {"zarr_format": 2},
+                    "data/.zarray": zarray().to_string(),
+                    "data/.zattrs": {"_ARRAY_DIMENSIONS": ["x", "y"]},
+                },
+                "record_size": 3,
+            })
+            .to_string(),
+        )?;
+        // chunks are numbered in C order over the 2x2 grid, 3 per file
+        write_parquet_refs(
+            &refs.join("data").join("refs.0.parq"),
+            &[(Some(&url), 0, 8), (None, 0, 0), (Some(&url), 24, 8)].map(
+                |(path, offset, size)| (path.map(String::as_str), offset, size, None),
+            ),
+        )?;
+        write_parquet_refs(
+            &refs.join("data").join("refs.1.parq"),
+            &[(None, 0, 0, Some(b"inline!!".as_slice()))],
+        )?;
+
+        let mut repo = new_repo().await?;
+        let report = import_kerchunk_parquet(&mut repo, &refs).await?;
+        assert_eq!(
+            report,
+            KerchunkImportReport {
+                groups: 1,
+                arrays: 1,
+                virtual_chunks: 2,
+                inline_chunks: 1
+            }
+        );
+        assert_eq!(read(&repo, "/data", vec![0, 0]).await?, Some((0u8..8).collect()));
+        assert_eq!(read(&repo, "/data", vec![0, 1]).await?, None);
+        assert_eq!(read(&repo, "/data", vec![1, 0]).await?, Some((24u8..32).collect()));
+        assert_eq!(read(&repo, "/data", vec![1, 1]).await?, Some("inline!!".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_metadata_codecs() -> Result<(), Box<dyn Error>> {
+        let zarray: ZArray = serde_json::from_value(json!({
+            "shape": [10, 20],
+            "chunks": [5, 20],
+            "dtype": ">f4",
+            "fill_value": "NaN",
+            "order": "F",
+            "compressor": {"id": "zlib", "level": 4},
+            "filters": [
+                {"id": "shuffle", "elementsize": 4},
+                {"id": "fixedscaleoffset", "scale": 10, "offset": 0},
+            ],
+        }))?;
+        let metadata = array_metadata("data/.zarray", zarray, &mut Default::default())?;
+        assert!(matches!(metadata.fill_value, FillValue::Float32(f) if f.is_nan()));
+        assert_eq!(
+            metadata.codecs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec![
+                "transpose",
+                "numcodecs.fixedscaleoffset",
+                "bytes",
+                "numcodecs.shuffle",
+                "numcodecs.zlib"
+            ]
+        );
+        assert_eq!(
+            metadata.codecs[2].configuration,
+            Some(HashMap::from([("endian".to_string(), json!("big"))]))
+        );
+        assert_eq!(
+            metadata.codecs[4].configuration,
+            Some(HashMap::from([("level".to_string(), json!(4))]))
+        );
+
+        let zarray: ZArray = serde_json::from_value(
+            json!({"shape": [1], "chunks": [1], "dtype": "<U8", "fill_value": ""}),
+        )?;
+        assert!(matches!(
+            array_metadata("text/.zarray", zarray, &mut Default::default()),
+            Err(KerchunkError::InvalidMetadata { .. })
+        ));
+        Ok(())
+    }
+}
@@ -19,7 +19,9 @@
 //!   These datastructures use Arrow RecordBatches for representation.
 pub mod change_set;
 pub mod format;
+pub mod kerchunk;
 pub mod metadata;
+pub mod ops;
 pub mod refs;
 pub mod repository;
 pub mod storage;
@@ -0,0 +1,307 @@
+//! Rewrite the manifests of a branch tip into a compact layout.
+//!
+//! After many small commits the chunks of an array can be spread over manifests that cover
+//! overlapping or unknown extents, and reads need to fetch more manifests than needed.
+//! Compaction writes, for every such array, one manifest per block of the chunk grid, with
+//! at most [`CompactionConfig::max_chunks_per_manifest`] chunks sorted by node and
+//! coordinates, and with their extents recorded. The result is committed as a new snapshot
+//! on the branch. Chunks are not read or written, and the replaced manifests are left in
+//! storage until they are reclaimed with [`super::gc::garbage_collect`].
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+};
+
+use chrono::Utc;
+use thiserror::Error;
+
+use crate::{
+    change_set::ChangeSet,
+    format::{
+        format_constants,
+        manifest::ManifestRef,
+        snapshot::{
+            ManifestFileInfo, NodeData, NodeSnapshot, Snapshot, SnapshotProperties,
+            ZarrArrayMetadata,
+        },
+        ManifestId, SnapshotId,
+    },
+    refs::{fetch_branch_tip, update_branch, RefError},
+    repository::{extents_block, write_node_manifests, RepositoryError},
+    RepositoryConfig, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum CompactionError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type CompactionResult<A> = Result<A, CompactionError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionConfig {
+    /// The maximum number of chunks in each of the new manifests
+    pub max_chunks_per_manifest: u32,
+    /// The commit message of the new snapshot
+    pub message: String,
+    pub properties: Option<SnapshotProperties>,
+    pub unsafe_overwrite_refs: bool,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            max_chunks_per_manifest: RepositoryConfig::default().max_chunks_per_manifest,
+            message: "compact manifests".to_string(),
+            properties: None,
+            unsafe_overwrite_refs: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub old_tip: SnapshotId,
+    pub new_tip: SnapshotId,
+    /// Number of arrays that got new manifests
+    pub arrays: usize,
+    /// Manifests no longer referenced by the new tip
+    pub manifests_replaced: usize,
+    pub manifests_written: usize,
+}
+
+/// Compact the manifests of the tip of `branch`, committing the result as a new snapshot
+///
+/// Arrays that already have one manifest per block are left untouched. If no array needs
+/// compaction, no snapshot is written and the report has the same old and new tip.
+pub async fn compact_manifests(
+    storage: &(dyn Storage + Send + Sync),
+    branch: &str,
+    config: &CompactionConfig,
+) -> CompactionResult<CompactionReport> {
+    let old_tip = fetch_branch_tip(storage, branch).await?.snapshot;
+    let snapshot = storage.fetch_snapshot(&old_tip).await?;
+    let max_chunks = config.max_chunks_per_manifest;
+
+    let mut report = CompactionReport {
+        old_tip: old_tip.clone(),
+        new_tip: old_tip,
+        arrays: 0,
+        manifests_replaced: 0,
+        manifests_written: 0,
+    };
+    let no_changes = ChangeSet::default();
+    let mut nodes = Vec::new();
+    for node in snapshot.iter() {
+        let node = match &node.node_data {
+            NodeData::Array(meta, manifests)
+                if !is_compacted(meta, manifests, max_chunks) =>
+            {
+                let mut refs =
+                    write_node_manifests(storage, &no_changes, node.clone(), max_chunks)
+                        .await?;
+                refs.sort_by(|a, b| a.extents.0.cmp(&b.extents.0));
+                report.arrays += 1;
+                report.manifests_written += refs.len();
+                NodeSnapshot {
+                    node_data: NodeData::Array(meta.clone(), refs),
+                    ..node.clone()
+                }
+            }
+            _ => node.clone(),
+        };
+        nodes.push(node);
+    }
+    if report.arrays == 0 {
+        return Ok(report);
+    }
+
+    let manifest_ids: BTreeSet<&ManifestId> = nodes
+        .iter()
+        .filter_map(|node| match &node.node_data {
+            NodeData::Array(_, manifests) => Some(manifests),
+            NodeData::Group => None,
+        })
+        .flatten()
+        .map(|mref| &mref.object_id)
+        .collect();
+    report.manifests_replaced = snapshot
+        .manifest_files
+        .iter()
+        .filter(|info| !manifest_ids.contains(&info.id))
+        .count();
+    let manifest_files = manifest_ids
+        .into_iter()
+        .map(|id| {
+            snapshot.manifest_files.iter().find(|info| &info.id == id).cloned().unwrap_or(
+                ManifestFileInfo {
+                    id: id.clone(),
+                    format_version: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+                },
+            )
+        })
+        .collect();
+
+    let mut new_snapshot = Snapshot::from_iter(
+        snapshot.as_ref(),
+        config.properties.clone(),
+        manifest_files,
+        snapshot.attribute_files.clone(),
+        nodes,
+    );
+    new_snapshot.metadata.message = config.message.clone();
+    new_snapshot.metadata.written_at = Utc::now();
+    let new_snapshot = Arc::new(new_snapshot);
+    let new_tip = new_snapshot.metadata.id.clone();
+    storage.write_snapshot(new_tip.clone(), new_snapshot).await?;
+
+    update_branch(
+        storage,
+        branch,
+        new_tip.clone(),
+        Some(&report.old_tip),
+        config.unsafe_overwrite_refs,
+    )
+    .await?;
+
+    Ok(CompactionReport { new_tip, ..report })
+}
+
+/// An array is compacted if each of its manifests covers a different block of the grid
+fn is_compacted(
+    meta: &ZarrArrayMetadata,
+    manifests: &[ManifestRef],
+    max_chunks_per_manifest: u32,
+) -> bool {
+    let mut blocks = HashSet::new();
+    manifests.iter().all(|mref| {
+        extents_block(&mref.extents, meta, max_chunks_per_manifest)
+            .map(|block| blocks.insert(block))
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        format::{
+            manifest::{ChunkPayload, ManifestExtents},
+            snapshot::ZarrArrayMetadata,
+            ChunkIndices, Path,
+        },
+        metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+        refs::Ref,
+        ObjectStorage, Repository,
+    };
+
+    use super::*;
+
+    fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![4, 4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(1).unwrap(),
+                NonZeroU64::new(1).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    async fn array_manifests(repo: &Repository, path: &Path) -> Vec<ManifestRef> {
+        match repo.get_node(path).await.unwrap().node_data {
+            NodeData::Array(_, manifests) => manifests,
+            NodeData::Group => panic!("not an array"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compact_manifests() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_chunks_per_manifest(1)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), array_metadata()).await?;
+        repo.commit(Ref::DEFAULT_BRANCH, "create array", None).await?;
+
+        // small commits, with one manifest per chunk
+        for i in 0..4u32 {
+            repo.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![i, i]),
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8]))),
+            )
+            .await?;
+            repo.commit(Ref::DEFAULT_BRANCH, "write", None).await?;
+        }
+        let old_tip = repo.snapshot_id().clone();
+        let mut old_chunks: Vec<_> = repo.all_chunks().await?.try_collect().await?;
+        old_chunks.sort();
+        assert_eq!(array_manifests(&repo, &array).await.len(), 4);
+
+        let config =
+            CompactionConfig { max_chunks_per_manifest: 16, ..Default::default() };
+        let report =
+            compact_manifests(storage.as_ref(), Ref::DEFAULT_BRANCH, &config).await?;
+        assert_eq!(report.old_tip, old_tip);
+        assert_ne!(report.new_tip, old_tip);
+        assert_eq!(report.arrays, 1);
+        assert_eq!(report.manifests_written, 1);
+        assert_eq!(report.manifests_replaced, 4);
+
+        let repo = Repository::from_branch_tip(Arc::clone(&storage), Ref::DEFAULT_BRANCH)
+            .await?
+            .build();
+        assert_eq!(repo.snapshot_id(), &report.new_tip);
+        let manifests = array_manifests(&repo, &array).await;
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(
+            manifests[0].extents,
+            ManifestExtents::from_coords([
+                &ChunkIndices(vec![0, 0]),
+                &ChunkIndices(vec![3, 3])
+            ])
+        );
+        let mut new_chunks: Vec<_> = repo.all_chunks().await?.try_collect().await?;
+        new_chunks.sort();
+        assert_eq!(new_chunks, old_chunks);
+        for i in 0..4u32 {
+            assert_eq!(
+                repo.get_chunk_ref(&array, &ChunkIndices(vec![i, i])).await?,
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8])))
+            );
+        }
+        let snapshot = storage.fetch_snapshot(&report.new_tip).await?;
+        assert_eq!(snapshot.metadata.message, config.message);
+        assert_eq!(snapshot.short_term_history.front().unwrap().id, old_tip);
+        assert_eq!(snapshot.manifest_files.len(), 1);
+
+        // compacting again has nothing to do
+        let again =
+            compact_manifests(storage.as_ref(), Ref::DEFAULT_BRANCH, &config).await?;
+        assert_eq!(again.old_tip, report.new_tip);
+        assert_eq!(again.new_tip, report.new_tip);
+        assert_eq!(again.arrays, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Rewrite the history of a branch, collapsing old snapshots into a single base snapshot.
+//!
+//! Snapshots written before the cutoff are replaced by one snapshot that has the contents of
+//! the newest of them, and sits directly on top of the initial repository commit. Newer
+//! snapshots are rewritten on top of it, keeping their contents, messages and properties.
+//! The original snapshots are not deleted, tags keep pointing to them. Once they are no
+//! longer referenced they can be reclaimed with [`super::gc::garbage_collect`].
+use std::{collections::HashSet, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use thiserror::Error;
+
+use crate::{
+    format::{snapshot::Snapshot, SnapshotId},
+    refs::{fetch_branch_tip, fetch_tag, list_refs, update_branch, Ref, RefError},
+    repository::{snapshot_ancestry, RepositoryError},
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum ExpirationError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("squashing the history would orphan tags: {0:?}")]
+    TagsWouldBeOrphaned(Vec<String>),
+}
+
+pub type ExpirationResult<A> = Result<A, ExpirationError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExpirationConfig {
+    /// Rewrite the branch even if some tags point to snapshots that will no longer be part
+    /// of its history. The tags keep their targets.
+    pub allow_orphaned_tags: bool,
+    /// Write the new branch ref without the conditional create that detects concurrent
+    /// updates, see [`crate::RepositoryConfig::unsafe_overwrite_refs`]. A commit made to
+    /// the branch while its history is rewritten can be lost.
+    pub unsafe_overwrite_refs: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpirationReport {
+    pub old_tip: SnapshotId,
+    pub new_tip: SnapshotId,
+    /// Snapshots that are no longer part of the branch history
+    pub expired: Vec<SnapshotId>,
+}
+
+/// Collapse the snapshots of `branch` written before `older_than` into a single snapshot
+///
+/// If there is nothing to collapse, the branch is left untouched and the report has the
+/// same old and new tip.
+pub async fn squash_history(
+    storage: Arc<dyn Storage + Send + Sync>,
+    branch: &str,
+    older_than: DateTime<Utc>,
+    config: &ExpirationConfig,
+) -> ExpirationResult<ExpirationReport> {
+    let old_tip = fetch_branch_tip(storage.as_ref(), branch).await?.snapshot;
+
+    // snapshots newer than the cutoff, from the tip backwards, they need to be rewritten
+    let mut newer = Vec::new();
+    let mut base = storage.fetch_snapshot(&old_tip).await?;
+    while base.metadata.written_at >= older_than {
+        let Some(parent) = base.short_term_history.front() else { break };
+        let parent = storage.fetch_snapshot(&parent.id).await?;
+        newer.push(base);
+        base = parent;
+    }
+
+    let unchanged =
+        ExpirationReport { old_tip: old_tip.clone(), new_tip: old_tip, expired: vec![] };
+    // the initial commit, or its direct children, are already as short as it gets
+    if base.total_parents <= 1 {
+        return Ok(unchanged);
+    }
+
+    let mut old_history: Vec<SnapshotId> =
+        newer.iter().map(|s| s.metadata.id.clone()).collect();
+    let ancestry = snapshot_ancestry(Arc::clone(&storage), &base.metadata.id).await?;
+    old_history.extend(ancestry.map_ok(|meta| meta.id).try_collect::<Vec<_>>().await?);
+    // the initial commit stays in the history
+    let root_id = old_history.pop().unwrap_or_else(|| base.metadata.id.clone());
+
+    if !config.allow_orphaned_tags {
+        let old_history: HashSet<&SnapshotId> = old_history.iter().collect();
+        let mut orphaned = Vec::new();
+        for r in list_refs(storage.as_ref()).await? {
+            if let Ref::Tag(name) = r {
+                let target = fetch_tag(storage.as_ref(), name.as_str()).await?.snapshot;
+                if old_history.contains(&target) {
+                    orphaned.push(name);
+                }
+            }
+        }
+        if !orphaned.is_empty() {
+            orphaned.sort();
+            return Err(ExpirationError::TagsWouldBeOrphaned(orphaned));
+        }
+    }
+
+    let root = storage.fetch_snapshot(&root_id).await?;
+    let mut new_tip = rewrite_snapshot(storage.as_ref(), &root, &base).await?;
+    for snapshot in newer.iter().rev() {
+        new_tip = rewrite_snapshot(storage.as_ref(), &new_tip, snapshot).await?;
+    }
+
+    update_branch(
+        storage.as_ref(),
+        branch,
+        new_tip.metadata.id.clone(),
+        Some(&unchanged.old_tip),
+        config.unsafe_overwrite_refs,
+    )
+    .await?;
+
+    Ok(ExpirationReport {
+        new_tip: new_tip.metadata.id.clone(),
+        expired: old_history,
+        ..unchanged
+    })
+}
+
+/// Write a copy of `snapshot` with `parent` as its parent snapshot
+async fn rewrite_snapshot(
+    storage: &(dyn Storage + Send + Sync),
+    parent: &Snapshot,
+    snapshot: &Snapshot,
+) -> ExpirationResult<Arc<Snapshot>> {
+    let mut new_snapshot = Snapshot::from_iter(
+        parent,
+        Some(snapshot.properties.clone()),
+        snapshot.manifest_files.clone(),
+        snapshot.attribute_files.clone(),
+        snapshot.iter().cloned(),
+    );
+    new_snapshot.metadata.message = snapshot.metadata.message.clone();
+    new_snapshot.metadata.written_at = snapshot.metadata.written_at;
+    new_snapshot.metadata.merged_parents = snapshot.metadata.merged_parents.clone();
+    new_snapshot.started_at = snapshot.started_at;
+    new_snapshot.virtual_prefixes = snapshot.virtual_prefixes.clone();
+
+    let new_snapshot = Arc::new(new_snapshot);
+    storage
+        .write_snapshot(new_snapshot.metadata.id.clone(), Arc::clone(&new_snapshot))
+        .await?;
+    Ok(new_snapshot)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, time::Duration};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        format::{
+            manifest::ChunkPayload, snapshot::ZarrArrayMetadata, ChunkIndices, Path,
+        },
+        metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+        ops::gc::{garbage_collect, GCConfig},
+        ObjectStorage, Repository,
+    };
+
+    use super::*;
+
+    fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    async fn messages(repo: &Repository) -> Vec<String> {
+        repo.ancestry()
+            .await
+            .unwrap()
+            .map_ok(|meta| meta.message)
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_squash_history() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), array_metadata()).await?;
+
+        let mut cutoff = Utc::now();
+        let mut tagged = None;
+        for n in 1..=5u8 {
+            repo.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![0]),
+                Some(ChunkPayload::Inline(vec![n].into())),
+            )
+            .await?;
+            let id =
+                repo.commit(Ref::DEFAULT_BRANCH, &format!("commit {n}"), None).await?;
+            if n == 2 {
+                tagged = Some(id);
+            }
+            if n == 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                cutoff = Utc::now();
+            }
+        }
+        let tagged = tagged.unwrap();
+        repo.tag("v2", &tagged).await?;
+        let old_tip = repo.snapshot_id().clone();
+
+        let res = squash_history(
+            Arc::clone(&storage),
+            Ref::DEFAULT_BRANCH,
+            cutoff,
+            &ExpirationConfig::default(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(ExpirationError::TagsWouldBeOrphaned(tags)) if tags == vec!["v2".to_string()]
+        ));
+
+        let config = ExpirationConfig { allow_orphaned_tags: true, ..Default::default() };
+        let report =
+            squash_history(Arc::clone(&storage), Ref::DEFAULT_BRANCH, cutoff, &config)
+                .await?;
+        assert_eq!(report.old_tip, old_tip);
+        assert_ne!(report.new_tip, old_tip);
+        assert_eq!(report.expired.len(), 5);
+        assert!(report.expired.contains(&tagged));
+
+        let repo = Repository::from_branch_tip(Arc::clone(&storage), Ref::DEFAULT_BRANCH)
+            .await?
+            .build();
+        assert_eq!(repo.snapshot_id(), &report.new_tip);
+        assert_eq!(
+            messages(&repo).await,
+            vec!["commit 5", "commit 4", "commit 3", Snapshot::INITIAL_COMMIT_MESSAGE]
+        );
+        assert_eq!(
+            repo.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Inline(vec![5].into()))
+        );
+
+        // the tag keeps its target and its history
+        let tag = Repository::from_tag(Arc::clone(&storage), "v2").await?.build();
+        assert_eq!(tag.snapshot_id(), &tagged);
+        assert_eq!(messages(&tag).await.len(), 3);
+        assert_eq!(
+            tag.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Inline(vec![2].into()))
+        );
+
+        // squashing again has nothing left to do
+        let again =
+            squash_history(Arc::clone(&storage), Ref::DEFAULT_BRANCH, cutoff, &config)
+                .await?;
+        assert_eq!(again.old_tip, again.new_tip);
+        assert!(again.expired.is_empty());
+
+        // the expired snapshots not kept alive by the tag can now be collected
+        let gc = garbage_collect(storage.as_ref(), &GCConfig::default()).await?;
+        assert_eq!(gc.snapshots.len(), 3);
+        assert!(gc.manifests.is_empty());
+        Ok(())
+    }
+}
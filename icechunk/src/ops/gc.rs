@@ -0,0 +1,284 @@
+//! Garbage collection of snapshots, manifests and chunks that are no longer reachable.
+//!
+//! An object is reachable if it can be found starting from the tip of a branch, or from a
+//! tag, following snapshot parents all the way to the initial commit. Everything else under
+//! the `snapshots/`, `manifests/`, `attributes/` and `chunks/` prefixes is garbage.
+//!
+//! Garbage collection doesn't coordinate with writers. Chunks and snapshots written by a
+//! session that hasn't committed yet are not reachable, use
+//! [`GCConfig::keep_newer_than`] to protect them.
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use thiserror::Error;
+
+use crate::{
+    format::{manifest::ChunkPayload, AttributesId, ChunkId, ManifestId, SnapshotId},
+    refs::{fetch_branch_tip, fetch_tag, list_refs, Ref, RefError},
+    storage::ListInfo,
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum GCError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+}
+
+pub type GCResult<A> = Result<A, GCError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GCConfig {
+    /// Objects written after this time are never deleted, and the snapshots among them are
+    /// treated as reachable, together with everything they point to.
+    pub keep_newer_than: Option<DateTime<Utc>>,
+    /// Only report what would be deleted, without deleting anything
+    pub dry_run: bool,
+}
+
+/// The objects found to be unreachable during garbage collection
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GCReport {
+    pub snapshots: Vec<SnapshotId>,
+    pub manifests: Vec<ManifestId>,
+    pub attributes: Vec<AttributesId>,
+    pub chunks: Vec<ChunkId>,
+    /// Total size of the unreachable objects, this is the space freed by the collection
+    pub bytes: u64,
+    /// `false` for dry runs, unreachable objects are then left in place
+    pub deleted: bool,
+}
+
+#[derive(Debug, Default)]
+struct Reachable {
+    snapshots: HashSet<SnapshotId>,
+    manifests: HashSet<ManifestId>,
+    attributes: HashSet<AttributesId>,
+    chunks: HashSet<ChunkId>,
+}
+
+/// Find, and unless doing a dry run delete, all objects unreachable from branches and tags
+pub async fn garbage_collect(
+    storage: &(dyn Storage + Send + Sync),
+    config: &GCConfig,
+) -> GCResult<GCReport> {
+    let is_recent = |info_time: &DateTime<Utc>| {
+        config.keep_newer_than.map(|limit| info_time > &limit).unwrap_or(false)
+    };
+
+    let mut roots = Vec::new();
+    for r in list_refs(storage).await? {
+        let data = match r {
+            Ref::Tag(name) => fetch_tag(storage, name.as_str()).await?,
+            Ref::Branch(name) => fetch_branch_tip(storage, name.as_str()).await?,
+        };
+        roots.push(data.snapshot);
+    }
+
+    let all_snapshots: Vec<ListInfo<SnapshotId>> =
+        storage.list_snapshots().await?.try_collect().await?;
+    roots.extend(
+        all_snapshots
+            .iter()
+            .filter(|info| is_recent(&info.created_at))
+            .map(|info| info.id.clone()),
+    );
+
+    let reachable = mark(storage, roots).await?;
+
+    let mut report = GCReport { deleted: !config.dry_run, ..GCReport::default() };
+    for info in all_snapshots {
+        if !reachable.snapshots.contains(&info.id) {
+            report.bytes += info.size_bytes;
+            report.snapshots.push(info.id);
+        }
+    }
+
+    let mut manifests = storage.list_manifests().await?;
+    while let Some(info) = manifests.try_next().await? {
+        if !reachable.manifests.contains(&info.id) && !is_recent(&info.created_at) {
+            report.bytes += info.size_bytes;
+            report.manifests.push(info.id);
+        }
+    }
+
+    let mut attributes = storage.list_attributes().await?;
+    while let Some(info) = attributes.try_next().await? {
+        if !reachable.attributes.contains(&info.id) && !is_recent(&info.created_at) {
+            report.bytes += info.size_bytes;
+            report.attributes.push(info.id);
+        }
+    }
+
+    let mut chunks = storage.list_chunks().await?;
+    while let Some(info) = chunks.try_next().await? {
+        if !reachable.chunks.contains(&info.id) && !is_recent(&info.created_at) {
+            report.bytes += info.size_bytes;
+            report.chunks.push(info.id);
+        }
+    }
+
+    if !config.dry_run {
+        // snapshots go first, so an interrupted collection never leaves a snapshot
+        // pointing to deleted manifests or chunks
+        storage.delete_snapshots(report.snapshots.clone()).await?;
+        storage.delete_manifests(report.manifests.clone()).await?;
+        storage.delete_attributes(report.attributes.clone()).await?;
+        storage.delete_chunks(report.chunks.clone()).await?;
+    }
+
+    Ok(report)
+}
+
+async fn mark(
+    storage: &(dyn Storage + Send + Sync),
+    roots: Vec<SnapshotId>,
+) -> GCResult<Reachable> {
+    let mut reachable = Reachable::default();
+    let mut pending = roots;
+    while let Some(snapshot_id) = pending.pop() {
+        if !reachable.snapshots.insert(snapshot_id.clone()) {
+            continue;
+        }
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        for manifest_info in snapshot.manifest_files.iter() {
+            if reachable.manifests.insert(manifest_info.id.clone()) {
+                let manifest = storage.fetch_manifests(&manifest_info.id).await?;
+                for payload in manifest.chunks().values() {
+                    if let ChunkPayload::Ref(chunk_ref) = payload {
+                        reachable.chunks.insert(chunk_ref.id.clone());
+                    }
+                }
+            }
+        }
+        reachable
+            .attributes
+            .extend(snapshot.attribute_files.iter().map(|info| info.id.clone()));
+        // the first element in the history is the parent snapshot
+        if let Some(parent) = snapshot.short_term_history.front() {
+            pending.push(parent.id.clone());
+        }
+        pending.extend(snapshot.metadata.merged_parents.iter().cloned());
+    }
+    Ok(reachable)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        format::{snapshot::ZarrArrayMetadata, ByteRange, ChunkIndices, Path},
+        metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+        ObjectStorage, Repository,
+    };
+
+    use super::*;
+
+    fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![std::num::NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    async fn write_chunk(
+        repo: &mut Repository,
+        path: &Path,
+        coord: u32,
+        data: &'static [u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let payload = repo.get_chunk_writer()(Bytes::from_static(data)).await?;
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![coord]), Some(payload))
+            .await?;
+        Ok(())
+    }
+
+    async fn object_counts(
+        storage: &(dyn Storage + Send + Sync),
+    ) -> (usize, usize, usize) {
+        let snapshots = storage.list_snapshots().await.unwrap();
+        let manifests = storage.list_manifests().await.unwrap();
+        let chunks = storage.list_chunks().await.unwrap();
+        (
+            snapshots.try_collect::<Vec<_>>().await.unwrap().len(),
+            manifests.try_collect::<Vec<_>>().await.unwrap().len(),
+            chunks.try_collect::<Vec<_>>().await.unwrap().len(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_gc() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), array_metadata()).await?;
+        write_chunk(&mut repo, &array, 0, b"zero").await?;
+        repo.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+        write_chunk(&mut repo, &array, 1, b"one").await?;
+        let tagged = repo.commit(Ref::DEFAULT_BRANCH, "second", None).await?;
+        repo.tag("v1", &tagged).await?;
+
+        // a chunk and a snapshot that never make it into a branch
+        let mut orphan = Repository::update(Arc::clone(&storage), tagged.clone())
+            .with_inline_threshold_bytes(0)
+            .build();
+        write_chunk(&mut orphan, &array, 0, b"orphan").await?;
+        let orphan_snapshot = orphan.flush("never committed", Default::default()).await?;
+
+        let before = object_counts(storage.as_ref()).await;
+        assert_eq!(before, (4, 3, 3));
+
+        let keep_all = GCConfig {
+            keep_newer_than: Some(chrono::DateTime::UNIX_EPOCH),
+            dry_run: false,
+        };
+        let report = garbage_collect(storage.as_ref(), &keep_all).await?;
+        assert_eq!(report, GCReport { deleted: true, ..GCReport::default() });
+        assert_eq!(object_counts(storage.as_ref()).await, before);
+
+        let dry_run = GCConfig { keep_newer_than: None, dry_run: true };
+        let report = garbage_collect(storage.as_ref(), &dry_run).await?;
+        assert_eq!(report.snapshots, vec![orphan_snapshot]);
+        assert_eq!(report.manifests.len(), 1);
+        assert_eq!(report.chunks.len(), 1);
+        assert!(report.bytes > 0);
+        assert!(!report.deleted);
+        assert_eq!(object_counts(storage.as_ref()).await, before);
+
+        let report = garbage_collect(storage.as_ref(), &GCConfig::default()).await?;
+        assert!(report.deleted);
+        assert_eq!(object_counts(storage.as_ref()).await, (3, 2, 2));
+
+        // everything reachable from main and the tag is still there
+        let repo = Repository::from_tag(Arc::clone(&storage), "v1").await?.build();
+        for (coord, data) in [(0, "zero"), (1, "one")] {
+            let chunk = repo.get_chunk_ref(&array, &ChunkIndices(vec![coord])).await?;
+            let ChunkPayload::Ref(chunk_ref) = chunk.unwrap() else { panic!() };
+            let bytes = storage.fetch_chunk(&chunk_ref.id, &ByteRange::ALL).await?;
+            assert_eq!(bytes, Bytes::from(data));
+        }
+
+        let report = garbage_collect(storage.as_ref(), &GCConfig::default()).await?;
+        assert_eq!(report, GCReport { deleted: true, ..GCReport::default() });
+        Ok(())
+    }
+}
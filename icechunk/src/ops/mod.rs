@@ -0,0 +1,5 @@
+//! Maintenance operations on the objects stored by a repository.
+pub mod compaction;
+pub mod expiration;
+pub mod gc;
+pub mod repack;
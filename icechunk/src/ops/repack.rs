@@ -0,0 +1,232 @@
+//! Pack small chunks written as individual objects into a few larger objects.
+//!
+//! Every chunk of the branch tip that is stored alone in its object, and is not larger than
+//! [`ChunkPackingConfig::max_chunk_size_bytes`], is copied into a pack object. The chunk
+//! references are updated in a new commit on the branch. The original objects are left in
+//! storage until they are reclaimed with [`super::gc::garbage_collect`].
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{StreamExt, TryStreamExt};
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, ChunkRef},
+        snapshot::SnapshotProperties,
+        ByteRange, ChunkId, ChunkIndices, Path, SnapshotId,
+    },
+    repository::{ChunkPacker, ChunkPackingConfig, RepositoryError},
+    Repository, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+pub enum RepackError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type RepackResult<A> = Result<A, RepackError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepackConfig {
+    pub packing: ChunkPackingConfig,
+    /// The commit message of the new snapshot
+    pub message: String,
+    pub properties: Option<SnapshotProperties>,
+    pub unsafe_overwrite_refs: bool,
+    /// How many chunks are fetched concurrently
+    pub fetch_concurrency: usize,
+}
+
+impl Default for RepackConfig {
+    fn default() -> Self {
+        Self {
+            packing: ChunkPackingConfig::default(),
+            message: "repack chunks".to_string(),
+            properties: None,
+            unsafe_overwrite_refs: false,
+            fetch_concurrency: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepackReport {
+    pub old_tip: SnapshotId,
+    pub new_tip: SnapshotId,
+    pub chunks: usize,
+    pub bytes: u64,
+    pub packs: usize,
+}
+
+/// Pack the small chunks of the tip of `branch`, committing the result as a new snapshot
+///
+/// If there are less than two chunks to pack, no snapshot is written and the report has
+/// the same old and new tip.
+pub async fn repack_chunks(
+    storage: Arc<dyn Storage + Send + Sync>,
+    branch: &str,
+    config: &RepackConfig,
+) -> RepackResult<RepackReport> {
+    let mut repo = Repository::from_branch_tip(Arc::clone(&storage), branch)
+        .await?
+        .with_unsafe_overwrite_refs(config.unsafe_overwrite_refs)
+        .build();
+    let old_tip = repo.snapshot_id().clone();
+
+    let mut references: HashMap<ChunkId, usize> = HashMap::new();
+    let mut candidates: Vec<(Path, ChunkIndices, ChunkRef)> = Vec::new();
+    {
+        let chunks = repo.all_chunks().await?;
+        futures::pin_mut!(chunks);
+        while let Some((path, chunk)) = chunks.try_next().await? {
+            if let ChunkPayload::Ref(chunk_ref) = chunk.payload {
+                *references.entry(chunk_ref.id.clone()).or_default() += 1;
+                if chunk_ref.offset == 0
+                    && chunk_ref.length <= config.packing.max_chunk_size_bytes as u64
+                {
+                    candidates.push((path, chunk.coord, chunk_ref));
+                }
+            }
+        }
+    }
+    // chunks sharing their object are already packed
+    candidates.retain(|(_, _, chunk_ref)| references.get(&chunk_ref.id) == Some(&1));
+    candidates.sort_by(|(p1, c1, _), (p2, c2, _)| (p1, c1).cmp(&(p2, c2)));
+
+    let mut report = RepackReport {
+        old_tip: old_tip.clone(),
+        new_tip: old_tip,
+        chunks: 0,
+        bytes: 0,
+        packs: 0,
+    };
+    if candidates.len() < 2 {
+        return Ok(report);
+    }
+
+    let packer =
+        ChunkPacker::new(Arc::clone(&storage), config.packing.target_pack_size_bytes);
+    let fetches = futures::stream::iter(candidates)
+        .map(|(path, coord, chunk_ref)| {
+            let storage = Arc::clone(&storage);
+            async move {
+                let range = ByteRange::from_offset_with_length(0, chunk_ref.length);
+                let data = storage.fetch_chunk(&chunk_ref.id, &range).await?;
+                Ok::<_, RepackError>((path, coord, data))
+            }
+        })
+        .buffered(config.fetch_concurrency.max(1));
+    futures::pin_mut!(fetches);
+    while let Some((path, coord, data)) = fetches.try_next().await? {
+        let payload = packer.add(&data).await?;
+        repo.set_chunk_ref(path, coord, Some(payload)).await?;
+        report.chunks += 1;
+        report.bytes += data.len() as u64;
+    }
+    report.packs = packer.finish().await?;
+
+    let new_tip =
+        repo.commit(branch, config.message.as_str(), config.properties.clone()).await?;
+    Ok(RepackReport { new_tip, ..report })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        format::snapshot::ZarrArrayMetadata,
+        metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+        refs::Ref,
+        repository::get_chunk,
+        ObjectStorage,
+    };
+
+    use super::*;
+
+    fn array_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![5],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repack_chunks() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), array_metadata()).await?;
+        let data = ["aaaa", "bbbb", "cccc", "dddd", "a large chunk"];
+        for (i, data) in data.iter().enumerate() {
+            let payload = repo.get_chunk_writer()(Bytes::from(*data)).await?;
+            repo.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![i as u32]),
+                Some(payload),
+            )
+            .await?;
+        }
+        let old_tip = repo.commit(Ref::DEFAULT_BRANCH, "write", None).await?;
+
+        let config = RepackConfig {
+            packing: ChunkPackingConfig {
+                max_chunk_size_bytes: 8,
+                target_pack_size_bytes: 10,
+            },
+            ..Default::default()
+        };
+        let report =
+            repack_chunks(Arc::clone(&storage), Ref::DEFAULT_BRANCH, &config).await?;
+        assert_eq!(report.old_tip, old_tip);
+        assert_ne!(report.new_tip, old_tip);
+        assert_eq!((report.chunks, report.bytes, report.packs), (4, 16, 2));
+
+        let repo = Repository::from_branch_tip(Arc::clone(&storage), Ref::DEFAULT_BRANCH)
+            .await?
+            .build();
+        assert_eq!(repo.snapshot_id(), &report.new_tip);
+        let mut refs = Vec::new();
+        for (i, expected) in data.iter().enumerate() {
+            let coord = ChunkIndices(vec![i as u32]);
+            let bytes =
+                get_chunk(repo.get_chunk_reader(&array, &coord, &ByteRange::ALL).await?)
+                    .await?;
+            assert_eq!(bytes, Some(Bytes::from(*expected)));
+            match repo.get_chunk_ref(&array, &coord).await? {
+                Some(ChunkPayload::Ref(chunk_ref)) => refs.push(chunk_ref),
+                other => panic!("unexpected payload {other:?}"),
+            }
+        }
+        assert_eq!(refs[0].id, refs[1].id);
+        assert_eq!(refs[2].id, refs[3].id);
+        assert_ne!(refs[1].id, refs[2].id);
+        assert_eq!((refs[3].offset, refs[3].length), (4, 4));
+        assert_eq!((refs[4].offset, refs[4].length), (0, 13));
+
+        // packed chunks are not repacked
+        let again =
+            repack_chunks(Arc::clone(&storage), Ref::DEFAULT_BRANCH, &config).await?;
+        assert_eq!(again.new_tip, report.new_tip);
+        assert_eq!(again.chunks, 0);
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     iter::{self},
+    ops::Range,
     pin::Pin,
     sync::Arc,
 };
@@ -19,19 +21,27 @@ pub use crate::{
 };
 use crate::{
     format::{
-        manifest::VirtualReferenceError, snapshot::ManifestFileInfo, ManifestId,
-        SnapshotId,
+        attributes::AttributesTable,
+        format_constants,
+        manifest::{VirtualChunkPin, VirtualReferenceError},
+        snapshot::{AttributeFileInfo, ManifestFileInfo, UserAttributesRef},
+        AttributesId, ManifestId, SnapshotId, TableOffset,
     },
     storage::virtual_ref::{
-        construct_valid_byte_range, ObjectStoreVirtualChunkResolverConfig,
-        VirtualChunkResolver,
+        construct_valid_byte_range, construct_valid_range, HttpConfig,
+        ObjectStoreVirtualChunkResolverConfig, VirtualChunkResolver,
     },
+    zarr::VersionInfo,
 };
+use async_stream::try_stream;
 use bytes::Bytes;
-use chrono::Utc;
-use futures::{future::ready, Future, FutureExt, Stream, StreamExt, TryStreamExt};
-use itertools::Either;
+use chrono::{DateTime, Utc};
+use futures::{
+    future::ready, pin_mut, Future, FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::Mutex;
 
 use crate::{
     format::{
@@ -42,13 +52,13 @@ use crate::{
             NodeData, NodeSnapshot, NodeType, Snapshot, SnapshotProperties,
             UserAttributesSnapshot,
         },
-        ByteRange, IcechunkFormatError, NodeId, ObjectId,
+        ByteRange, ChunkId, IcechunkFormatError, NodeId, ObjectId,
     },
     refs::{
         create_tag, fetch_branch_tip, fetch_tag, update_branch, BranchVersion, Ref,
         RefError,
     },
-    storage::virtual_ref::ObjectStoreVirtualChunkResolver,
+    storage::{virtual_ref::ObjectStoreVirtualChunkResolver, CacheStats, CachingConfig},
     MemCachingStorage, Storage, StorageError,
 };
 
@@ -61,11 +71,69 @@ pub struct RepositoryConfig {
     // the possibility of race conditions if this variable is set to true and there are concurrent
     // commit attempts.
     pub unsafe_overwrite_refs: bool,
+    // How many times a commit is rebased on top of the new branch tip and retried, if the
+    // branch moved concurrently. Rebasing only happens when the concurrent changes don't
+    // overlap with the ones being committed. Zero disables rebasing.
+    pub commit_rebase_attempts: u16,
+    // Each array gets one manifest for every block of the chunk grid that holds this many
+    // chunks at most. Commits only rewrite the manifests for blocks with modified chunks.
+    pub max_chunks_per_manifest: u32,
+    // User attributes larger than this, once serialized to json, are stored in a separate
+    // attributes file instead of inline in the snapshot
+    pub inline_attributes_threshold_bytes: u32,
+    // When set, chunks that are not stored inline are appended to an open pack object, which
+    // is written once it's full or at commit, instead of writing one object per chunk.
+    pub chunk_packing: Option<ChunkPackingConfig>,
+    // Partial reads of chunks up to this size fetch the whole chunk instead, so the
+    // chunk cache can answer later reads of other ranges. Zero disables promotion.
+    pub promote_partial_reads_max_bytes: u64,
+    // How reads check that the object of a virtual chunk still matches the ETag or
+    // modification time recorded in its reference
+    pub virtual_ref_integrity: VirtualRefIntegrity,
+}
+
+/// What to do when the object of a virtual chunk was modified after the reference was set
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VirtualRefIntegrity {
+    /// Fail the read with [`VirtualReferenceError::ObjectModified`]
+    #[default]
+    Strict,
+    /// Log a warning and return the current contents of the object
+    Warn,
+    /// Don't check, the ETag and modification time of references are ignored
+    Off,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkPackingConfig {
+    /// Chunks up to this size are packed together, larger chunks get their own object
+    pub max_chunk_size_bytes: u32,
+    /// A new pack object is started once the current one reaches this size
+    pub target_pack_size_bytes: u64,
+}
+
+impl Default for ChunkPackingConfig {
+    fn default() -> Self {
+        Self {
+            max_chunk_size_bytes: 1024 * 1024,
+            target_pack_size_bytes: 64 * 1024 * 1024,
+        }
+    }
 }
 
 impl Default for RepositoryConfig {
     fn default() -> Self {
-        Self { inline_chunk_threshold_bytes: 512, unsafe_overwrite_refs: false }
+        Self {
+            inline_chunk_threshold_bytes: 512,
+            unsafe_overwrite_refs: false,
+            commit_rebase_attempts: 0,
+            max_chunks_per_manifest: 100_000,
+            inline_attributes_threshold_bytes: 4096,
+            chunk_packing: None,
+            promote_partial_reads_max_bytes: 0,
+            virtual_ref_integrity: VirtualRefIntegrity::default(),
+        }
     }
 }
 
@@ -76,6 +144,8 @@ pub struct Repository {
     snapshot_id: SnapshotId,
     change_set: ChangeSet,
     virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    // the pack new chunks are appended to, when chunk packing is configured
+    chunk_packer: Option<Arc<ChunkPacker>>,
 }
 
 #[derive(Debug, Clone)]
@@ -85,6 +155,7 @@ pub struct RepositoryBuilder {
     snapshot_id: SnapshotId,
     change_set: Option<ChangeSet>,
     virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    virtual_http_config: Option<HttpConfig>,
 }
 
 impl RepositoryBuilder {
@@ -95,6 +166,7 @@ impl RepositoryBuilder {
             storage,
             change_set: None,
             virtual_ref_config: None,
+            virtual_http_config: None,
         }
     }
 
@@ -108,6 +180,24 @@ impl RepositoryBuilder {
         self
     }
 
+    pub fn with_commit_rebase_attempts(&mut self, attempts: u16) -> &mut Self {
+        self.config.commit_rebase_attempts = attempts;
+        self
+    }
+
+    pub fn with_inline_attributes_threshold_bytes(
+        &mut self,
+        threshold: u32,
+    ) -> &mut Self {
+        self.config.inline_attributes_threshold_bytes = threshold;
+        self
+    }
+
+    pub fn with_max_chunks_per_manifest(&mut self, max_chunks: u32) -> &mut Self {
+        self.config.max_chunks_per_manifest = max_chunks;
+        self
+    }
+
     pub fn with_config(&mut self, config: RepositoryConfig) -> &mut Self {
         self.config = config;
         self
@@ -121,18 +211,46 @@ impl RepositoryBuilder {
         self
     }
 
+    pub fn with_virtual_http_config(&mut self, config: HttpConfig) -> &mut Self {
+        self.virtual_http_config = Some(config);
+        self
+    }
+
+    pub fn with_chunk_packing(&mut self, config: ChunkPackingConfig) -> &mut Self {
+        self.config.chunk_packing = Some(config);
+        self
+    }
+
+    pub fn with_promote_partial_reads_max_bytes(&mut self, max_bytes: u64) -> &mut Self {
+        self.config.promote_partial_reads_max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_virtual_ref_integrity(
+        &mut self,
+        integrity: VirtualRefIntegrity,
+    ) -> &mut Self {
+        self.config.virtual_ref_integrity = integrity;
+        self
+    }
+
     pub fn with_change_set(&mut self, change_set_bytes: ChangeSet) -> &mut Self {
         self.change_set = Some(change_set_bytes);
         self
     }
 
     pub fn build(&self) -> Repository {
+        let mut virtual_resolver =
+            ObjectStoreVirtualChunkResolver::new(self.virtual_ref_config.clone());
+        if let Some(config) = &self.virtual_http_config {
+            virtual_resolver = virtual_resolver.with_http_config(config.clone());
+        }
         Repository::new(
             self.config.clone(),
             self.storage.clone(),
             self.snapshot_id.clone(),
             self.change_set.clone(),
-            self.virtual_ref_config.clone(),
+            virtual_resolver,
         )
     }
 }
@@ -150,6 +268,8 @@ pub enum RepositoryError {
     NodeNotFound { path: Path, message: String },
     #[error("there is not an array at `{node:?}`: {message}")]
     NotAnArray { node: NodeSnapshot, message: String },
+    #[error("invalid chunk coordinates `{coords:?}` for array at `{path}`: {message}")]
+    InvalidChunkCoordinates { path: Path, coords: ChunkIndices, message: String },
     #[error("there is not a group at `{node:?}`: {message}")]
     NotAGroup { node: NodeSnapshot, message: String },
     #[error("node already exists at `{node:?}`: {message}")]
@@ -172,6 +292,86 @@ pub enum RepositoryError {
     SerializationError(#[from] rmp_serde::encode::Error),
     #[error("error in repository deserialization `{0}`")]
     DeserializationError(#[from] rmp_serde::decode::Error),
+    #[error("cannot rebase commit from `{expected_parent}` onto `{actual_parent}`, {} conflicts found: {conflicts:?}", conflicts.len())]
+    RebaseConflicts {
+        expected_parent: SnapshotId,
+        actual_parent: SnapshotId,
+        conflicts: Vec<MergeConflict>,
+    },
+    #[error("snapshots `{ours}` and `{theirs}` have no common ancestor")]
+    NoCommonAncestor { ours: SnapshotId, theirs: SnapshotId },
+    #[error("cannot merge `{theirs}` into `{ours}`, {} conflicts found: {conflicts:?}", conflicts.len())]
+    MergeConflicts {
+        ours: SnapshotId,
+        theirs: SnapshotId,
+        ancestor: SnapshotId,
+        conflicts: Vec<MergeConflict>,
+    },
+}
+
+/// A difference between two versions of the repository, see [`Repository::diff`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Change {
+    NodeAdded {
+        path: Path,
+        node_id: NodeId,
+        node_type: NodeType,
+    },
+    NodeDeleted {
+        path: Path,
+        node_id: NodeId,
+        node_type: NodeType,
+    },
+    ZarrMetadataChanged {
+        path: Path,
+        node_id: NodeId,
+        old: Box<ZarrArrayMetadata>,
+        new: Box<ZarrArrayMetadata>,
+    },
+    UserAttributesChanged {
+        path: Path,
+        node_id: NodeId,
+        old: Option<UserAttributesSnapshot>,
+        new: Option<UserAttributesSnapshot>,
+    },
+    ChunkAdded {
+        path: Path,
+        node_id: NodeId,
+        coord: ChunkIndices,
+        payload: ChunkPayload,
+    },
+    ChunkRemoved {
+        path: Path,
+        node_id: NodeId,
+        coord: ChunkIndices,
+    },
+    ChunkChanged {
+        path: Path,
+        node_id: NodeId,
+        coord: ChunkIndices,
+        payload: ChunkPayload,
+    },
+}
+
+/// A change that cannot be merged automatically, see [`Repository::merge`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MergeConflict {
+    /// Both sides created a node at the same path, with different contents
+    NodeAddedOnBothSides { path: Path },
+    /// One side deleted a node (or one of its parents) that the other side modified
+    NodeDeletedAndModified { path: Path },
+    /// A group was replaced by an array, or the other way around
+    NodeTypeChanged { path: Path },
+    /// Both sides changed the Zarr metadata of the array
+    ZarrMetadataUpdated { path: Path },
+    /// Both sides changed the user attributes of the node
+    UserAttributesUpdated { path: Path },
+    /// Both sides wrote or deleted the same chunks of the array
+    ChunksUpdated { path: Path, coords: Vec<ChunkIndices> },
+    /// Both sides changed the url of the same virtual chunk prefix
+    VirtualPrefixUpdated { name: String },
 }
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
@@ -214,7 +414,8 @@ impl Repository {
             return Err(RepositoryError::AlreadyInitialized);
         }
         let new_snapshot = Snapshot::empty();
-        let new_snapshot_id = ObjectId::random();
+        // children record the parent by its metadata id, the file must be stored under it
+        let new_snapshot_id = new_snapshot.metadata.id.clone();
         storage.write_snapshot(new_snapshot_id.clone(), Arc::new(new_snapshot)).await?;
         update_branch(
             storage.as_ref(),
@@ -243,8 +444,15 @@ impl Repository {
     pub fn add_in_mem_asset_caching(
         storage: Arc<dyn Storage + Send + Sync>,
     ) -> Arc<dyn Storage + Send + Sync> {
-        // TODO: allow tuning once we experiment with different configurations
-        Arc::new(MemCachingStorage::new(storage, 2, 2, 2, 0))
+        Self::add_in_mem_asset_caching_with_config(storage, &CachingConfig::default())
+    }
+
+    /// Cache assets in memory, using at most the number of bytes in `config` for each type
+    pub fn add_in_mem_asset_caching_with_config(
+        storage: Arc<dyn Storage + Send + Sync>,
+        config: &CachingConfig,
+    ) -> Arc<dyn Storage + Send + Sync> {
+        Arc::new(MemCachingStorage::new(storage, config))
     }
 
     fn new(
@@ -252,16 +460,21 @@ impl Repository {
         storage: Arc<dyn Storage + Send + Sync>,
         snapshot_id: SnapshotId,
         change_set: Option<ChangeSet>,
-        virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+        virtual_resolver: ObjectStoreVirtualChunkResolver,
     ) -> Self {
+        let chunk_packer = config.chunk_packing.as_ref().map(|packing| {
+            Arc::new(ChunkPacker::new(
+                Arc::clone(&storage),
+                packing.target_pack_size_bytes,
+            ))
+        });
         Repository {
             snapshot_id,
             config,
             storage,
             change_set: change_set.unwrap_or_default(),
-            virtual_resolver: Arc::new(ObjectStoreVirtualChunkResolver::new(
-                virtual_ref_config,
-            )),
+            virtual_resolver: Arc::new(virtual_resolver),
+            chunk_packer,
         }
     }
 
@@ -302,6 +515,11 @@ impl Repository {
         &self.snapshot_id
     }
 
+    /// Hits and misses of the in memory caches, if the storage has them
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.storage.cache_stats()
+    }
+
     /// Indicates if the repository has pending changes
     pub fn has_uncommitted_changes(&self) -> bool {
         !self.change_set.is_empty()
@@ -310,17 +528,9 @@ impl Repository {
     /// Returns the sequence of parents of the current session, in order of latest first.
     pub async fn ancestry(
         &self,
-    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<SnapshotMetadata>>> {
-        let parent = self.storage.fetch_snapshot(self.snapshot_id()).await?;
-        let last = parent.metadata.clone();
-        let it = if parent.short_term_history.len() < parent.total_parents as usize {
-            // TODO: implement splitting of snapshot history
-            Either::Left(parent.local_ancestry().chain(iter::once_with(|| todo!())))
-        } else {
-            Either::Right(parent.local_ancestry())
-        };
-
-        Ok(futures::stream::iter(iter::once(Ok(last)).chain(it.map(Ok))))
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<SnapshotMetadata>> + Send>
+    {
+        snapshot_ancestry(Arc::clone(&self.storage), self.snapshot_id()).await
     }
 
     /// Add a group to the store.
@@ -424,11 +634,99 @@ impl Repository {
         coord: ChunkIndices,
         data: Option<ChunkPayload>,
     ) -> RepositoryResult<()> {
+        if let Some(ChunkPayload::Virtual(VirtualChunkRef {
+            location: VirtualChunkLocation::Relative(name, _),
+            ..
+        })) = &data
+        {
+            if !self.virtual_prefixes().await?.contains_key(name) {
+                return Err(VirtualReferenceError::UnknownPrefix(name.clone()).into());
+            }
+        }
         self.get_array(&path)
             .await
             .map(|node| self.change_set.set_chunk_ref(node.id, coord, data))
     }
 
+    /// Record many virtual chunk references of the array at `path`
+    ///
+    /// The array and the virtual prefixes are looked up once, and every reference is
+    /// validated before any is recorded, so either all or none of them are set.
+    /// Returns the number of references set.
+    pub async fn set_virtual_refs(
+        &mut self,
+        path: Path,
+        refs: impl IntoIterator<Item = (ChunkIndices, VirtualChunkRef)>,
+    ) -> RepositoryResult<usize> {
+        let node = self.get_array(&path).await?;
+        let ndim = match &node.node_data {
+            NodeData::Array(metadata, _) => metadata.shape.len(),
+            NodeData::Group => 0,
+        };
+        let prefixes = self.virtual_prefixes().await?;
+        let refs = refs
+            .into_iter()
+            .map(|(coord, vref)| {
+                if coord.0.len() != ndim {
+                    return Err(RepositoryError::InvalidChunkCoordinates {
+                        path: path.clone(),
+                        coords: coord,
+                        message: format!("the array has {ndim} dimensions"),
+                    });
+                }
+                if let VirtualChunkLocation::Relative(name, _) = &vref.location {
+                    if !prefixes.contains_key(name) {
+                        return Err(
+                            VirtualReferenceError::UnknownPrefix(name.clone()).into()
+                        );
+                    }
+                }
+                Ok((coord, Some(ChunkPayload::Virtual(vref))))
+            })
+            .collect::<RepositoryResult<Vec<_>>>()?;
+        let count = refs.len();
+        self.change_set.set_chunk_refs(node.id, refs);
+        Ok(count)
+    }
+
+    /// Set the url `prefix` for the [`VirtualChunkLocation::Relative`] locations named `name`
+    ///
+    /// Changing the prefix moves all the virtual chunks that use it, without rewriting them.
+    pub fn set_virtual_prefix(
+        &mut self,
+        name: &str,
+        prefix: &str,
+    ) -> RepositoryResult<()> {
+        url::Url::parse(prefix).map_err(VirtualReferenceError::CannotParseUrl)?;
+        self.change_set.set_virtual_prefix(name.to_string(), Some(prefix.to_string()));
+        Ok(())
+    }
+
+    /// Delete a virtual chunk prefix, virtual chunks that still use it can no longer be read
+    pub fn delete_virtual_prefix(&mut self, name: &str) {
+        self.change_set.set_virtual_prefix(name.to_string(), None);
+    }
+
+    /// The url prefixes of relative virtual chunk locations, including changes in this session
+    pub async fn virtual_prefixes(&self) -> RepositoryResult<BTreeMap<String, String>> {
+        let snapshot = self.storage.fetch_snapshot(self.snapshot_id()).await?;
+        let mut prefixes = snapshot.virtual_prefixes.clone();
+        self.change_set.update_virtual_prefixes(&mut prefixes);
+        Ok(prefixes)
+    }
+
+    async fn resolve_virtual_location(
+        &self,
+        location: VirtualChunkLocation,
+    ) -> RepositoryResult<VirtualChunkLocation> {
+        match location {
+            VirtualChunkLocation::Relative(..) => {
+                Ok(location.resolve(&self.virtual_prefixes().await?)?)
+            }
+            _ => Ok(location),
+        }
+    }
+
     pub async fn get_node(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
         get_node(self.storage.as_ref(), &self.change_set, self.snapshot_id(), path).await
     }
@@ -520,12 +818,26 @@ impl Repository {
     ) -> RepositoryResult<
         Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
     > {
-        match self.get_chunk_ref(path, coords).await? {
-            Some(ChunkPayload::Ref(ChunkRef { id, .. })) => {
+        match self.get_chunk_ref_for_read(path, coords).await? {
+            Some(ChunkPayload::Ref(ChunkRef { id, offset, length })) => {
                 let storage = Arc::clone(&self.storage);
-                let byte_range = byte_range.clone();
+                let byte_range = construct_valid_byte_range(byte_range, offset, length);
+                let whole = ByteRange::from_offset_with_length(offset, length);
+                let promote = length <= self.config.promote_partial_reads_max_bytes
+                    && byte_range != whole;
                 Ok(Some(
                     async move {
+                        if promote {
+                            // fetch the whole chunk so the cache can answer the next reads
+                            let bytes = storage.fetch_chunk(&id, &whole).await?;
+                            if let ByteRange::Bounded(range) = &byte_range {
+                                let start = (range.start - offset) as usize;
+                                let end = (range.end - offset) as usize;
+                                if end <= bytes.len() {
+                                    return Ok(bytes.slice(start..end));
+                                }
+                            }
+                        }
                         // TODO: we don't have a way to distinguish if we want to pass a range or not
                         storage.fetch_chunk(&id, &byte_range).await.map_err(|e| e.into())
                     }
@@ -535,15 +847,23 @@ impl Repository {
             Some(ChunkPayload::Inline(bytes)) => {
                 Ok(Some(ready(Ok(byte_range.slice(bytes))).boxed()))
             }
-            Some(ChunkPayload::Virtual(VirtualChunkRef { location, offset, length })) => {
-                let byte_range = construct_valid_byte_range(byte_range, offset, length);
+            Some(ChunkPayload::Virtual(vref)) => {
+                let pin = vref.pin();
+                let location = self.resolve_virtual_location(vref.location).await?;
+                let byte_range =
+                    construct_valid_byte_range(byte_range, vref.offset, vref.length);
                 let resolver = Arc::clone(&self.virtual_resolver);
+                let integrity = self.config.virtual_ref_integrity;
                 Ok(Some(
                     async move {
-                        resolver
-                            .fetch_chunk(&location, &byte_range)
-                            .await
-                            .map_err(|e| e.into())
+                        fetch_virtual_chunk(
+                            resolver.as_ref(),
+                            integrity,
+                            &location,
+                            &byte_range,
+                            &pin,
+                        )
+                        .await
                     }
                     .boxed(),
                 ))
@@ -552,6 +872,115 @@ impl Repository {
         }
     }
 
+    /// Like [`Repository::get_chunk_ref`], but chunks in the pack that is still open are
+    /// returned inline, because they cannot be fetched from storage yet
+    async fn get_chunk_ref_for_read(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<ChunkPayload>> {
+        let payload = self.get_chunk_ref(path, coords).await?;
+        if let (Some(ChunkPayload::Ref(chunk_ref)), Some(packer)) =
+            (&payload, &self.chunk_packer)
+        {
+            if let Some(data) = packer.get(chunk_ref).await {
+                return Ok(Some(ChunkPayload::Inline(data)));
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Fetch the bytes of many chunk ranges, merging the requests to the same object.
+    ///
+    /// Ranges that fall in the same chunk object or virtual file, and are at most
+    /// `max_gap_bytes` apart, are fetched with a single range request and then split back.
+    /// A merged request spans at most `max_coalesced_bytes`, unless a single range is larger.
+    /// At most `concurrency` requests are in flight at any time. Results are returned in the
+    /// same order as `requests`, with `None` for chunks that are not set.
+    pub async fn get_chunks_coalesced(
+        &self,
+        requests: &[(Path, ChunkIndices, ByteRange)],
+        max_gap_bytes: u64,
+        max_coalesced_bytes: u64,
+        concurrency: usize,
+    ) -> Vec<RepositoryResult<Option<Bytes>>> {
+        self.plan_chunks_coalesced(requests, max_gap_bytes, max_coalesced_bytes)
+            .await
+            .fetch(concurrency)
+            .await
+    }
+
+    /// The first half of [`Repository::get_chunks_coalesced`], it resolves the chunk
+    /// references and merges the reads, without fetching anything.
+    ///
+    /// The returned [`CoalescedChunkReads`] doesn't borrow the repository, so callers can
+    /// release any lock they hold on it before doing the I/O.
+    pub async fn plan_chunks_coalesced(
+        &self,
+        requests: &[(Path, ChunkIndices, ByteRange)],
+        max_gap_bytes: u64,
+        max_coalesced_bytes: u64,
+    ) -> CoalescedChunkReads {
+        let mut results: Vec<Option<RepositoryResult<Option<Bytes>>>> =
+            requests.iter().map(|_| None).collect();
+        let mut reads: HashMap<ChunkObject, Vec<CoalescedRead>> = HashMap::new();
+        for (index, (path, coords, byte_range)) in requests.iter().enumerate() {
+            let (object, offset, length) =
+                match self.get_chunk_ref_for_read(path, coords).await {
+                    Ok(Some(ChunkPayload::Ref(ChunkRef { id, offset, length }))) => {
+                        (ChunkObject::Chunk(id), offset, length)
+                    }
+                    Ok(Some(ChunkPayload::Virtual(vref))) => {
+                        let pin = vref.pin();
+                        match self.resolve_virtual_location(vref.location).await {
+                            Ok(location) => (
+                                ChunkObject::Virtual(location, pin),
+                                vref.offset,
+                                vref.length,
+                            ),
+                            Err(err) => {
+                                results[index] = Some(Err(err));
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(Some(ChunkPayload::Inline(bytes))) => {
+                        results[index] = Some(Ok(Some(byte_range.slice(bytes))));
+                        continue;
+                    }
+                    Ok(None) => {
+                        results[index] = Some(Ok(None));
+                        continue;
+                    }
+                    Err(err) => {
+                        results[index] = Some(Err(err));
+                        continue;
+                    }
+                };
+            let range = construct_valid_range(byte_range, offset, length);
+            let promote = matches!(object, ChunkObject::Chunk(_))
+                && length <= self.config.promote_partial_reads_max_bytes;
+            let fetch = if promote { offset..offset + length } else { range.clone() };
+            reads.entry(object).or_default().push(CoalescedRead { index, range, fetch });
+        }
+
+        let fetches = reads
+            .into_iter()
+            .flat_map(|(object, reads)| {
+                coalesce_reads(reads, max_gap_bytes, max_coalesced_bytes)
+                    .into_iter()
+                    .map(move |(range, reads)| (object.clone(), range, reads))
+            })
+            .collect();
+        CoalescedChunkReads {
+            storage: Arc::clone(&self.storage),
+            virtual_resolver: Arc::clone(&self.virtual_resolver),
+            integrity: self.config.virtual_ref_integrity,
+            results,
+            fetches,
+        }
+    }
+
     /// Returns a function that can be used to asynchronously write chunk bytes to object store
     ///
     /// The reason to use this design, instead of simple pass the [`Bytes`] is to avoid holding a
@@ -573,13 +1002,22 @@ impl Repository {
         Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>,
     > {
         let threshold = self.config.inline_chunk_threshold_bytes as usize;
+        let max_packed_size = self
+            .config
+            .chunk_packing
+            .as_ref()
+            .map(|packing| packing.max_chunk_size_bytes as usize)
+            .unwrap_or(0);
+        let packer = self.chunk_packer.clone();
         let storage = Arc::clone(&self.storage);
         move |data: Bytes| {
             async move {
-                let payload = if data.len() > threshold {
-                    new_materialized_chunk(storage.as_ref(), data).await?
-                } else {
-                    new_inline_chunk(data)
+                let payload = match packer {
+                    _ if data.len() <= threshold => new_inline_chunk(data),
+                    Some(packer) if data.len() <= max_packed_size => {
+                        packer.add(&data).await?
+                    }
+                    _ => new_materialized_chunk(storage.as_ref(), data).await?,
                 };
                 Ok(payload)
             }
@@ -606,8 +1044,7 @@ impl Repository {
         manifests: &[ManifestRef],
         coords: &ChunkIndices,
     ) -> RepositoryResult<Option<ChunkPayload>> {
-        // FIXME: use manifest extents
-        for manifest in manifests {
+        for manifest in manifests.iter().filter(|m| m.extents.contains(coords)) {
             let manifest_structure =
                 self.storage.fetch_manifests(&manifest.object_id).await?;
             match manifest_structure.get_chunk_payload(&node, coords.clone()) {
@@ -621,6 +1058,14 @@ impl Repository {
         Ok(None)
     }
 
+    /// Get the contents of user attributes, fetching them from storage if they are not inline
+    pub async fn load_user_attributes(
+        &self,
+        atts: Option<UserAttributesSnapshot>,
+    ) -> RepositoryResult<Option<UserAttributes>> {
+        fetch_user_attributes(self.storage.as_ref(), atts).await
+    }
+
     pub async fn list_nodes(
         &self,
     ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + '_> {
@@ -641,6 +1086,7 @@ impl Repository {
         message: &str,
         properties: SnapshotProperties,
     ) -> RepositoryResult<SnapshotId> {
+        self.flush_packed_chunks().await?;
         // FIXME: this clone can be avoided
         let change_sets = iter::once(self.change_set.clone()).chain(other_change_sets);
         let new_snapshot_id = distributed_flush(
@@ -649,6 +1095,7 @@ impl Repository {
             self.snapshot_id(),
             message,
             properties,
+            &self.config,
         )
         .await?;
 
@@ -682,12 +1129,100 @@ impl Repository {
             .await
     }
 
+    /// Commit the changes in the current session, and in `other_change_sets`, to the branch.
+    ///
+    /// If the branch tip is no longer the parent snapshot of the session, a
+    /// [`RepositoryError::Conflict`] is returned. When the repository is configured with
+    /// [`RepositoryConfig::commit_rebase_attempts`], the changes are instead rebased on top
+    /// of the new tip and the commit is retried, as long as the concurrent commits touched
+    /// different nodes and chunks. Overlapping changes fail with
+    /// [`RepositoryError::RebaseConflicts`].
     pub async fn distributed_commit<I: IntoIterator<Item = ChangeSet>>(
         &mut self,
         update_branch_name: &str,
         other_change_sets: I,
         message: &str,
         properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<SnapshotId> {
+        if self.config.commit_rebase_attempts == 0 {
+            return self
+                .try_distributed_commit(
+                    update_branch_name,
+                    other_change_sets,
+                    message,
+                    properties,
+                )
+                .await;
+        }
+
+        // we may need to commit more than once, so we keep all changes in our own change set
+        self.change_set.merge_many(other_change_sets);
+        let mut attempts = 0;
+        loop {
+            let parent = self.snapshot_id.clone();
+            let change_set = self.change_set.clone();
+            match self
+                .try_distributed_commit(
+                    update_branch_name,
+                    iter::empty(),
+                    message,
+                    properties.clone(),
+                )
+                .await
+            {
+                Err(RepositoryError::Conflict { actual_parent: Some(tip), .. })
+                    if attempts < self.config.commit_rebase_attempts =>
+                {
+                    attempts += 1;
+                    // a failed attempt may have flushed already, we go back to the original
+                    // session before rebasing it
+                    self.snapshot_id = parent;
+                    self.change_set = change_set;
+                    self.rebase(&tip).await?;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Move the changes in the current session on top of `new_parent`
+    ///
+    /// `new_parent` must be a descendant of the current snapshot, and the changes done since
+    /// cannot overlap with the changes in the session.
+    async fn rebase(&mut self, new_parent: &SnapshotId) -> RepositoryResult<()> {
+        let storage = self.storage.as_ref();
+        let parent = &self.snapshot_id;
+        if find_common_ancestor(&self.storage, parent, new_parent).await?.as_ref()
+            != Some(parent)
+        {
+            // the branch was reset, we cannot know what changed
+            return Err(RepositoryError::Conflict {
+                expected_parent: Some(parent.clone()),
+                actual_parent: Some(new_parent.clone()),
+            });
+        }
+
+        let (_, conflicts) =
+            three_way_merge(storage, parent, parent, &self.change_set, new_parent)
+                .await?;
+        if !conflicts.is_empty() {
+            return Err(RepositoryError::RebaseConflicts {
+                expected_parent: parent.clone(),
+                actual_parent: new_parent.clone(),
+                conflicts,
+            });
+        }
+
+        self.snapshot_id = new_parent.clone();
+        Ok(())
+    }
+
+    async fn try_distributed_commit<I: IntoIterator<Item = ChangeSet>>(
+        &mut self,
+        update_branch_name: &str,
+        other_change_sets: I,
+        message: &str,
+        properties: Option<SnapshotProperties>,
     ) -> RepositoryResult<SnapshotId> {
         let current = fetch_branch_tip(self.storage.as_ref(), update_branch_name).await;
         match current {
@@ -750,10 +1285,33 @@ impl Repository {
         }
     }
 
-    pub fn change_set_bytes(&self) -> RepositoryResult<Vec<u8>> {
+    /// The changes in the session, to commit them from another [`Repository`]
+    ///
+    /// Like [`Repository::change_set_bytes`], this writes the open pack first.
+    pub async fn into_change_set(self) -> RepositoryResult<ChangeSet> {
+        self.flush_packed_chunks().await?;
+        Ok(self.change_set)
+    }
+
+    /// Serialize the changes in the session, to commit them from another [`Repository`]
+    ///
+    /// The open pack is written first, so the changes only reference stored chunks.
+    pub async fn change_set_bytes(&self) -> RepositoryResult<Vec<u8>> {
+        self.flush_packed_chunks().await?;
         self.change_set.export_to_bytes()
     }
 
+    /// Write the open pack object of [`RepositoryConfig::chunk_packing`], if it has chunks
+    ///
+    /// Commits, [`Repository::change_set_bytes`] and [`Repository::into_change_set`] call
+    /// this. If it fails, the chunks are kept in memory and can still be read.
+    pub async fn flush_packed_chunks(&self) -> RepositoryResult<()> {
+        if let Some(packer) = &self.chunk_packer {
+            packer.flush().await?;
+        }
+        Ok(())
+    }
+
     pub async fn new_branch(&self, branch_name: &str) -> RepositoryResult<BranchVersion> {
         // TODO: The parent snapshot should exist?
         let version = match update_branch(
@@ -789,68 +1347,292 @@ impl Repository {
         .await?;
         Ok(())
     }
-}
 
-impl From<Repository> for ChangeSet {
-    fn from(val: Repository) -> Self {
-        val.change_set
+    /// Returns the changes needed to go from snapshot `from` to snapshot `to`
+    ///
+    /// Changes are generated node by node, in path order. A node whose id or type changed is
+    /// reported as deleted and added again. Chunks of added arrays are reported as added, but
+    /// chunks of deleted arrays are not reported as removed.
+    pub async fn diff(
+        &self,
+        from: &SnapshotId,
+        to: &SnapshotId,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<Change>> + '_> {
+        let storage = self.storage.as_ref();
+        let from = VersionNodes::new(storage, Cow::default(), from).await?;
+        let to = VersionNodes::new(storage, Cow::default(), to).await?;
+        Ok(diff_versions(from, to))
+    }
+
+    /// Returns the changes done in the current session, on top of its parent snapshot
+    ///
+    /// See [`Repository::diff`] for details.
+    pub async fn uncommitted_changes(
+        &self,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<Change>> + '_> {
+        let storage = self.storage.as_ref();
+        let from = VersionNodes::new(storage, Cow::default(), &self.snapshot_id).await?;
+        let to = VersionNodes::new(
+            storage,
+            Cow::Borrowed(&self.change_set),
+            &self.snapshot_id,
+        )
+        .await?;
+        Ok(diff_versions(from, to))
     }
-}
 
-async fn new_materialized_chunk(
-    storage: &(dyn Storage + Send + Sync),
-    data: Bytes,
-) -> RepositoryResult<ChunkPayload> {
-    let new_id = ObjectId::random();
-    storage.write_chunk(new_id.clone(), data.clone()).await?;
-    Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length: data.len() as u64 }))
-}
+    /// Merge into the current session the changes made in `source` since its common ancestor
+    /// with this repository.
+    ///
+    /// Both sides are diffed against the common ancestor snapshot, node by node and chunk by
+    /// chunk. Changes in `source` that don't overlap with the changes made in this repository,
+    /// committed or not, are recorded in the current session; the merge snapshot is written by
+    /// the next commit, which lists `source` in its [`SnapshotMetadata::merged_parents`]. The
+    /// common ancestor of later merges follows those parents, so changes are only merged once.
+    /// If both sides changed the same thing, nothing is recorded and a
+    /// [`RepositoryError::MergeConflicts`] is returned with the full list of conflicts.
+    pub async fn merge(&mut self, source: VersionInfo) -> RepositoryResult<()> {
+        let storage = self.storage.as_ref();
+        let theirs = resolve_version(storage, &source).await?;
+        let ancestor = find_common_ancestor(&self.storage, &self.snapshot_id, &theirs)
+            .await?
+            .ok_or_else(|| RepositoryError::NoCommonAncestor {
+                ours: self.snapshot_id.clone(),
+                theirs: theirs.clone(),
+            })?;
+        if ancestor == theirs {
+            // source has nothing we don't have already
+            return Ok(());
+        }
 
-fn new_inline_chunk(data: Bytes) -> ChunkPayload {
-    ChunkPayload::Inline(data)
-}
+        let (changes, conflicts) = three_way_merge(
+            storage,
+            &ancestor,
+            &self.snapshot_id,
+            &self.change_set,
+            &theirs,
+        )
+        .await?;
+        if !conflicts.is_empty() {
+            return Err(RepositoryError::MergeConflicts {
+                ours: self.snapshot_id.clone(),
+                theirs,
+                ancestor,
+                conflicts,
+            });
+        }
 
-pub async fn get_chunk(
-    reader: Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
-) -> RepositoryResult<Option<Bytes>> {
-    match reader {
-        Some(reader) => Ok(Some(reader.await?)),
-        None => Ok(None),
+        self.change_set.merge(changes);
+        self.change_set.add_merged_snapshot(theirs);
+        Ok(())
     }
 }
 
-async fn updated_existing_nodes<'a>(
-    storage: &(dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
-    parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
-) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    let manifest_refs = manifest_id.map(|mid| {
-        vec![ManifestRef { object_id: mid.clone(), extents: ManifestExtents(vec![]) }]
-    });
-    let updated_nodes =
-        storage.fetch_snapshot(parent_id).await?.iter_arc().filter_map(move |node| {
-            let new_manifests = if node.node_type() == NodeType::Array {
-                //FIXME: it could be none for empty arrays
-                manifest_refs.clone()
-            } else {
-                None
-            };
-            change_set.update_existing_node(node, new_manifests)
-        });
-
-    Ok(updated_nodes)
+/// Chunk reads planned by [`Repository::plan_chunks_coalesced`], ready to be fetched
+#[derive(Debug)]
+pub struct CoalescedChunkReads {
+    storage: Arc<dyn Storage + Send + Sync>,
+    virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    integrity: VirtualRefIntegrity,
+    // the results known without fetching, like inline chunks and missing refs
+    results: Vec<Option<RepositoryResult<Option<Bytes>>>>,
+    fetches: Vec<(ChunkObject, Range<u64>, Vec<CoalescedRead>)>,
 }
 
-async fn updated_nodes<'a>(
-    storage: &(dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
-    parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
-) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    Ok(updated_existing_nodes(storage, change_set, parent_id, manifest_id)
-        .await?
-        .chain(change_set.new_nodes_iterator(manifest_id)))
+impl CoalescedChunkReads {
+    /// Fetch the merged ranges, with at most `concurrency` requests in flight.
+    ///
+    /// Results are returned in the same order as the requests passed to
+    /// [`Repository::plan_chunks_coalesced`].
+    pub async fn fetch(self, concurrency: usize) -> Vec<RepositoryResult<Option<Bytes>>> {
+        let Self { storage, virtual_resolver, integrity, mut results, fetches } = self;
+        let fetcher = ChunkObjectFetcher {
+            storage: storage.as_ref(),
+            virtual_resolver: virtual_resolver.as_ref(),
+            integrity,
+        };
+        let fetched: Vec<_> = futures::stream::iter(fetches)
+            .map(|(object, range, reads)| fetcher.fetch_coalesced(object, range, reads))
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        for (index, result) in fetched.into_iter().flatten() {
+            results[index] = Some(result);
+        }
+        results.into_iter().map(|result| result.unwrap_or(Ok(None))).collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ChunkObjectFetcher<'a> {
+    storage: &'a (dyn Storage + Send + Sync),
+    virtual_resolver: &'a (dyn VirtualChunkResolver + Send + Sync),
+    integrity: VirtualRefIntegrity,
+}
+
+impl ChunkObjectFetcher<'_> {
+    async fn fetch_coalesced(
+        &self,
+        object: ChunkObject,
+        range: Range<u64>,
+        reads: Vec<CoalescedRead>,
+    ) -> Vec<(usize, RepositoryResult<Option<Bytes>>)> {
+        let bytes = self.fetch_chunk_object(&object, range.clone()).await;
+        if let ([read], Err(_)) = (reads.as_slice(), &bytes) {
+            return vec![(read.index, bytes.map(Some))];
+        }
+        let mut res = Vec::with_capacity(reads.len());
+        for read in reads {
+            let start = (read.range.start - range.start) as usize;
+            let end = (read.range.end - range.start) as usize;
+            let value = match &bytes {
+                Ok(bytes) if end <= bytes.len() => Ok(bytes.slice(start..end)),
+                // the merged request failed or came back short, fetch this read alone
+                _ => self.fetch_chunk_object(&object, read.range).await,
+            };
+            res.push((read.index, value.map(Some)));
+        }
+        res
+    }
+
+    async fn fetch_chunk_object(
+        &self,
+        object: &ChunkObject,
+        range: Range<u64>,
+    ) -> RepositoryResult<Bytes> {
+        match object {
+            ChunkObject::Chunk(id) => {
+                Ok(self.storage.fetch_chunk(id, &range.into()).await?)
+            }
+            ChunkObject::Virtual(location, pin) => {
+                fetch_virtual_chunk(
+                    self.virtual_resolver,
+                    self.integrity,
+                    location,
+                    &range.into(),
+                    pin,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// The object a chunk is read from, reads from the same object can be coalesced
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ChunkObject {
+    Chunk(ChunkId),
+    Virtual(VirtualChunkLocation, VirtualChunkPin),
+}
+
+/// Fetch a virtual chunk, checking the `pin` as the `integrity` policy says
+async fn fetch_virtual_chunk(
+    resolver: &(dyn VirtualChunkResolver + Send + Sync),
+    integrity: VirtualRefIntegrity,
+    location: &VirtualChunkLocation,
+    range: &ByteRange,
+    pin: &VirtualChunkPin,
+) -> RepositoryResult<Bytes> {
+    let unpinned = VirtualChunkPin::default();
+    let pin = if integrity == VirtualRefIntegrity::Off { &unpinned } else { pin };
+    match resolver.fetch_chunk(location, range, pin).await {
+        Err(VirtualReferenceError::ObjectModified(url))
+            if integrity == VirtualRefIntegrity::Warn =>
+        {
+            tracing::warn!(
+                "virtual reference object at {url} was modified after the reference was set"
+            );
+            Ok(resolver.fetch_chunk(location, range, &unpinned).await?)
+        }
+        res => Ok(res?),
+    }
+}
+
+/// A read of the absolute `range` of a chunk object, for the request at position `index`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CoalescedRead {
+    index: usize,
+    range: Range<u64>,
+    // the range to fetch for this read, it contains `range`
+    fetch: Range<u64>,
+}
+
+/// Group reads into ranges that span all the reads at most `max_gap` bytes apart.
+///
+/// A group is closed when adding the next read would make its range longer than `max_len`,
+/// so a long run of adjacent reads is fetched with several bounded requests.
+fn coalesce_reads(
+    mut reads: Vec<CoalescedRead>,
+    max_gap: u64,
+    max_len: u64,
+) -> Vec<(Range<u64>, Vec<CoalescedRead>)> {
+    reads.sort_by_key(|read| read.fetch.start);
+    let mut res: Vec<(Range<u64>, Vec<CoalescedRead>)> = Vec::new();
+    for read in reads {
+        match res.last_mut() {
+            Some((range, group))
+                if read.fetch.start <= range.end.saturating_add(max_gap)
+                    && range.end.max(read.fetch.end) - range.start <= max_len =>
+            {
+                range.end = range.end.max(read.fetch.end);
+                group.push(read);
+            }
+            _ => res.push((read.fetch.clone(), vec![read])),
+        }
+    }
+    res
+}
+
+async fn new_materialized_chunk(
+    storage: &(dyn Storage + Send + Sync),
+    data: Bytes,
+) -> RepositoryResult<ChunkPayload> {
+    let new_id = ObjectId::random();
+    storage.write_chunk(new_id.clone(), data.clone()).await?;
+    Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length: data.len() as u64 }))
+}
+
+fn new_inline_chunk(data: Bytes) -> ChunkPayload {
+    ChunkPayload::Inline(data)
+}
+
+pub async fn get_chunk(
+    reader: Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
+) -> RepositoryResult<Option<Bytes>> {
+    match reader {
+        Some(reader) => Ok(Some(reader.await?)),
+        None => Ok(None),
+    }
+}
+
+async fn updated_existing_nodes<'a>(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
+    parent_id: &SnapshotId,
+    manifests: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
+) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
+    let updated_nodes =
+        storage.fetch_snapshot(parent_id).await?.iter_arc().filter_map(move |node| {
+            let new_manifests = if node.node_type() == NodeType::Array {
+                manifests.map(|m| m.get(&node.id).cloned().unwrap_or_default())
+            } else {
+                None
+            };
+            change_set.update_existing_node(node, new_manifests)
+        });
+
+    Ok(updated_nodes)
+}
+
+async fn updated_nodes<'a>(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &'a ChangeSet,
+    parent_id: &SnapshotId,
+    manifests: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
+) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
+    Ok(updated_existing_nodes(storage, change_set, parent_id, manifests)
+        .await?
+        .chain(change_set.new_nodes_iterator(manifests)))
 }
 
 async fn get_node<'a>(
@@ -860,14 +1642,16 @@ async fn get_node<'a>(
     path: &Path,
 ) -> RepositoryResult<NodeSnapshot> {
     // We need to look for nodes in self.change_set and the snapshot file
-    if change_set.is_deleted(path) {
-        return Err(RepositoryError::NodeNotFound {
+    match change_set.get_new_node(path) {
+        Some(node) if !change_set.is_new_node_deleted(path) => Ok(node),
+        Some(_) => Err(RepositoryError::NodeNotFound {
             path: path.clone(),
             message: "getting node".to_string(),
-        });
-    }
-    match change_set.get_new_node(path) {
-        Some(node) => Ok(node),
+        }),
+        None if change_set.is_deleted(path) => Err(RepositoryError::NodeNotFound {
+            path: path.clone(),
+            message: "getting node".to_string(),
+        }),
         None => {
             let node = get_existing_node(storage, change_set, snapshot_id, path).await?;
             if change_set.is_deleted(&node.path) {
@@ -928,6 +1712,7 @@ async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
     parent_id: &SnapshotId,
     message: &str,
     properties: SnapshotProperties,
+    config: &RepositoryConfig,
 ) -> RepositoryResult<SnapshotId> {
     let mut change_set = ChangeSet::default();
     change_set.merge_many(change_sets);
@@ -935,40 +1720,54 @@ async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
         return Err(RepositoryError::NoChangesToCommit);
     }
 
-    let chunks = all_chunks(storage, &change_set, parent_id)
-        .await?
-        .map_ok(|(_path, chunk_info)| chunk_info);
-
-    let new_manifest = Arc::new(Manifest::from_stream(chunks).await?);
-    let new_manifest_id = if new_manifest.len() > 0 {
-        let id = ObjectId::random();
-        storage.write_manifests(id.clone(), Arc::clone(&new_manifest)).await?;
-        Some(id)
-    } else {
-        None
-    };
+    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
+    let manifests = write_array_manifests(
+        storage,
+        &change_set,
+        &old_snapshot,
+        config.max_chunks_per_manifest,
+    )
+    .await?;
+    let manifest_ids: BTreeSet<&ManifestId> =
+        manifests.values().flatten().map(|mref| &mref.object_id).collect();
+    let manifest_files = manifest_ids
+        .into_iter()
+        .map(|id| {
+            old_snapshot
+                .manifest_files
+                .iter()
+                .find(|info| &info.id == id)
+                .cloned()
+                .unwrap_or(ManifestFileInfo {
+                    id: id.clone(),
+                    format_version: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+                })
+        })
+        .collect();
 
     let all_nodes =
-        updated_nodes(storage, &change_set, parent_id, new_manifest_id.as_ref()).await?;
+        updated_nodes(storage, &change_set, parent_id, Some(&manifests)).await?;
+    let (all_nodes, attribute_files) = write_attributes_table(
+        storage,
+        &change_set,
+        &old_snapshot,
+        all_nodes,
+        config.inline_attributes_threshold_bytes,
+    )
+    .await?;
 
-    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
     let mut new_snapshot = Snapshot::from_iter(
         old_snapshot.as_ref(),
         Some(properties),
-        new_manifest_id
-            .as_ref()
-            .map(|mid| {
-                vec![ManifestFileInfo {
-                    id: mid.clone(),
-                    format_version: new_manifest.icechunk_manifest_format_version,
-                }]
-            })
-            .unwrap_or_default(),
-        vec![],
+        manifest_files,
+        attribute_files,
         all_nodes,
     );
     new_snapshot.metadata.message = message.to_string();
     new_snapshot.metadata.written_at = Utc::now();
+    new_snapshot.metadata.merged_parents =
+        change_set.merged_snapshots().cloned().collect();
+    change_set.update_virtual_prefixes(&mut new_snapshot.virtual_prefixes);
 
     let new_snapshot = Arc::new(new_snapshot);
     let new_snapshot_id = &new_snapshot.metadata.id;
@@ -977,6 +1776,358 @@ async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
     Ok(new_snapshot_id.clone())
 }
 
+/// Concatenates chunks into pack objects of about `target_size` bytes
+///
+/// Chunks are appended to an open pack, which is sealed once it's full and written without
+/// holding the lock, so other chunks can be added meanwhile. Sealed packs are kept, and
+/// their chunks can still be read, until they are written successfully. Packs that failed
+/// to upload are written again by the next [`ChunkPacker::flush`].
+#[derive(Debug)]
+pub(crate) struct ChunkPacker {
+    storage: Arc<dyn Storage + Send + Sync>,
+    target_size: u64,
+    state: Mutex<ChunkPackerState>,
+}
+
+#[derive(Debug)]
+struct ChunkPackerState {
+    /// The pack new chunks are appended to
+    id: ChunkId,
+    buffer: Vec<u8>,
+    /// Full packs that were not written yet
+    sealed: Vec<SealedPack>,
+    /// Number of pack objects written
+    packs: usize,
+}
+
+#[derive(Debug)]
+struct SealedPack {
+    id: ChunkId,
+    data: Bytes,
+    /// A write of this pack is in progress
+    writing: bool,
+}
+
+impl ChunkPackerState {
+    fn seal(&mut self) {
+        if !self.buffer.is_empty() {
+            let id = std::mem::replace(&mut self.id, ChunkId::random());
+            let data = Bytes::from(std::mem::take(&mut self.buffer));
+            self.sealed.push(SealedPack { id, data, writing: false });
+        }
+    }
+}
+
+impl ChunkPacker {
+    pub(crate) fn new(storage: Arc<dyn Storage + Send + Sync>, target_size: u64) -> Self {
+        let state = ChunkPackerState {
+            id: ChunkId::random(),
+            buffer: Vec::new(),
+            sealed: Vec::new(),
+            packs: 0,
+        };
+        Self { storage, target_size, state: Mutex::new(state) }
+    }
+
+    /// Add `data` to the open pack, returning its reference
+    ///
+    /// If this fills the pack, it's written before returning. Failing to write it returns
+    /// an error, but the chunks are kept to be written later.
+    pub(crate) async fn add(&self, data: &[u8]) -> RepositoryResult<ChunkPayload> {
+        let chunk_ref = {
+            let mut state = self.state.lock().await;
+            if !state.buffer.is_empty()
+                && (state.buffer.len() + data.len()) as u64 > self.target_size
+            {
+                state.seal();
+            }
+            let chunk_ref = ChunkRef {
+                id: state.id.clone(),
+                offset: state.buffer.len() as u64,
+                length: data.len() as u64,
+            };
+            state.buffer.extend_from_slice(data);
+            chunk_ref
+        };
+        self.write_sealed(false).await?;
+        Ok(ChunkPayload::Ref(chunk_ref))
+    }
+
+    /// The data of a chunk in a pack that was not written yet
+    pub(crate) async fn get(&self, chunk_ref: &ChunkRef) -> Option<Bytes> {
+        let start = chunk_ref.offset as usize;
+        let end = start + chunk_ref.length as usize;
+        let state = self.state.lock().await;
+        if chunk_ref.id == state.id {
+            return state.buffer.get(start..end).map(Bytes::copy_from_slice);
+        }
+        state.sealed.iter().find(|pack| pack.id == chunk_ref.id).and_then(|pack| {
+            (end <= pack.data.len()).then(|| pack.data.slice(start..end))
+        })
+    }
+
+    /// Write the open pack, and any pack not written yet
+    pub(crate) async fn flush(&self) -> RepositoryResult<()> {
+        self.write_sealed(true).await
+    }
+
+    /// Write all packs, returning how many were written
+    pub(crate) async fn finish(self) -> RepositoryResult<usize> {
+        self.flush().await?;
+        Ok(self.state.into_inner().packs)
+    }
+
+    /// Write the sealed packs, with `all` the open pack is sealed first, and the packs
+    /// being written concurrently are written again, so they are all stored on return
+    async fn write_sealed(&self, all: bool) -> RepositoryResult<()> {
+        let to_write: Vec<(ChunkId, Bytes)> = {
+            let mut state = self.state.lock().await;
+            if all {
+                state.seal();
+            }
+            state
+                .sealed
+                .iter_mut()
+                .filter(|pack| all || !pack.writing)
+                .map(|pack| {
+                    pack.writing = true;
+                    (pack.id.clone(), pack.data.clone())
+                })
+                .collect()
+        };
+
+        let mut res = Ok(());
+        for (id, data) in to_write {
+            let written = self.storage.write_chunk(id.clone(), data).await;
+            let mut state = self.state.lock().await;
+            let index = state.sealed.iter().position(|pack| pack.id == id);
+            match (written, index) {
+                (Ok(()), Some(index)) => {
+                    state.sealed.remove(index);
+                    state.packs += 1;
+                }
+                // another write of the same pack finished first
+                (Ok(()), None) => {}
+                (Err(err), index) => {
+                    if let Some(index) = index {
+                        state.sealed[index].writing = false;
+                    }
+                    if res.is_ok() {
+                        res = Err(err.into());
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Write new manifests for the arrays with modified chunks
+///
+/// The chunk grid of each array is split in blocks of at most `max_chunks_per_manifest`
+/// chunks, with one manifest per block. Only blocks with modified chunks get a new
+/// manifest, the rest are reused from `parent`. Returns the manifests of every array.
+async fn write_array_manifests(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    parent: &Snapshot,
+    max_chunks_per_manifest: u32,
+) -> RepositoryResult<HashMap<NodeId, Vec<ManifestRef>>> {
+    let existing =
+        parent.iter().filter(|node| !change_set.is_deleted(&node.path)).cloned();
+    let arrays =
+        existing.chain(change_set.new_nodes_iterator(None)).filter_map(|node| match node
+            .node_data
+        {
+            NodeData::Array(meta, manifests) => {
+                Some((node.id, node.path, meta, manifests))
+            }
+            NodeData::Group => None,
+        });
+
+    let mut res = HashMap::new();
+    for (node_id, path, meta, manifests) in arrays {
+        let modified_blocks: HashSet<ChunkIndices> = change_set
+            .array_chunks_iterator(&node_id, &path)
+            .map(|(coord, _)| manifest_block(coord, &meta, max_chunks_per_manifest))
+            .collect();
+        if modified_blocks.is_empty() {
+            res.insert(node_id, manifests);
+            continue;
+        }
+
+        let (mut refs, to_rewrite): (Vec<_>, Vec<_>) =
+            manifests.into_iter().partition(|mref| {
+                extents_block(&mref.extents, &meta, max_chunks_per_manifest)
+                    .map(|block| !modified_blocks.contains(&block))
+                    .unwrap_or(false)
+            });
+
+        let node = NodeSnapshot {
+            id: node_id.clone(),
+            path,
+            user_attributes: None,
+            node_data: NodeData::Array(meta, to_rewrite),
+        };
+        refs.extend(
+            write_node_manifests(storage, change_set, node, max_chunks_per_manifest)
+                .await?,
+        );
+        refs.sort_by(|a, b| a.extents.0.cmp(&b.extents.0));
+        res.insert(node_id, refs);
+    }
+    Ok(res)
+}
+
+/// Write the chunks of `node`, including the changes in `change_set`, to one new manifest
+/// per block of the chunk grid
+pub(crate) async fn write_node_manifests(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    node: NodeSnapshot,
+    max_chunks_per_manifest: u32,
+) -> RepositoryResult<Vec<ManifestRef>> {
+    let NodeData::Array(meta, _) = &node.node_data else { return Ok(Vec::new()) };
+    let meta = meta.clone();
+    let mut blocks: BTreeMap<
+        ChunkIndices,
+        BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+    > = BTreeMap::new();
+    let chunks = verified_node_chunk_iterator(storage, change_set, node).await;
+    pin_mut!(chunks);
+    while let Some(chunk) = chunks.try_next().await? {
+        blocks
+            .entry(manifest_block(&chunk.coord, &meta, max_chunks_per_manifest))
+            .or_default()
+            .insert((chunk.node, chunk.coord), chunk.payload);
+    }
+
+    let mut refs = Vec::with_capacity(blocks.len());
+    for chunks in blocks.into_values() {
+        let extents = ManifestExtents::from_coords(chunks.keys().map(|(_, coord)| coord));
+        let id = ObjectId::random();
+        storage.write_manifests(id.clone(), Arc::new(Manifest::new(chunks))).await?;
+        refs.push(ManifestRef { object_id: id, extents });
+    }
+    Ok(refs)
+}
+
+/// Move the user attributes updated in `change_set` that are larger than `threshold` to a
+/// new attributes file
+///
+/// Returns the nodes pointing to their new attributes, and all the attribute files used by
+/// them, including those reused from `parent`.
+async fn write_attributes_table(
+    storage: &(dyn Storage + Send + Sync),
+    change_set: &ChangeSet,
+    parent: &Snapshot,
+    nodes: impl Iterator<Item = NodeSnapshot>,
+    threshold: u32,
+) -> RepositoryResult<(Vec<NodeSnapshot>, Vec<AttributeFileInfo>)> {
+    let attributes_id = AttributesId::random();
+    let mut table = Vec::new();
+    let nodes: Vec<NodeSnapshot> = nodes
+        .map(|node| match node.user_attributes {
+            Some(UserAttributesSnapshot::Inline(atts))
+                if change_set.has_updated_attributes(&node.id)
+                    && atts.to_bytes().len() > threshold as usize =>
+            {
+                let location = table.len() as TableOffset;
+                table.push(atts);
+                let atts_ref =
+                    UserAttributesRef { object_id: attributes_id.clone(), location };
+                NodeSnapshot {
+                    user_attributes: Some(UserAttributesSnapshot::Ref(atts_ref)),
+                    ..node
+                }
+            }
+            _ => node,
+        })
+        .collect();
+    if !table.is_empty() {
+        storage
+            .write_attributes(attributes_id, Arc::new(AttributesTable::new(table)))
+            .await?;
+    }
+
+    let attribute_ids: BTreeSet<&AttributesId> = nodes
+        .iter()
+        .filter_map(|node| match &node.user_attributes {
+            Some(UserAttributesSnapshot::Ref(atts_ref)) => Some(&atts_ref.object_id),
+            _ => None,
+        })
+        .collect();
+    let attribute_files = attribute_ids
+        .into_iter()
+        .map(|id| {
+            parent.attribute_files.iter().find(|info| &info.id == id).cloned().unwrap_or(
+                AttributeFileInfo {
+                    id: id.clone(),
+                    format_version: format_constants::LATEST_ICECHUNK_ATTRIBUTES_FORMAT,
+                },
+            )
+        })
+        .collect();
+    Ok((nodes, attribute_files))
+}
+
+/// The shape, in chunks, of the blocks the chunk grid of an array is split in
+///
+/// Blocks hold whole runs of the fastest varying dimensions, the last ones, adding slower
+/// dimensions until they reach `max_chunks_per_manifest` chunks.
+fn manifest_block_shape(
+    meta: &ZarrArrayMetadata,
+    max_chunks_per_manifest: u32,
+) -> Vec<u64> {
+    let mut remaining = max_chunks_per_manifest.max(1) as u64;
+    let mut shape: Vec<u64> = meta
+        .shape
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(dim, size)| {
+            let chunk_size = meta.chunk_shape.0.get(dim).map_or(1, |size| size.get());
+            let grid_size = size.div_ceil(chunk_size).max(1);
+            let edge = grid_size.min(remaining).max(1);
+            remaining /= edge;
+            edge
+        })
+        .collect();
+    shape.reverse();
+    shape
+}
+
+fn manifest_block(
+    coord: &ChunkIndices,
+    meta: &ZarrArrayMetadata,
+    max_chunks_per_manifest: u32,
+) -> ChunkIndices {
+    let shape = manifest_block_shape(meta, max_chunks_per_manifest);
+    ChunkIndices(
+        coord
+            .0
+            .iter()
+            .enumerate()
+            .map(|(dim, c)| (*c as u64 / shape.get(dim).copied().unwrap_or(1)) as u32)
+            .collect(),
+    )
+}
+
+/// The block a manifest belongs to, if all its chunks are in the same block
+pub(crate) fn extents_block(
+    extents: &ManifestExtents,
+    meta: &ZarrArrayMetadata,
+    max_chunks_per_manifest: u32,
+) -> Option<ChunkIndices> {
+    match extents.0.as_slice() {
+        [min, max] => {
+            let block = manifest_block(min, meta, max_chunks_per_manifest);
+            (block == manifest_block(max, meta, max_chunks_per_manifest)).then_some(block)
+        }
+        _ => None,
+    }
+}
+
 /// Warning: The presence of a single error may mean multiple missing items
 async fn updated_chunk_iterator<'a>(
     storage: &'a (dyn Storage + Send + Sync),
@@ -1100,50 +2251,571 @@ async fn all_chunks<'a>(
     Ok(existing_array_chunks.chain(new_array_chunks))
 }
 
-pub async fn raise_if_invalid_snapshot_id(
-    storage: &(dyn Storage + Send + Sync),
+/// Returns the snapshot and all its parents, in order of latest first.
+///
+/// Snapshots only keep a limited number of ancestors inline, older ancestors are found by
+/// fetching the oldest snapshot in that history, and continuing with its own history.
+pub(crate) async fn snapshot_ancestry(
+    storage: Arc<dyn Storage + Send + Sync>,
     snapshot_id: &SnapshotId,
-) -> RepositoryResult<()> {
-    storage
-        .fetch_snapshot(snapshot_id)
-        .await
-        .map_err(|_| RepositoryError::SnapshotNotFound { id: snapshot_id.clone() })?;
-    Ok(())
+) -> RepositoryResult<impl Stream<Item = RepositoryResult<SnapshotMetadata>> + Send> {
+    let mut snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    Ok(try_stream! {
+        yield snapshot.metadata.clone();
+        loop {
+            for parent in snapshot.short_term_history.iter() {
+                yield parent.clone();
+            }
+            if snapshot.short_term_history.len() >= snapshot.total_parents as usize {
+                break;
+            }
+            match snapshot.short_term_history.back() {
+                Some(oldest) => {
+                    let oldest_id = oldest.id.clone();
+                    snapshot = storage.fetch_snapshot(&oldest_id).await?;
+                }
+                None => break,
+            }
+        }
+    })
 }
 
-#[cfg(test)]
-#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
-mod tests {
-
-    use std::{error::Error, num::NonZeroU64};
+async fn resolve_version(
+    storage: &(dyn Storage + Send + Sync),
+    version: &VersionInfo,
+) -> RepositoryResult<SnapshotId> {
+    match version {
+        VersionInfo::SnapshotId(sid) => {
+            raise_if_invalid_snapshot_id(storage, sid).await?;
+            Ok(sid.clone())
+        }
+        VersionInfo::TagRef(tag) => Ok(fetch_tag(storage, tag).await?.snapshot),
+        VersionInfo::BranchTipRef(branch) => {
+            Ok(fetch_branch_tip(storage, branch).await?.snapshot)
+        }
+    }
+}
 
-    use crate::{
-        format::manifest::ChunkInfo,
-        metadata::{
-            ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
-        },
-        refs::{fetch_ref, Ref},
-        storage::{logging::LoggingStorage, ObjectStorage},
-        strategies::*,
-    };
+/// Finds the most recent snapshot that is an ancestor of (or equal to) both `ours` and `theirs`
+///
+/// Ancestors include the parents recorded by merges.
+async fn find_common_ancestor(
+    storage: &Arc<dyn Storage + Send + Sync>,
+    ours: &SnapshotId,
+    theirs: &SnapshotId,
+) -> RepositoryResult<Option<SnapshotId>> {
+    let our_ancestors = snapshot_ancestors(storage, ours).await?;
+    let their_ancestors = snapshot_ancestors(storage, theirs).await?;
+    Ok(their_ancestors
+        .into_iter()
+        .filter(|(id, _)| our_ancestors.contains_key(id))
+        .max_by_key(|(_, written_at)| *written_at)
+        .map(|(id, _)| id))
+}
 
-    use super::*;
-    use itertools::Itertools;
-    use pretty_assertions::assert_eq;
-    use proptest::prelude::{prop_assert, prop_assert_eq};
-    use test_strategy::proptest;
-    use tokio::sync::Barrier;
+/// The snapshots `snapshot_id` descends from, including itself, with their write time
+///
+/// Unlike [`snapshot_ancestry`], this follows [`SnapshotMetadata::merged_parents`] too.
+async fn snapshot_ancestors(
+    storage: &Arc<dyn Storage + Send + Sync>,
+    snapshot_id: &SnapshotId,
+) -> RepositoryResult<HashMap<SnapshotId, DateTime<Utc>>> {
+    let mut res = HashMap::new();
+    let mut pending = vec![snapshot_id.clone()];
+    while let Some(id) = pending.pop() {
+        if res.contains_key(&id) {
+            continue;
+        }
+        let ancestry = snapshot_ancestry(Arc::clone(storage), &id).await?;
+        pin_mut!(ancestry);
+        while let Some(meta) = ancestry.try_next().await? {
+            if res.insert(meta.id, meta.written_at).is_some() {
+                // the rest of this line of history was already visited
+                break;
+            }
+            pending.extend(meta.merged_parents);
+        }
+    }
+    Ok(res)
+}
 
-    #[proptest(async = "tokio")]
-    async fn test_add_delete_group(
-        #[strategy(node_paths())] path: Path,
-        #[strategy(empty_repositories())] mut repository: Repository,
-    ) {
-        // getting any path from an empty repository must fail
-        prop_assert!(repository.get_node(&path).await.is_err());
+/// The nodes of a version of the repository, indexed by path
+///
+/// Unlike [`updated_nodes`], array nodes keep their manifests, so their chunks can be retrieved.
+struct VersionNodes<'a> {
+    storage: &'a (dyn Storage + Send + Sync),
+    change_set: Cow<'a, ChangeSet>,
+    nodes: BTreeMap<Path, NodeSnapshot>,
+    virtual_prefixes: BTreeMap<String, String>,
+}
 
-        // adding a new group must succeed
-        prop_assert!(repository.add_group(path.clone()).await.is_ok());
+impl<'a> VersionNodes<'a> {
+    async fn new(
+        storage: &'a (dyn Storage + Send + Sync),
+        change_set: Cow<'a, ChangeSet>,
+        snapshot_id: &SnapshotId,
+    ) -> RepositoryResult<VersionNodes<'a>> {
+        let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+        let mut virtual_prefixes = snapshot.virtual_prefixes.clone();
+        change_set.update_virtual_prefixes(&mut virtual_prefixes);
+        let existing = snapshot.iter_arc().filter_map(|node| {
+            let manifests = match &node.node_data {
+                NodeData::Array(_, manifests) => Some(manifests.clone()),
+                NodeData::Group => None,
+            };
+            change_set.update_existing_node(node, manifests)
+        });
+        let nodes = existing
+            .chain(change_set.new_nodes_iterator(None))
+            .map(|node| (node.path.clone(), node))
+            .collect();
+        Ok(Self { storage, change_set, nodes, virtual_prefixes })
+    }
+
+    async fn chunks(
+        &self,
+        node: &NodeSnapshot,
+    ) -> RepositoryResult<HashMap<ChunkIndices, ChunkPayload>> {
+        verified_node_chunk_iterator(self.storage, &self.change_set, node.clone())
+            .await
+            .map_ok(|chunk| (chunk.coord, chunk.payload))
+            .try_collect()
+            .await
+    }
+
+    /// True if the node has the same type, metadata, user attributes and chunks in both versions
+    async fn same_node(
+        &self,
+        node: &NodeSnapshot,
+        other: &VersionNodes<'_>,
+        other_node: &NodeSnapshot,
+    ) -> RepositoryResult<bool> {
+        if node.user_attributes != other_node.user_attributes {
+            return Ok(false);
+        }
+        match (&node.node_data, &other_node.node_data) {
+            (NodeData::Group, NodeData::Group) => Ok(true),
+            (NodeData::Array(meta, _), NodeData::Array(other_meta, _)) => Ok(meta
+                == other_meta
+                && self.chunks(node).await? == other.chunks(other_node).await?),
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the first parent of `path` that is present in `self` but was deleted in `other`
+    fn deleted_parent(&self, path: &Path, other: &VersionNodes<'_>) -> Option<Path> {
+        path.ancestors().skip(1).find(|parent| {
+            self.nodes.contains_key(parent) && !other.nodes.contains_key(parent)
+        })
+    }
+}
+
+fn diff_versions<'a>(
+    from: VersionNodes<'a>,
+    to: VersionNodes<'a>,
+) -> impl Stream<Item = RepositoryResult<Change>> + 'a {
+    try_stream! {
+        let paths: BTreeSet<Path> =
+            from.nodes.keys().chain(to.nodes.keys()).cloned().collect();
+        for path in paths {
+            let changes =
+                diff_node(&path, &from, from.nodes.get(&path), &to, to.nodes.get(&path))
+                    .await?;
+            for change in changes {
+                yield change;
+            }
+        }
+    }
+}
+
+async fn diff_node(
+    path: &Path,
+    from: &VersionNodes<'_>,
+    old: Option<&NodeSnapshot>,
+    to: &VersionNodes<'_>,
+    new: Option<&NodeSnapshot>,
+) -> RepositoryResult<Vec<Change>> {
+    let deleted = |node: &NodeSnapshot| Change::NodeDeleted {
+        path: path.clone(),
+        node_id: node.id.clone(),
+        node_type: node.node_type(),
+    };
+
+    let (old, new) = match (old, new) {
+        (None, None) => return Ok(vec![]),
+        (Some(old), None) => return Ok(vec![deleted(old)]),
+        (None, Some(new)) => return added_node(path, to, new).await,
+        (Some(old), Some(new))
+            if old.id != new.id || old.node_type() != new.node_type() =>
+        {
+            let mut changes = vec![deleted(old)];
+            changes.extend(added_node(path, to, new).await?);
+            return Ok(changes);
+        }
+        (Some(old), Some(new)) => (old, new),
+    };
+
+    let mut changes = Vec::new();
+    if old.user_attributes != new.user_attributes {
+        changes.push(Change::UserAttributesChanged {
+            path: path.clone(),
+            node_id: new.id.clone(),
+            old: old.user_attributes.clone(),
+            new: new.user_attributes.clone(),
+        });
+    }
+
+    if let (
+        NodeData::Array(old_meta, old_manifests),
+        NodeData::Array(new_meta, new_manifests),
+    ) = (&old.node_data, &new.node_data)
+    {
+        if old_meta != new_meta {
+            changes.push(Change::ZarrMetadataChanged {
+                path: path.clone(),
+                node_id: new.id.clone(),
+                old: Box::new(old_meta.clone()),
+                new: Box::new(new_meta.clone()),
+            });
+        }
+
+        // manifests are immutable, no need to look at chunks if they are the same
+        let session_chunks =
+            from.change_set.array_chunks_iterator(&old.id, path).next().is_some()
+                || to.change_set.array_chunks_iterator(&new.id, path).next().is_some();
+        if old_manifests == new_manifests && !session_chunks {
+            return Ok(changes);
+        }
+
+        let old_chunks = from.chunks(old).await?;
+        let new_chunks = to.chunks(new).await?;
+        let coords: BTreeSet<&ChunkIndices> =
+            old_chunks.keys().chain(new_chunks.keys()).collect();
+        for coord in coords {
+            let change = match (old_chunks.get(coord), new_chunks.get(coord)) {
+                (None, Some(payload)) => Change::ChunkAdded {
+                    path: path.clone(),
+                    node_id: new.id.clone(),
+                    coord: coord.clone(),
+                    payload: payload.clone(),
+                },
+                (Some(_), None) => Change::ChunkRemoved {
+                    path: path.clone(),
+                    node_id: new.id.clone(),
+                    coord: coord.clone(),
+                },
+                (Some(old_payload), Some(payload)) if old_payload != payload => {
+                    Change::ChunkChanged {
+                        path: path.clone(),
+                        node_id: new.id.clone(),
+                        coord: coord.clone(),
+                        payload: payload.clone(),
+                    }
+                }
+                _ => continue,
+            };
+            changes.push(change);
+        }
+    }
+
+    Ok(changes)
+}
+
+async fn added_node(
+    path: &Path,
+    to: &VersionNodes<'_>,
+    node: &NodeSnapshot,
+) -> RepositoryResult<Vec<Change>> {
+    let mut changes = vec![Change::NodeAdded {
+        path: path.clone(),
+        node_id: node.id.clone(),
+        node_type: node.node_type(),
+    }];
+    let chunks: BTreeMap<_, _> = to.chunks(node).await?.into_iter().collect();
+    changes.extend(chunks.into_iter().map(|(coord, payload)| Change::ChunkAdded {
+        path: path.clone(),
+        node_id: node.id.clone(),
+        coord,
+        payload,
+    }));
+    Ok(changes)
+}
+
+async fn fetch_user_attributes(
+    storage: &(dyn Storage + Send + Sync),
+    atts: Option<UserAttributesSnapshot>,
+) -> RepositoryResult<Option<UserAttributes>> {
+    match atts {
+        None => Ok(None),
+        Some(UserAttributesSnapshot::Inline(atts)) => Ok(Some(atts)),
+        Some(UserAttributesSnapshot::Ref(atts_ref)) => {
+            let table = storage.fetch_attributes(&atts_ref.object_id).await?;
+            Ok(Some(table.get(atts_ref.location)?.clone()))
+        }
+    }
+}
+
+/// Record in `changes` the creation of `node` from `version`, with its attributes and chunks
+async fn add_node(
+    storage: &(dyn Storage + Send + Sync),
+    changes: &mut ChangeSet,
+    version: &VersionNodes<'_>,
+    node: &NodeSnapshot,
+) -> RepositoryResult<()> {
+    match &node.node_data {
+        NodeData::Group => changes.add_group(node.path.clone(), node.id.clone()),
+        NodeData::Array(meta, _) => {
+            changes.add_array(node.path.clone(), node.id.clone(), meta.clone());
+            for (coord, payload) in version.chunks(node).await? {
+                changes.set_chunk_ref(node.id.clone(), coord, Some(payload));
+            }
+        }
+    }
+    if let Some(atts) =
+        fetch_user_attributes(storage, node.user_attributes.clone()).await?
+    {
+        changes.update_user_attributes(node.id.clone(), Some(atts));
+    }
+    Ok(())
+}
+
+/// Computes the changes done in `theirs_id` since `ancestor_id` that can be applied on top of
+/// `ours_id` with `change_set`.
+///
+/// Changes that overlap with changes done in `ours_id` or `change_set` since the ancestor are
+/// returned as conflicts instead.
+async fn three_way_merge(
+    storage: &(dyn Storage + Send + Sync),
+    ancestor_id: &SnapshotId,
+    ours_id: &SnapshotId,
+    change_set: &ChangeSet,
+    theirs_id: &SnapshotId,
+) -> RepositoryResult<(ChangeSet, Vec<MergeConflict>)> {
+    let ancestor = VersionNodes::new(storage, Cow::default(), ancestor_id).await?;
+    let ours = VersionNodes::new(storage, Cow::Borrowed(change_set), ours_id).await?;
+    let theirs = VersionNodes::new(storage, Cow::default(), theirs_id).await?;
+
+    let mut changes = ChangeSet::default();
+    let mut conflicts = Vec::new();
+    let mut add_conflict = |conflict: MergeConflict| {
+        if !conflicts.contains(&conflict) {
+            conflicts.push(conflict)
+        }
+    };
+
+    let paths: BTreeSet<&Path> = ancestor
+        .nodes
+        .keys()
+        .chain(ours.nodes.keys())
+        .chain(theirs.nodes.keys())
+        .collect();
+    for path in paths {
+        match (ancestor.nodes.get(path), ours.nodes.get(path), theirs.nodes.get(path)) {
+            (None, None, None) => {}
+            (None, Some(_), None) => {
+                // a new node of ours, it cannot live in a group they deleted
+                if let Some(parent) = ancestor.deleted_parent(path, &theirs) {
+                    add_conflict(MergeConflict::NodeDeletedAndModified { path: parent })
+                }
+            }
+            (None, None, Some(t)) => {
+                if let Some(parent) = ancestor.deleted_parent(path, &ours) {
+                    add_conflict(MergeConflict::NodeDeletedAndModified { path: parent })
+                } else {
+                    add_node(storage, &mut changes, &theirs, t).await?;
+                }
+            }
+            (None, Some(o), Some(t)) => {
+                if !ours.same_node(o, &theirs, t).await? {
+                    add_conflict(MergeConflict::NodeAddedOnBothSides {
+                        path: path.clone(),
+                    })
+                }
+            }
+            (Some(_), None, None) => {}
+            (Some(a), Some(o), None) => {
+                // they deleted the node, we can only follow if we didn't modify it
+                if ancestor.same_node(a, &ours, o).await? {
+                    match o.node_data {
+                        NodeData::Group => changes.delete_group(path.clone(), &o.id),
+                        NodeData::Array(..) => changes.delete_array(path.clone(), &o.id),
+                    }
+                } else {
+                    add_conflict(MergeConflict::NodeDeletedAndModified {
+                        path: path.clone(),
+                    })
+                }
+            }
+            (Some(a), None, Some(t)) => {
+                if !ancestor.same_node(a, &theirs, t).await? {
+                    add_conflict(MergeConflict::NodeDeletedAndModified {
+                        path: path.clone(),
+                    })
+                }
+            }
+            (Some(a), Some(o), Some(t)) => {
+                if a.node_type() != t.node_type() || a.node_type() != o.node_type() {
+                    let ours_changed = !ancestor.same_node(a, &ours, o).await?;
+                    let theirs_changed = !ancestor.same_node(a, &theirs, t).await?;
+                    if ours_changed && theirs_changed {
+                        if !ours.same_node(o, &theirs, t).await? {
+                            add_conflict(MergeConflict::NodeTypeChanged {
+                                path: path.clone(),
+                            })
+                        }
+                    } else if theirs_changed {
+                        // they replaced the node, and we didn't touch it
+                        match o.node_data {
+                            NodeData::Group => changes.delete_group(path.clone(), &o.id),
+                            NodeData::Array(..) => {
+                                changes.delete_array(path.clone(), &o.id)
+                            }
+                        }
+                        add_node(storage, &mut changes, &theirs, t).await?;
+                    }
+                    continue;
+                }
+
+                if t.user_attributes != a.user_attributes {
+                    if o.user_attributes == a.user_attributes {
+                        changes.update_user_attributes(
+                            o.id.clone(),
+                            fetch_user_attributes(storage, t.user_attributes.clone())
+                                .await?,
+                        );
+                    } else if o.user_attributes != t.user_attributes {
+                        add_conflict(MergeConflict::UserAttributesUpdated {
+                            path: path.clone(),
+                        })
+                    }
+                }
+
+                if let (
+                    NodeData::Array(a_meta, a_manifests),
+                    NodeData::Array(o_meta, _),
+                    NodeData::Array(t_meta, t_manifests),
+                ) = (&a.node_data, &o.node_data, &t.node_data)
+                {
+                    if t_meta != a_meta {
+                        if o_meta == a_meta {
+                            changes.update_array(o.id.clone(), t_meta.clone());
+                        } else if o_meta != t_meta {
+                            add_conflict(MergeConflict::ZarrMetadataUpdated {
+                                path: path.clone(),
+                            })
+                        }
+                    }
+
+                    // manifests are immutable, no need to look at chunks if they are the same
+                    if a.id == t.id && a_manifests == t_manifests {
+                        continue;
+                    }
+                    let a_chunks = ancestor.chunks(a).await?;
+                    let t_chunks = theirs.chunks(t).await?;
+                    if a_chunks == t_chunks {
+                        continue;
+                    }
+                    let o_chunks = ours.chunks(o).await?;
+                    let coords: BTreeSet<&ChunkIndices> =
+                        a_chunks.keys().chain(t_chunks.keys()).collect();
+                    let mut conflicting = Vec::new();
+                    for coord in coords {
+                        let (a_chunk, o_chunk, t_chunk) = (
+                            a_chunks.get(coord),
+                            o_chunks.get(coord),
+                            t_chunks.get(coord),
+                        );
+                        if t_chunk == a_chunk || t_chunk == o_chunk {
+                            continue;
+                        }
+                        if o_chunk == a_chunk {
+                            changes.set_chunk_ref(
+                                o.id.clone(),
+                                coord.clone(),
+                                t_chunk.cloned(),
+                            );
+                        } else {
+                            conflicting.push(coord.clone());
+                        }
+                    }
+                    if !conflicting.is_empty() {
+                        add_conflict(MergeConflict::ChunksUpdated {
+                            path: path.clone(),
+                            coords: conflicting,
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    // virtual prefixes merge like the user attributes of a node
+    let names: BTreeSet<&String> = ancestor
+        .virtual_prefixes
+        .keys()
+        .chain(ours.virtual_prefixes.keys())
+        .chain(theirs.virtual_prefixes.keys())
+        .collect();
+    for name in names {
+        let a = ancestor.virtual_prefixes.get(name);
+        let o = ours.virtual_prefixes.get(name);
+        let t = theirs.virtual_prefixes.get(name);
+        if t != a {
+            if o == a {
+                changes.set_virtual_prefix(name.clone(), t.cloned());
+            } else if o != t {
+                add_conflict(MergeConflict::VirtualPrefixUpdated { name: name.clone() })
+            }
+        }
+    }
+
+    Ok((changes, conflicts))
+}
+
+pub async fn raise_if_invalid_snapshot_id(
+    storage: &(dyn Storage + Send + Sync),
+    snapshot_id: &SnapshotId,
+) -> RepositoryResult<()> {
+    storage
+        .fetch_snapshot(snapshot_id)
+        .await
+        .map_err(|_| RepositoryError::SnapshotNotFound { id: snapshot_id.clone() })?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+
+    use std::{error::Error, num::NonZeroU64};
+
+    use crate::{
+        format::manifest::ChunkInfo,
+        metadata::{
+            ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
+        },
+        refs::{fetch_ref, Ref},
+        storage::{logging::LoggingStorage, ObjectStorage},
+        strategies::*,
+    };
+
+    use super::*;
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::{prop_assert, prop_assert_eq};
+    use test_strategy::proptest;
+    use tokio::sync::Barrier;
+
+    #[proptest(async = "tokio")]
+    async fn test_add_delete_group(
+        #[strategy(node_paths())] path: Path,
+        #[strategy(empty_repositories())] mut repository: Repository,
+    ) {
+        // getting any path from an empty repository must fail
+        prop_assert!(repository.get_node(&path).await.is_err());
+
+        // adding a new group must succeed
+        prop_assert!(repository.add_group(path.clone()).await.is_ok());
 
         // Getting a group just added must succeed
         let node = repository.get_node(&path).await;
@@ -1898,9 +3570,9 @@ mod tests {
 
         ds.commit("main", "commit", None).await?;
 
-        // there should be one manifest now
+        // there should be one manifest per array now
         assert_eq!(
-            1,
+            2,
             in_mem_storage
                 .all_keys()
                 .await?
@@ -1921,7 +3593,7 @@ mod tests {
         ds.delete_array(a2path).await?;
         ds.commit("main", "array2 deleted", None).await?;
 
-        // there should be two manifests
+        // array1 didn't change, it keeps its manifest
         assert_eq!(
             2,
             in_mem_storage
@@ -1932,16 +3604,17 @@ mod tests {
                 .count()
         );
 
-        let manifest_id = match ds.get_array(&a1path).await?.node_data {
+        let manifest_id_after_delete = match ds.get_array(&a1path).await?.node_data {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
             NodeData::Group => panic!("must be an array"),
         };
+        assert_eq!(manifest_id_after_delete, manifest_id);
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let size_after_delete = manifest.len();
 
-        assert!(size_after_delete < initial_size);
+        assert_eq!(size_after_delete, initial_size);
 
         // delete a chunk
         ds.set_chunk_ref(a1path.clone(), ChunkIndices(vec![0, 0]), None).await?;
@@ -2174,6 +3847,1265 @@ mod tests {
         Ok(())
     }
 
+    fn merge_test_metadata() -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: vec![4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_merge_disjoint_changes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array1: Path = "/array1".try_into().unwrap();
+        let array2: Path = "/array2".try_into().unwrap();
+        main.add_group(Path::root()).await?;
+        main.add_array(array1.clone(), merge_test_metadata()).await?;
+        main.add_array(array2.clone(), merge_test_metadata()).await?;
+        main.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("zero".into())),
+        )
+        .await?;
+        main.set_chunk_ref(
+            array2.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("zero".into())),
+        )
+        .await?;
+        main.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        main.new_branch("experiment").await?;
+
+        let mut exp = Repository::from_branch_tip(Arc::clone(&storage), "experiment")
+            .await?
+            .build();
+        exp.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("one".into())),
+        )
+        .await?;
+        exp.set_chunk_ref(array2.clone(), ChunkIndices(vec![0]), None).await?;
+        exp.set_user_attributes(
+            array1.clone(),
+            Some(UserAttributes::try_new(br#"{"experiment":1}"#).unwrap()),
+        )
+        .await?;
+        exp.add_group("/results".try_into().unwrap()).await?;
+        exp.commit("experiment", "experiment commit", None).await?;
+
+        main.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![2]),
+            Some(ChunkPayload::Inline("two".into())),
+        )
+        .await?;
+        main.commit(Ref::DEFAULT_BRANCH, "main commit", None).await?;
+
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "merge experiment", None).await?;
+
+        let main =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        for (coord, data) in [(0, "zero"), (1, "one"), (2, "two")] {
+            assert_eq!(
+                main.get_chunk_ref(&array1, &ChunkIndices(vec![coord])).await?,
+                Some(ChunkPayload::Inline(data.into()))
+            );
+        }
+        assert_eq!(main.get_chunk_ref(&array2, &ChunkIndices(vec![0])).await?, None);
+        assert_eq!(
+            main.get_node(&array1).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(
+                UserAttributes::try_new(br#"{"experiment":1}"#).unwrap()
+            ))
+        );
+        assert!(main.get_group(&"/results".try_into().unwrap()).await.is_ok());
+
+        // merging again is a no-op
+        let mut main = main;
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        assert!(!main.has_uncommitted_changes());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repeated_merge() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        main.add_group(Path::root()).await?;
+        main.add_array(array.clone(), merge_test_metadata()).await?;
+        main.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("zero".into())),
+        )
+        .await?;
+        main.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        main.new_branch("experiment").await?;
+
+        let mut exp = Repository::from_branch_tip(Arc::clone(&storage), "experiment")
+            .await?
+            .build();
+        exp.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("one".into())),
+        )
+        .await?;
+        let first_exp = exp.commit("experiment", "experiment commit", None).await?;
+
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "merge experiment", None).await?;
+        let merge_commit = main.ancestry().await?.boxed().try_next().await?.unwrap();
+        assert_eq!(merge_commit.merged_parents, vec![first_exp.clone()]);
+
+        // main changes a chunk that came from the first merge, it's not a conflict because
+        // experiment didn't change it after that
+        main.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("main one".into())),
+        )
+        .await?;
+        main.commit(Ref::DEFAULT_BRANCH, "main commit", None).await?;
+        exp.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline("three".into())),
+        )
+        .await?;
+        exp.commit("experiment", "second experiment commit", None).await?;
+
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "merge experiment again", None).await?;
+        for (coord, data) in [(0, "zero"), (1, "main one"), (3, "three")] {
+            assert_eq!(
+                main.get_chunk_ref(&array, &ChunkIndices(vec![coord])).await?,
+                Some(ChunkPayload::Inline(data.into()))
+            );
+        }
+
+        // the merge commits are ancestors of experiment's next merge from main
+        exp.merge(VersionInfo::BranchTipRef(Ref::DEFAULT_BRANCH.to_string())).await?;
+        exp.commit("experiment", "merge main", None).await?;
+        assert_eq!(
+            exp.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?,
+            Some(ChunkPayload::Inline("main one".into()))
+        );
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "merge experiment back", None).await?;
+        for (coord, data) in [(0, "zero"), (1, "main one"), (3, "three")] {
+            assert_eq!(
+                main.get_chunk_ref(&array, &ChunkIndices(vec![coord])).await?,
+                Some(ChunkPayload::Inline(data.into()))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_merge_node_type_change() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        let replaced: Path = "/replaced".try_into().unwrap();
+        let modified: Path = "/modified".try_into().unwrap();
+        main.add_group(Path::root()).await?;
+        main.add_array(array.clone(), merge_test_metadata()).await?;
+        main.add_group(replaced.clone()).await?;
+        main.add_group(modified.clone()).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        main.new_branch("experiment").await?;
+
+        // they only replace a group with an array
+        let mut exp = Repository::from_branch_tip(Arc::clone(&storage), "experiment")
+            .await?
+            .build();
+        exp.delete_group(replaced.clone()).await?;
+        exp.add_array(replaced.clone(), merge_test_metadata()).await?;
+        exp.set_chunk_ref(
+            replaced.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("theirs".into())),
+        )
+        .await?;
+        exp.commit("experiment", "replace group", None).await?;
+
+        main.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("ours".into())),
+        )
+        .await?;
+        main.commit(Ref::DEFAULT_BRANCH, "main commit", None).await?;
+        main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "merge experiment", None).await?;
+        let main =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert!(matches!(main.get_node(&replaced).await?.node_data, NodeData::Array(..)));
+        assert_eq!(
+            main.get_chunk_ref(&replaced, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Inline("theirs".into()))
+        );
+        assert_eq!(
+            main.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Inline("ours".into()))
+        );
+
+        // replacing a node we modified is a conflict
+        exp.delete_group(modified.clone()).await?;
+        exp.add_array(modified.clone(), merge_test_metadata()).await?;
+        exp.commit("experiment", "replace another group", None).await?;
+        let mut main = main;
+        main.set_user_attributes(
+            modified.clone(),
+            Some(UserAttributes::try_new(br#"{"ours":true}"#).unwrap()),
+        )
+        .await?;
+        let res = main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await;
+        match res {
+            Err(RepositoryError::MergeConflicts { conflicts, .. }) => {
+                assert_eq!(
+                    conflicts,
+                    vec![MergeConflict::NodeTypeChanged { path: modified }]
+                );
+            }
+            other => panic!("expected merge conflicts, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_merge_conflicts() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array1: Path = "/array1".try_into().unwrap();
+        let array2: Path = "/array2".try_into().unwrap();
+        main.add_group(Path::root()).await?;
+        main.add_array(array1.clone(), merge_test_metadata()).await?;
+        main.add_array(array2.clone(), merge_test_metadata()).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        main.new_branch("experiment").await?;
+
+        let mut exp = Repository::from_branch_tip(Arc::clone(&storage), "experiment")
+            .await?
+            .build();
+        exp.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("theirs".into())),
+        )
+        .await?;
+        exp.delete_array(array2.clone()).await?;
+        exp.add_group("/new".try_into().unwrap()).await?;
+        exp.commit("experiment", "experiment commit", None).await?;
+
+        // uncommitted changes are also taken into account
+        main.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("ours".into())),
+        )
+        .await?;
+        main.set_user_attributes(
+            array2.clone(),
+            Some(UserAttributes::try_new(br#"{"ours":true}"#).unwrap()),
+        )
+        .await?;
+        main.add_array("/new".try_into().unwrap(), merge_test_metadata()).await?;
+        let before = main.change_set.clone();
+
+        let res = main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await;
+        match res {
+            Err(RepositoryError::MergeConflicts { conflicts, .. }) => {
+                assert_eq!(
+                    conflicts,
+                    vec![
+                        MergeConflict::ChunksUpdated {
+                            path: array1.clone(),
+                            coords: vec![ChunkIndices(vec![0])]
+                        },
+                        MergeConflict::NodeDeletedAndModified { path: array2.clone() },
+                        MergeConflict::NodeAddedOnBothSides {
+                            path: "/new".try_into().unwrap()
+                        },
+                    ]
+                );
+            }
+            other => panic!("expected merge conflicts, got {other:?}"),
+        }
+        // nothing was changed
+        assert_eq!(main.change_set, before);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_rebase() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+
+        let mut ds1 = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_commit_rebase_attempts(1)
+            .build();
+        let mut ds2 = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_commit_rebase_attempts(1)
+            .build();
+        let mut ds3 = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_commit_rebase_attempts(1)
+            .build();
+
+        for (ds, coord, data) in
+            [(&mut ds1, 0, "chunk 0"), (&mut ds2, 1, "chunk 1"), (&mut ds3, 1, "other")]
+        {
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![coord]),
+                Some(ChunkPayload::Inline(data.into())),
+            )
+            .await?;
+        }
+        let first = ds1.commit("main", "from 1", None).await?;
+
+        // a disjoint chunk gets rebased
+        let second = ds2.commit("main", "from 2", None).await?;
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(ds.snapshot_id(), &second);
+        for coord in [0, 1] {
+            assert_eq!(
+                ds.get_chunk_ref(&array, &ChunkIndices(vec![coord])).await?,
+                Some(ChunkPayload::Inline(format!("chunk {coord}").into()))
+            );
+        }
+        let parents = ds.ancestry().await?.try_collect::<Vec<_>>().await?;
+        assert_eq!(parents[1].id, first);
+
+        // the same chunk cannot be rebased
+        let res = ds3.commit("main", "from 3", None).await;
+        match res {
+            Err(RepositoryError::RebaseConflicts {
+                actual_parent, conflicts, ..
+            }) => {
+                assert_eq!(actual_parent, second);
+                assert_eq!(
+                    conflicts,
+                    vec![MergeConflict::ChunksUpdated {
+                        path: array.clone(),
+                        coords: vec![ChunkIndices(vec![1])]
+                    }]
+                );
+            }
+            other => panic!("expected rebase conflicts, got {other:?}"),
+        }
+        assert!(ds3.has_uncommitted_changes());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_diff() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array1: Path = "/array1".try_into().unwrap();
+        let array2: Path = "/array2".try_into().unwrap();
+        let group: Path = "/group".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array1.clone(), merge_test_metadata()).await?;
+        ds.add_array(array2.clone(), merge_test_metadata()).await?;
+        for coord in [0, 1] {
+            ds.set_chunk_ref(
+                array1.clone(),
+                ChunkIndices(vec![coord]),
+                Some(ChunkPayload::Inline("old".into())),
+            )
+            .await?;
+        }
+        let first = ds.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        let array1_id = ds.get_node(&array1).await?.id;
+        let array2_id = ds.get_node(&array2).await?.id;
+
+        let changes = ds.diff(&first, &first).await?.try_collect::<Vec<_>>().await?;
+        assert!(changes.is_empty());
+
+        ds.set_chunk_ref(array1.clone(), ChunkIndices(vec![0]), None).await?;
+        ds.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+        ds.set_chunk_ref(
+            array1.clone(),
+            ChunkIndices(vec![2]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+        let new_meta = ZarrArrayMetadata { shape: vec![8], ..merge_test_metadata() };
+        ds.update_array(array1.clone(), new_meta.clone()).await?;
+        let atts = UserAttributes::try_new(br#"{"foo":1}"#).unwrap();
+        ds.set_user_attributes(array1.clone(), Some(atts.clone())).await?;
+        ds.delete_array(array2.clone()).await?;
+        ds.add_group(group.clone()).await?;
+
+        let uncommitted = ds.uncommitted_changes().await?.try_collect::<Vec<_>>().await?;
+        let second = ds.commit(Ref::DEFAULT_BRANCH, "second commit", None).await?;
+        let group_id = ds.get_node(&group).await?.id;
+        let changes = ds.diff(&first, &second).await?.try_collect::<Vec<_>>().await?;
+        assert_eq!(
+            changes,
+            vec![
+                Change::UserAttributesChanged {
+                    path: array1.clone(),
+                    node_id: array1_id.clone(),
+                    old: None,
+                    new: Some(UserAttributesSnapshot::Inline(atts)),
+                },
+                Change::ZarrMetadataChanged {
+                    path: array1.clone(),
+                    node_id: array1_id.clone(),
+                    old: Box::new(merge_test_metadata()),
+                    new: Box::new(new_meta),
+                },
+                Change::ChunkRemoved {
+                    path: array1.clone(),
+                    node_id: array1_id.clone(),
+                    coord: ChunkIndices(vec![0]),
+                },
+                Change::ChunkChanged {
+                    path: array1.clone(),
+                    node_id: array1_id.clone(),
+                    coord: ChunkIndices(vec![1]),
+                    payload: ChunkPayload::Inline("new".into()),
+                },
+                Change::ChunkAdded {
+                    path: array1.clone(),
+                    node_id: array1_id.clone(),
+                    coord: ChunkIndices(vec![2]),
+                    payload: ChunkPayload::Inline("new".into()),
+                },
+                Change::NodeDeleted {
+                    path: array2.clone(),
+                    node_id: array2_id,
+                    node_type: NodeType::Array,
+                },
+                Change::NodeAdded {
+                    path: group.clone(),
+                    node_id: group_id,
+                    node_type: NodeType::Group,
+                },
+            ]
+        );
+        assert_eq!(changes, uncommitted);
+
+        // the reverse diff
+        let changes = ds.diff(&second, &first).await?.try_collect::<Vec<_>>().await?;
+        assert_eq!(changes.len(), 7);
+        assert!(changes.contains(&Change::ChunkAdded {
+            path: array1.clone(),
+            node_id: array1_id,
+            coord: ChunkIndices(vec![0]),
+            payload: ChunkPayload::Inline("old".into()),
+        }));
+
+        assert!(ds
+            .uncommitted_changes()
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_long_ancestry() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+
+        let commits = 3 * Snapshot::MAX_SHORT_TERM_HISTORY + 2;
+        let mut expected = vec![];
+        for n in 0..commits {
+            ds.set_user_attributes(
+                Path::root(),
+                Some(
+                    UserAttributes::try_new(format!(r#"{{"n":{n}}}"#).as_bytes())
+                        .unwrap(),
+                ),
+            )
+            .await?;
+            expected.push(ds.commit(Ref::DEFAULT_BRANCH, &n.to_string(), None).await?);
+        }
+        expected.reverse();
+
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(snapshot.short_term_history.len(), Snapshot::MAX_SHORT_TERM_HISTORY);
+        assert_eq!(snapshot.total_parents as usize, commits);
+
+        let parents = ds.ancestry().await?.try_collect::<Vec<_>>().await?;
+        assert_eq!(parents.len(), commits + 1);
+        assert_eq!(
+            parents.iter().map(|m| m.id.clone()).take(commits).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(parents[0].message, (commits - 1).to_string());
+        assert_eq!(parents[commits].message, Snapshot::INITIAL_COMMIT_MESSAGE);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_manifest_splitting() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_max_chunks_per_manifest(4)
+            .build();
+
+        let path: Path = "/array".try_into().unwrap();
+        let zarr_meta = ZarrArrayMetadata { shape: vec![4, 4], ..merge_test_metadata() };
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), zarr_meta).await?;
+        for (x, y) in (0..4).cartesian_product(0..4) {
+            ds.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![x, y]),
+                Some(ChunkPayload::Inline(format!("{x}-{y}").into())),
+            )
+            .await?;
+        }
+        ds.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+
+        let manifests = |node: NodeSnapshot| match node.node_data {
+            NodeData::Array(_, manifests) => manifests,
+            NodeData::Group => panic!("must be an array"),
+        };
+        // blocks of 1x4 chunks, whole rows of the fastest dimension
+        let first = manifests(ds.get_array(&path).await?);
+        assert_eq!(
+            first.iter().map(|m| m.extents.clone()).collect::<Vec<_>>(),
+            (0..4)
+                .map(|x| ManifestExtents(vec![
+                    ChunkIndices(vec![x, 0]),
+                    ChunkIndices(vec![x, 3])
+                ]))
+                .collect::<Vec<_>>()
+        );
+
+        ds.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![3, 0]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "second", None).await?;
+
+        // only the manifest for the modified block is rewritten
+        let second = manifests(ds.get_array(&path).await?);
+        assert_eq!(second.len(), 4);
+        for (ix, (old, new)) in first.iter().zip(second.iter()).enumerate() {
+            assert_eq!(old.extents, new.extents);
+            assert_eq!(old.object_id == new.object_id, ix != 3);
+        }
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(snapshot.manifest_files.len(), 4);
+
+        // reading a chunk only needs the manifest that contains it
+        let before = logging.fetch_operations().len();
+        assert_eq!(
+            ds.get_chunk_ref(&path, &ChunkIndices(vec![3, 0])).await?,
+            Some(ChunkPayload::Inline("new".into()))
+        );
+        assert_eq!(
+            logging.fetch_operations()[before..]
+                .iter()
+                .filter(|(op, _)| op == "fetch_manifests")
+                .map(|(_, id)| id.clone())
+                .collect::<Vec<_>>(),
+            vec![second[3].object_id.0.to_vec()]
+        );
+
+        for (x, y) in (0..4).cartesian_product(0..4) {
+            let expected =
+                if (x, y) == (3, 0) { "new".to_string() } else { format!("{x}-{y}") };
+            assert_eq!(
+                ds.get_chunk_ref(&path, &ChunkIndices(vec![x, y])).await?,
+                Some(ChunkPayload::Inline(expected.into()))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_block_shape() {
+        let meta = |shape: Vec<u64>, chunk_shape: Vec<u64>| ZarrArrayMetadata {
+            shape,
+            chunk_shape: ChunkShape(
+                chunk_shape.into_iter().map(|c| NonZeroU64::new(c).unwrap()).collect(),
+            ),
+            ..merge_test_metadata()
+        };
+        // long arrays are split along their only dimension with many chunks
+        let long = meta(vec![1_000_000, 1, 1], vec![1, 1, 1]);
+        assert_eq!(manifest_block_shape(&long, 100_000), vec![100_000, 1, 1]);
+        assert_eq!(
+            manifest_block(&ChunkIndices(vec![250_000, 0, 0]), &long, 100_000),
+            ChunkIndices(vec![2, 0, 0])
+        );
+        // the fastest dimensions are filled first
+        let grid = meta(vec![100, 100, 100], vec![10, 10, 1]);
+        assert_eq!(manifest_block_shape(&grid, 1000), vec![1, 10, 100]);
+        assert_eq!(manifest_block_shape(&grid, 250), vec![1, 2, 100]);
+        assert_eq!(manifest_block_shape(&grid, 50), vec![1, 1, 50]);
+        // arrays with many dimensions still get large blocks
+        let many = meta(vec![4; 17], vec![1; 17]);
+        assert_eq!(
+            manifest_block_shape(&many, 100_000).iter().product::<u64>(),
+            4u64.pow(8)
+        );
+        assert_eq!(manifest_block_shape(&many, 0).iter().product::<u64>(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_packing() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_chunk_packing(ChunkPackingConfig {
+                max_chunk_size_bytes: 8,
+                target_pack_size_bytes: 10,
+            })
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        for (i, data) in ["aaaa", "bbbb", "cccc", "large chunk"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(Bytes::from(data)).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i as u32]), Some(payload))
+                .await?;
+        }
+        // the first pack was written once full, the last one is still open
+        let chunk_objects = || async {
+            storage.list_chunks().await.unwrap().try_collect::<Vec<_>>().await.unwrap()
+        };
+        assert_eq!(chunk_objects().await.len(), 2);
+        assert!(matches!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![2])).await?,
+            Some(ChunkPayload::Ref(_))
+        ));
+        assert_eq!(
+            get_chunk(
+                ds.get_chunk_reader(&array, &ChunkIndices(vec![2]), &ByteRange::ALL)
+                    .await?
+            )
+            .await?,
+            Some(Bytes::from("cccc"))
+        );
+        ds.commit(Ref::DEFAULT_BRANCH, "packed", None).await?;
+        assert_eq!(chunk_objects().await.len(), 3);
+
+        let mut refs = Vec::new();
+        for i in 0..4 {
+            match ds.get_chunk_ref(&array, &ChunkIndices(vec![i])).await? {
+                Some(ChunkPayload::Ref(chunk_ref)) => refs.push(chunk_ref),
+                other => panic!("unexpected payload {other:?}"),
+            }
+        }
+        assert_eq!(refs[0].id, refs[1].id);
+        assert_ne!(refs[1].id, refs[2].id);
+        assert_ne!(refs[2].id, refs[3].id);
+        assert_eq!(
+            refs.iter().map(|r| (r.offset, r.length)).collect::<Vec<_>>(),
+            vec![(0, 4), (4, 4), (0, 4), (0, 11)]
+        );
+
+        let read = |i: u32, range: ByteRange| {
+            let ds = &ds;
+            let array = &array;
+            async move {
+                get_chunk(
+                    ds.get_chunk_reader(array, &ChunkIndices(vec![i]), &range).await?,
+                )
+                .await
+            }
+        };
+        assert_eq!(read(0, ByteRange::ALL).await?, Some(Bytes::from("aaaa")));
+        assert_eq!(read(1, ByteRange::ALL).await?, Some(Bytes::from("bbbb")));
+        assert_eq!(read(1, ByteRange::from_offset(2)).await?, Some(Bytes::from("bb")));
+        assert_eq!(read(2, ByteRange::ALL).await?, Some(Bytes::from("cccc")));
+        assert_eq!(read(3, ByteRange::ALL).await?, Some(Bytes::from("large chunk")));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_packing_write_failure() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_chunk_packing(ChunkPackingConfig {
+                max_chunk_size_bytes: 8,
+                target_pack_size_bytes: 10,
+            })
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        for (i, data) in ["aaaa", "bbbb"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(Bytes::from(data)).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i as u32]), Some(payload))
+                .await?;
+        }
+
+        // the full pack fails to upload, but its chunks are not lost
+        logging.set_fail_chunk_writes(true);
+        assert!(ds.get_chunk_writer()(Bytes::from("cccc")).await.is_err());
+        assert!(ds.flush_packed_chunks().await.is_err());
+        assert!(ds.commit(Ref::DEFAULT_BRANCH, "packed", None).await.is_err());
+        let read = |i: u32| {
+            let ds = &ds;
+            let array = &array;
+            async move {
+                get_chunk(
+                    ds.get_chunk_reader(array, &ChunkIndices(vec![i]), &ByteRange::ALL)
+                        .await?,
+                )
+                .await
+            }
+        };
+        assert_eq!(read(0).await?, Some(Bytes::from("aaaa")));
+        assert_eq!(read(1).await?, Some(Bytes::from("bbbb")));
+
+        logging.set_fail_chunk_writes(false);
+        ds.commit(Ref::DEFAULT_BRANCH, "packed", None).await?;
+        let ds = Repository::from_branch_tip(Arc::clone(&backend), "main").await?.build();
+        for (i, data) in ["aaaa", "bbbb"].into_iter().enumerate() {
+            assert_eq!(
+                get_chunk(
+                    ds.get_chunk_reader(
+                        &array,
+                        &ChunkIndices(vec![i as u32]),
+                        &ByteRange::ALL
+                    )
+                    .await?,
+                )
+                .await?,
+                Some(Bytes::from(data))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_promote_partial_reads() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let storage = Repository::add_in_mem_asset_caching_with_config(
+            logging_c,
+            &CachingConfig { chunks_bytes: 1024 * 1024, ..CachingConfig::default() },
+        );
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_promote_partial_reads_max_bytes(100)
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from("hello world")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+
+        let read = |range: ByteRange| {
+            let ds = &ds;
+            let array = &array;
+            async move {
+                get_chunk(
+                    ds.get_chunk_reader(array, &ChunkIndices(vec![0]), &range).await?,
+                )
+                .await
+            }
+        };
+        let chunk_fetches = || {
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_chunk")
+                .count()
+        };
+        assert_eq!(read(ByteRange::bounded(0, 5)).await?, Some(Bytes::from("hello")));
+        assert_eq!(read(ByteRange::from_offset(6)).await?, Some(Bytes::from("world")));
+        assert_eq!(read(ByteRange::Last(3)).await?, Some(Bytes::from("rld")));
+        assert_eq!(chunk_fetches(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_coalesced_chunk_reads() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_chunk_packing(ChunkPackingConfig {
+                max_chunk_size_bytes: 8,
+                target_pack_size_bytes: 1024,
+            })
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        for (i, data) in
+            ["aaaa", "bbbb", "cccc", "dddd", "a large chunk", "e"].into_iter().enumerate()
+        {
+            let payload = ds.get_chunk_writer()(Bytes::from(data)).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i as u32]), Some(payload))
+                .await?;
+        }
+        ds.commit(Ref::DEFAULT_BRANCH, "packed", None).await?;
+
+        let requests = [
+            (3, ByteRange::ALL),
+            (0, ByteRange::from_offset(1)),
+            (4, ByteRange::bounded(2, 7)),
+            (2, ByteRange::Last(4)),
+            (5, ByteRange::ALL),
+            (9, ByteRange::ALL),
+            (1, ByteRange::ALL),
+        ]
+        .map(|(i, range)| (array.clone(), ChunkIndices(vec![i]), range));
+        let chunk_fetches = || {
+            logging
+                .fetch_operations()
+                .iter()
+                .filter(|(op, _)| op == "fetch_chunk")
+                .count()
+        };
+        let expected = [Some("dddd"), Some("aaa"), Some("large"), Some("cccc")]
+            .into_iter()
+            .chain([Some("e"), None, Some("bbbb")])
+            .map(|value| value.map(Bytes::from))
+            .collect::<Vec<_>>();
+
+        let res = ds.get_chunks_coalesced(&requests, 0, u64::MAX, 2).await;
+        let res = res.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(res, expected);
+        // one read for the packed chunks and one for the large chunk
+        assert_eq!(chunk_fetches(), 2);
+
+        // gaps larger than the threshold are fetched separately
+        let requests = [&requests[0], &requests[1]].map(Clone::clone);
+        let res = ds.get_chunks_coalesced(&requests, 4, u64::MAX, 2).await;
+        assert_eq!(res.into_iter().collect::<Result<Vec<_>, _>>()?, expected[0..2]);
+        assert_eq!(chunk_fetches(), 4);
+        let res = ds.get_chunks_coalesced(&requests, 100, u64::MAX, 2).await;
+        assert_eq!(res.into_iter().collect::<Result<Vec<_>, _>>()?, expected[0..2]);
+        assert_eq!(chunk_fetches(), 5);
+
+        // merged requests are bounded in size
+        let res = ds.get_chunks_coalesced(&requests, 100, 4, 2).await;
+        assert_eq!(res.into_iter().collect::<Result<Vec<_>, _>>()?, expected[0..2]);
+        assert_eq!(chunk_fetches(), 7);
+
+        // planned reads don't borrow the repository, it can change before they are fetched
+        let planned = ds.plan_chunks_coalesced(&requests, 100, u64::MAX).await;
+        assert_eq!(chunk_fetches(), 7);
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![3]), None).await?;
+        let res = planned.fetch(2).await;
+        assert_eq!(res.into_iter().collect::<Result<Vec<_>, _>>()?, expected[0..2]);
+        assert_eq!(chunk_fetches(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_coalesce_reads() {
+        let read = |index, range: Range<u64>| CoalescedRead {
+            index,
+            range: range.clone(),
+            fetch: range,
+        };
+        let reads = vec![
+            read(0, 20..30),
+            read(1, 0..10),
+            read(2, 12..15),
+            read(3, 5..8),
+            read(4, 100..110),
+        ];
+        assert_eq!(
+            coalesce_reads(reads.clone(), 5, u64::MAX),
+            vec![
+                (
+                    0..30,
+                    vec![read(1, 0..10), read(3, 5..8), read(2, 12..15), read(0, 20..30)]
+                ),
+                (100..110, vec![read(4, 100..110)]),
+            ]
+        );
+        assert_eq!(
+            coalesce_reads(reads, 0, u64::MAX)
+                .into_iter()
+                .map(|(range, _)| range)
+                .collect::<Vec<_>>(),
+            vec![0..10, 12..15, 20..30, 100..110]
+        );
+
+        // a long run of adjacent reads is split in bounded groups
+        let reads: Vec<_> =
+            (0..10).map(|i| read(i, i as u64 * 4..i as u64 * 4 + 4)).collect();
+        assert_eq!(
+            coalesce_reads(reads.clone(), 0, 16)
+                .into_iter()
+                .map(|(range, group)| (range, group.len()))
+                .collect::<Vec<_>>(),
+            vec![(0..16, 4), (16..32, 4), (32..40, 2)]
+        );
+        // reads larger than the limit are fetched alone
+        assert_eq!(
+            coalesce_reads(reads, 0, 3)
+                .into_iter()
+                .map(|(range, _)| range)
+                .collect::<Vec<_>>(),
+            (0..10).map(|i| i * 4..i * 4 + 4).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_relative_virtual_refs() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::TempDir::new()?;
+        for version in ["v1", "v2"] {
+            std::fs::create_dir(dir.path().join(version))?;
+            std::fs::write(dir.path().join(version).join("data.nc"), version.repeat(4))?;
+        }
+        let prefix =
+            |version: &str| format!("file://{}/{version}/", dir.path().display());
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut main = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        main.add_group(Path::root()).await?;
+        main.add_array(array.clone(), merge_test_metadata()).await?;
+        main.commit(Ref::DEFAULT_BRANCH, "first commit", None).await?;
+        main.new_branch("experiment").await?;
+
+        let relative = |name: &str| {
+            Some(ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::Relative(name.into(), "data.nc".into()),
+                offset: 2,
+                length: 4,
+                etag: None,
+                last_modified: None,
+            }))
+        };
+        let res = main
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![0]), relative("archive"))
+            .await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::VirtualReferenceError(
+                VirtualReferenceError::UnknownPrefix(name)
+            )) if name == "archive"
+        ));
+
+        main.set_virtual_prefix("archive", &prefix("v1"))?;
+        main.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), relative("archive"))
+            .await?;
+        async fn read(repo: &Repository) -> RepositoryResult<Option<Bytes>> {
+            let array: Path = "/array".try_into().unwrap();
+            get_chunk(
+                repo.get_chunk_reader(&array, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                    .await?,
+            )
+            .await
+        }
+        assert_eq!(read(&main).await?, Some(Bytes::from("v1v1")));
+        let snapshot = main.commit(Ref::DEFAULT_BRANCH, "relative refs", None).await?;
+        let repo = Repository::update(Arc::clone(&storage), snapshot).build();
+        assert_eq!(
+            repo.virtual_prefixes().await?,
+            BTreeMap::from([("archive".to_string(), prefix("v1"))])
+        );
+        assert_eq!(read(&repo).await?, Some(Bytes::from("v1v1")));
+
+        // moving the prefix moves every chunk that uses it
+        main.set_virtual_prefix("archive", &prefix("v2"))?;
+        assert_eq!(read(&main).await?, Some(Bytes::from("v2v2")));
+        main.commit(Ref::DEFAULT_BRANCH, "move archive", None).await?;
+
+        // prefixes changed on both sides conflict
+        let mut exp = Repository::from_branch_tip(Arc::clone(&storage), "experiment")
+            .await?
+            .build();
+        exp.set_virtual_prefix("archive", &prefix("v1"))?;
+        exp.commit("experiment", "other archive", None).await?;
+        match main.merge(VersionInfo::BranchTipRef("experiment".to_string())).await {
+            Err(RepositoryError::MergeConflicts { conflicts, .. }) => assert_eq!(
+                conflicts,
+                vec![MergeConflict::VirtualPrefixUpdated { name: "archive".to_string() }]
+            ),
+            other => panic!("expected merge conflicts, got {other:?}"),
+        }
+        main.delete_virtual_prefix("archive");
+        assert!(read(&main).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_set_virtual_refs_validates_all() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        ds.set_virtual_prefix("archive", "s3://bucket/archive/")?;
+
+        let vref = |location| VirtualChunkRef {
+            location,
+            offset: 0,
+            length: 10,
+            etag: None,
+            last_modified: None,
+        };
+        let absolute = || vref(VirtualChunkLocation::Absolute("s3://bucket/a.nc".into()));
+        let relative =
+            |name: &str| vref(VirtualChunkLocation::Relative(name.into(), "a.nc".into()));
+
+        let res = ds
+            .set_virtual_refs(
+                array.clone(),
+                [
+                    (ChunkIndices(vec![0]), absolute()),
+                    (ChunkIndices(vec![1, 0]), absolute()),
+                ],
+            )
+            .await;
+        assert!(matches!(res, Err(RepositoryError::InvalidChunkCoordinates { .. })));
+        let res = ds
+            .set_virtual_refs(
+                array.clone(),
+                [
+                    (ChunkIndices(vec![0]), relative("archive")),
+                    (ChunkIndices(vec![1]), relative("missing")),
+                ],
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::VirtualReferenceError(
+                VirtualReferenceError::UnknownPrefix(name)
+            )) if name == "missing"
+        ));
+        // failed batches don't set any reference
+        assert_eq!(ds.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?, None);
+
+        let count = ds
+            .set_virtual_refs(
+                array.clone(),
+                (0..4).map(|idx| (ChunkIndices(vec![idx]), relative("archive"))),
+            )
+            .await?;
+        assert_eq!(count, 4);
+        assert_eq!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![3])).await?,
+            Some(ChunkPayload::Virtual(relative("archive")))
+        );
+        assert!(matches!(
+            ds.set_virtual_refs(Path::root(), [(ChunkIndices(vec![0]), absolute())])
+                .await,
+            Err(RepositoryError::NotAnArray { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_virtual_ref_integrity() -> Result<(), Box<dyn Error>> {
+        use object_store::{local::LocalFileSystem, ObjectStore};
+
+        let dir = tempfile::TempDir::new()?;
+        let file = dir.path().join("data.nc");
+        std::fs::write(&file, "original")?;
+        let etag = LocalFileSystem::new()
+            .head(&object_store::path::Path::from_absolute_path(&file)?)
+            .await?
+            .e_tag;
+        let location = VirtualChunkLocation::from_absolute_path(&format!(
+            "file://{}",
+            file.display()
+        ))?;
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        let pinned = |coord, etag, last_modified| {
+            (
+                ChunkIndices(vec![coord]),
+                Some(ChunkPayload::Virtual(VirtualChunkRef {
+                    location: location.clone(),
+                    offset: 0,
+                    length: 4,
+                    etag,
+                    last_modified,
+                })),
+            )
+        };
+        let in_an_hour = Utc::now() + chrono::Duration::hours(1);
+        for (coord, data) in [
+            pinned(0, etag, None),
+            pinned(1, None, Some(in_an_hour)),
+            pinned(2, Some("not-the-etag".to_string()), None),
+            pinned(3, None, Some(Utc::now() - chrono::Duration::hours(1))),
+        ] {
+            ds.set_chunk_ref(array.clone(), coord, data).await?;
+        }
+        let snapshot = ds.commit(Ref::DEFAULT_BRANCH, "pinned refs", None).await?;
+
+        let repo = |integrity| {
+            Repository::update(Arc::clone(&storage), snapshot.clone())
+                .with_virtual_ref_integrity(integrity)
+                .build()
+        };
+        async fn read(repo: &Repository, coord: u32) -> RepositoryResult<Option<Bytes>> {
+            let array: Path = "/array".try_into().unwrap();
+            get_chunk(
+                repo.get_chunk_reader(
+                    &array,
+                    &ChunkIndices(vec![coord]),
+                    &ByteRange::ALL,
+                )
+                .await?,
+            )
+            .await
+        }
+        let modified = |res: RepositoryResult<Option<Bytes>>| {
+            matches!(
+                res,
+                Err(RepositoryError::VirtualReferenceError(
+                    VirtualReferenceError::ObjectModified(_)
+                ))
+            )
+        };
+
+        let strict = repo(VirtualRefIntegrity::Strict);
+        assert_eq!(read(&strict, 0).await?, Some(Bytes::from("orig")));
+        assert_eq!(read(&strict, 1).await?, Some(Bytes::from("orig")));
+        assert!(modified(read(&strict, 2).await));
+        assert!(modified(read(&strict, 3).await));
+
+        // replacing the file changes its etag
+        std::fs::write(&file, "replaced file")?;
+        assert!(modified(read(&strict, 0).await));
+        let coalesced = strict
+            .get_chunks_coalesced(
+                &[(array.clone(), ChunkIndices(vec![0]), ByteRange::ALL)],
+                0,
+                u64::MAX,
+                1,
+            )
+            .await;
+        assert!(modified(coalesced.into_iter().next().unwrap()));
+
+        for integrity in [VirtualRefIntegrity::Warn, VirtualRefIntegrity::Off] {
+            let repo = repo(integrity);
+            for coord in 0..4 {
+                assert_eq!(read(&repo, coord).await?, Some(Bytes::from("repl")));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_large_user_attributes() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_attributes_threshold_bytes(20)
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        let group: Path = "/group".try_into().unwrap();
+        let large = UserAttributes::try_new(br#"{"history":"a very long description"}"#)?;
+        let small = UserAttributes::try_new(br#"{"n":1}"#)?;
+        ds.add_group(Path::root()).await?;
+        ds.add_group(group.clone()).await?;
+        ds.add_array(array.clone(), merge_test_metadata()).await?;
+        ds.set_user_attributes(array.clone(), Some(large.clone())).await?;
+        ds.set_user_attributes(group.clone(), Some(small.clone())).await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "attributes", None).await?;
+
+        let node = ds.get_node(&array).await?;
+        let Some(UserAttributesSnapshot::Ref(atts_ref)) = node.user_attributes.clone()
+        else {
+            panic!("large attributes must be stored out of line")
+        };
+        assert_eq!(ds.load_user_attributes(node.user_attributes).await?, Some(large));
+        assert_eq!(
+            ds.get_node(&group).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(small))
+        );
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(
+            snapshot.attribute_files.iter().map(|f| f.id.clone()).collect::<Vec<_>>(),
+            vec![atts_ref.object_id.clone()]
+        );
+
+        // later commits keep pointing to the same attributes file
+        ds.add_group("/other".try_into().unwrap()).await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "unrelated", None).await?;
+        assert_eq!(
+            ds.get_node(&array).await?.user_attributes,
+            Some(UserAttributesSnapshot::Ref(atts_ref.clone()))
+        );
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert_eq!(snapshot.attribute_files.len(), 1);
+
+        ds.set_user_attributes(array.clone(), None).await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "no attributes", None).await?;
+        assert_eq!(ds.get_node(&array).await?.user_attributes, None);
+        let snapshot = storage.fetch_snapshot(ds.snapshot_id()).await?;
+        assert!(snapshot.attribute_files.is_empty());
+        Ok(())
+    }
+
     #[cfg(test)]
     mod state_machine_test {
         use crate::format::snapshot::NodeData;
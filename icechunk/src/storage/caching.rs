@@ -1,9 +1,21 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
 
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::stream::BoxStream;
-use quick_cache::sync::Cache;
+use quick_cache::{
+    sync::{Cache, DefaultLifecycle},
+    DefaultHashBuilder, OptionsBuilder, Weighter,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
@@ -13,31 +25,365 @@ use crate::{
     private,
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{ListInfo, Storage, StorageError, StorageResult};
+
+/// Maximum size of each of the in memory caches
+///
+/// Snapshots, manifests and attribute files are measured by the length of their
+/// serialized form, chunks by their length. A limit of zero disables the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CachingConfig {
+    pub snapshots_bytes: u64,
+    pub manifests_bytes: u64,
+    pub attributes_bytes: u64,
+    pub chunks_bytes: u64,
+}
+
+impl Default for CachingConfig {
+    fn default() -> Self {
+        Self {
+            snapshots_bytes: 32 * 1024 * 1024,
+            manifests_bytes: 256 * 1024 * 1024,
+            attributes_bytes: 32 * 1024 * 1024,
+            chunks_bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Hits and misses of each of the caches of a [`MemCachingStorage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub snapshots: CacheCounters,
+    pub manifests: CacheCounters,
+    pub attributes: CacheCounters,
+    pub chunks: CacheCounters,
+}
+
+#[derive(Debug, Default)]
+struct AtomicCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl AtomicCounters {
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> CacheCounters {
+        CacheCounters {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A cached value together with its size, computed once when it's inserted
+#[derive(Debug, Clone)]
+struct Weighted<V> {
+    value: V,
+    weight: u64,
+}
+
+impl<V> Weighted<V> {
+    fn new(value: V, weight: u64) -> Self {
+        // zero weight items are never evicted
+        Self { value, weight: weight.max(1) }
+    }
+}
+
+impl<T: Serialize> Weighted<Arc<T>> {
+    fn serialized(value: Arc<T>) -> Self {
+        let weight = serialized_size(value.as_ref());
+        Self::new(value, weight)
+    }
+}
+
+/// Length of the msgpack encoding of `value`, the same bytes the backends read and write
+///
+/// The encoding is only counted, not buffered, and it runs once, when the object enters
+/// the cache. This accounts for the payloads of virtual refs and inline chunks, that can
+/// make up most of the size of a manifest.
+fn serialized_size<T: Serialize + ?Sized>(value: &T) -> u64 {
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter::default();
+    // writing to the counter cannot fail, and the object was already encoded by the backend
+    let _ = rmp_serde::encode::write(&mut counter, value);
+    counter.0
+}
+
+#[derive(Debug, Clone)]
+struct SizeWeighter;
+
+impl<K, V> Weighter<K, Weighted<V>> for SizeWeighter {
+    fn weight(&self, _key: &K, val: &Weighted<V>) -> u64 {
+        val.weight
+    }
+}
+
+type WeightedCache<K, V> = Cache<K, Weighted<V>, SizeWeighter>;
+
+fn new_cache<K, V>(capacity_bytes: u64) -> WeightedCache<K, V>
+where
+    K: Eq + std::hash::Hash,
+    V: Clone,
+{
+    #[allow(clippy::expect_used)]
+    let options = OptionsBuilder::new()
+        // a single shard, objects can be large compared to the capacity of the cache
+        .shards(1)
+        .estimated_items_capacity(1024)
+        .weight_capacity(capacity_bytes)
+        .build()
+        .expect("valid cache options");
+    Cache::with_options(
+        options,
+        SizeWeighter,
+        DefaultHashBuilder::default(),
+        DefaultLifecycle::default(),
+    )
+}
+
+/// The parts of a chunk object held in the cache
+///
+/// Any range covered by the cached segments is answered from the cache, so a fetch of the
+/// whole object serves all the partial reads that follow.
+#[derive(Debug, Clone, Default)]
+struct CachedChunk {
+    /// Segments of the object by offset, they don't overlap
+    ///
+    /// Adjacent segments are not joined, that would copy the cached bytes on every
+    /// insert. Only the reads that span several segments copy, and only what they return.
+    segments: BTreeMap<u64, Bytes>,
+    /// Length of the object, if we fetched up to its end from a known offset
+    length: Option<u64>,
+    /// The longest suffix fetched while the length of the object was unknown
+    suffix: Option<Bytes>,
+}
+
+impl CachedChunk {
+    /// The range requested as a bounded range, if we know enough to compute it
+    fn bounded(&self, range: &ByteRange) -> Option<Range<u64>> {
+        match range {
+            ByteRange::Bounded(range) => Some(range.clone()),
+            ByteRange::From(start) => self.length.map(|length| *start..length),
+            ByteRange::Last(n) => {
+                self.length.map(|length| length.saturating_sub(*n)..length)
+            }
+        }
+    }
+
+    fn get(&self, range: &ByteRange) -> Option<Bytes> {
+        match (self.bounded(range), range) {
+            (Some(range), _) => self.get_bounded(range),
+            (None, ByteRange::Last(n)) => self
+                .suffix
+                .as_ref()
+                .filter(|suffix| suffix.len() as u64 >= *n)
+                .map(|suffix| suffix.slice(suffix.len() - *n as usize..)),
+            (None, _) => None,
+        }
+    }
+
+    fn get_bounded(&self, range: Range<u64>) -> Option<Bytes> {
+        let mut parts = Vec::new();
+        let mut pos = range.start;
+        while pos < range.end {
+            let (start, bytes) = self.segments.range(..=pos).next_back()?;
+            let end = start + bytes.len() as u64;
+            if end <= pos {
+                return None;
+            }
+            let part_end = end.min(range.end);
+            parts.push(bytes.slice((pos - start) as usize..(part_end - start) as usize));
+            pos = part_end;
+        }
+        match parts.as_slice() {
+            [] => None,
+            [bytes] => Some(bytes.clone()),
+            _ => {
+                let mut joined =
+                    BytesMut::with_capacity((range.end - range.start) as usize);
+                for part in parts {
+                    joined.extend_from_slice(&part);
+                }
+                Some(joined.freeze())
+            }
+        }
+    }
+
+    /// The end of the cached bytes that follow `pos` without gaps
+    fn covered_from(&self, mut pos: u64) -> u64 {
+        while let Some((start, bytes)) = self.segments.range(..=pos).next_back() {
+            let end = start + bytes.len() as u64;
+            if end <= pos {
+                break;
+            }
+            pos = end;
+        }
+        pos
+    }
+
+    /// The start of the cached bytes that precede `pos` without gaps
+    fn covered_until(&self, mut pos: u64) -> u64 {
+        while let Some((start, bytes)) = self.segments.range(..pos).next_back() {
+            if start + (bytes.len() as u64) < pos {
+                break;
+            }
+            pos = *start;
+        }
+        pos
+    }
+
+    /// The part of a bounded range not cached, if the cache covers its start or its end
+    fn missing_part(&self, range: &ByteRange) -> Option<Range<u64>> {
+        let ByteRange::Bounded(range) = range else {
+            return None;
+        };
+        let start = self.covered_from(range.start);
+        let end = self.covered_until(range.end);
+        (start < end && (start, end) != (range.start, range.end)).then_some(start..end)
+    }
+
+    fn insert(&mut self, range: &ByteRange, bytes: Bytes) {
+        let start = match range {
+            ByteRange::Bounded(range) => range.start,
+            ByteRange::From(start) => {
+                self.length = Some(start + bytes.len() as u64);
+                *start
+            }
+            ByteRange::Last(_) => match self.length {
+                Some(length) => length - bytes.len() as u64,
+                None => {
+                    if self.suffix.as_ref().map(|s| s.len() < bytes.len()).unwrap_or(true)
+                    {
+                        self.suffix = Some(bytes);
+                    }
+                    return;
+                }
+            },
+        };
+        if bytes.is_empty() {
+            return;
+        }
+        let end = start + bytes.len() as u64;
+        // the new segment replaces the ones it contains, and fills the gaps between the rest
+        self.segments.retain(|s, b| *s < start || s + b.len() as u64 > end);
+        let overlapping: Vec<_> = self
+            .segments
+            .range(..end)
+            .map(|(s, b)| (*s, s + b.len() as u64))
+            .filter(|(_, e)| *e > start)
+            .collect();
+        let mut pos = start;
+        for (s, e) in overlapping {
+            if pos < s {
+                self.segments.insert(
+                    pos,
+                    bytes.slice((pos - start) as usize..(s - start) as usize),
+                );
+            }
+            pos = pos.max(e);
+        }
+        if pos < end {
+            self.segments.insert(pos, bytes.slice((pos - start) as usize..));
+        }
+    }
+
+    fn weight(&self) -> u64 {
+        self.segments.values().map(|b| b.len() as u64).sum::<u64>()
+            + self.suffix.as_ref().map(|s| s.len() as u64).unwrap_or(0)
+    }
+}
 
 #[derive(Debug)]
 pub struct MemCachingStorage {
     backend: Arc<dyn Storage + Send + Sync>,
-    snapshot_cache: Cache<SnapshotId, Arc<Snapshot>>,
-    manifest_cache: Cache<ManifestId, Arc<Manifest>>,
-    attributes_cache: Cache<AttributesId, Arc<AttributesTable>>,
-    chunk_cache: Cache<(ChunkId, ByteRange), Bytes>,
+    snapshot_cache: WeightedCache<SnapshotId, Arc<Snapshot>>,
+    manifest_cache: WeightedCache<ManifestId, Arc<Manifest>>,
+    attributes_cache: WeightedCache<AttributesId, Arc<AttributesTable>>,
+    chunk_cache: WeightedCache<ChunkId, Arc<CachedChunk>>,
+    /// Chunks with a fetch in progress, reads of the same chunk wait for each other
+    chunk_locks: Mutex<HashMap<ChunkId, Arc<tokio::sync::Mutex<()>>>>,
+    snapshot_counters: AtomicCounters,
+    manifest_counters: AtomicCounters,
+    attributes_counters: AtomicCounters,
+    chunk_counters: AtomicCounters,
 }
 
 impl MemCachingStorage {
-    pub fn new(
-        backend: Arc<dyn Storage + Send + Sync>,
-        num_snapshots: u16,
-        num_manifests: u16,
-        num_attributes: u16,
-        num_chunks: u16,
-    ) -> Self {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>, config: &CachingConfig) -> Self {
         MemCachingStorage {
             backend,
-            snapshot_cache: Cache::new(num_snapshots as usize),
-            manifest_cache: Cache::new(num_manifests as usize),
-            attributes_cache: Cache::new(num_attributes as usize),
-            chunk_cache: Cache::new(num_chunks as usize),
+            snapshot_cache: new_cache(config.snapshots_bytes),
+            manifest_cache: new_cache(config.manifests_bytes),
+            attributes_cache: new_cache(config.attributes_bytes),
+            chunk_cache: new_cache(config.chunks_bytes),
+            chunk_locks: Mutex::new(HashMap::new()),
+            snapshot_counters: AtomicCounters::default(),
+            manifest_counters: AtomicCounters::default(),
+            attributes_counters: AtomicCounters::default(),
+            chunk_counters: AtomicCounters::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            snapshots: self.snapshot_counters.get(),
+            manifests: self.manifest_counters.get(),
+            attributes: self.attributes_counters.get(),
+            chunks: self.chunk_counters.get(),
+        }
+    }
+
+    fn cached_chunk_range(&self, id: &ChunkId, range: &ByteRange) -> Option<Bytes> {
+        self.chunk_cache.get(id).and_then(|cached| cached.value.get(range))
+    }
+
+    fn chunk_lock(&self, id: &ChunkId) -> ChunkLock<'_> {
+        let mut locks = self.chunk_locks.lock().unwrap_or_else(PoisonError::into_inner);
+        let lock = Arc::clone(locks.entry(id.clone()).or_default());
+        ChunkLock { locks: &self.chunk_locks, id: id.clone(), lock }
+    }
+}
+
+/// A lock on the reads of a chunk, removed from the map when its last user is done
+struct ChunkLock<'a> {
+    locks: &'a Mutex<HashMap<ChunkId, Arc<tokio::sync::Mutex<()>>>>,
+    id: ChunkId,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for ChunkLock<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        // one reference is in the map, the other is ours
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.id);
         }
     }
 }
@@ -51,10 +397,15 @@ impl Storage for MemCachingStorage {
         id: &SnapshotId,
     ) -> Result<Arc<Snapshot>, StorageError> {
         match self.snapshot_cache.get_value_or_guard_async(id).await {
-            Ok(snapshot) => Ok(snapshot),
+            Ok(snapshot) => {
+                self.snapshot_counters.hit();
+                Ok(snapshot.value)
+            }
             Err(guard) => {
+                self.snapshot_counters.miss();
                 let snapshot = self.backend.fetch_snapshot(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&snapshot));
+                let _fail_is_ok =
+                    guard.insert(Weighted::serialized(Arc::clone(&snapshot)));
                 Ok(snapshot)
             }
         }
@@ -65,10 +416,14 @@ impl Storage for MemCachingStorage {
         id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
         match self.attributes_cache.get_value_or_guard_async(id).await {
-            Ok(table) => Ok(table),
+            Ok(table) => {
+                self.attributes_counters.hit();
+                Ok(table.value)
+            }
             Err(guard) => {
+                self.attributes_counters.miss();
                 let table = self.backend.fetch_attributes(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&table));
+                let _fail_is_ok = guard.insert(Weighted::serialized(Arc::clone(&table)));
                 Ok(table)
             }
         }
@@ -79,10 +434,15 @@ impl Storage for MemCachingStorage {
         id: &ManifestId,
     ) -> Result<Arc<Manifest>, StorageError> {
         match self.manifest_cache.get_value_or_guard_async(id).await {
-            Ok(manifest) => Ok(manifest),
+            Ok(manifest) => {
+                self.manifest_counters.hit();
+                Ok(manifest.value)
+            }
             Err(guard) => {
+                self.manifest_counters.miss();
                 let manifest = self.backend.fetch_manifests(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
+                let _fail_is_ok =
+                    guard.insert(Weighted::serialized(Arc::clone(&manifest)));
                 Ok(manifest)
             }
         }
@@ -93,15 +453,45 @@ impl Storage for MemCachingStorage {
         id: &ChunkId,
         range: &ByteRange,
     ) -> Result<Bytes, StorageError> {
-        let key = (id.clone(), range.clone());
-        match self.chunk_cache.get_value_or_guard_async(&key).await {
-            Ok(bytes) => Ok(bytes),
-            Err(guard) => {
+        if let Some(bytes) = self.cached_chunk_range(id, range) {
+            self.chunk_counters.hit();
+            return Ok(bytes);
+        }
+
+        // concurrent reads of the chunk merge what they fetch into the same cache entry,
+        // they take turns so none of them overwrites the others
+        let chunk_lock = self.chunk_lock(id);
+        let _guard = chunk_lock.lock.lock().await;
+        // a read of the same range may have been cached while we waited
+        let cached = self.chunk_cache.get(id).map(|cached| cached.value);
+        if let Some(bytes) = cached.as_ref().and_then(|cached| cached.get(range)) {
+            self.chunk_counters.hit();
+            return Ok(bytes);
+        }
+        self.chunk_counters.miss();
+
+        let mut chunk = cached.as_deref().cloned().unwrap_or_default();
+        let bytes = match (range, chunk.missing_part(range)) {
+            // only fetch the part of the range that is not cached
+            (ByteRange::Bounded(_), Some(missing)) => {
+                let fetched =
+                    self.backend.fetch_chunk(id, &missing.clone().into()).await?;
+                chunk.insert(&missing.into(), fetched);
+                match chunk.get(range) {
+                    Some(bytes) => bytes,
+                    // the object ends before the requested range, let the backend handle it
+                    None => self.backend.fetch_chunk(id, range).await?,
+                }
+            }
+            _ => {
                 let bytes = self.backend.fetch_chunk(id, range).await?;
-                let _fail_is_ok = guard.insert(bytes.clone());
-                Ok(bytes)
+                chunk.insert(range, bytes.clone());
+                bytes
             }
-        }
+        };
+        let weight = chunk.weight();
+        self.chunk_cache.insert(id.clone(), Weighted::new(Arc::new(chunk), weight));
+        Ok(bytes)
     }
 
     async fn write_snapshot(
@@ -110,7 +500,7 @@ impl Storage for MemCachingStorage {
         snapshot: Arc<Snapshot>,
     ) -> Result<(), StorageError> {
         self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
-        self.snapshot_cache.insert(id, snapshot);
+        self.snapshot_cache.insert(id, Weighted::serialized(snapshot));
         Ok(())
     }
 
@@ -120,7 +510,7 @@ impl Storage for MemCachingStorage {
         table: Arc<AttributesTable>,
     ) -> Result<(), StorageError> {
         self.backend.write_attributes(id.clone(), Arc::clone(&table)).await?;
-        self.attributes_cache.insert(id, table);
+        self.attributes_cache.insert(id, Weighted::serialized(table));
         Ok(())
     }
 
@@ -130,7 +520,7 @@ impl Storage for MemCachingStorage {
         manifest: Arc<Manifest>,
     ) -> Result<(), StorageError> {
         self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
-        self.manifest_cache.insert(id, manifest);
+        self.manifest_cache.insert(id, Weighted::serialized(manifest));
         Ok(())
     }
 
@@ -163,6 +553,48 @@ impl Storage for MemCachingStorage {
     ) -> StorageResult<BoxStream<StorageResult<String>>> {
         self.backend.ref_versions(ref_name).await
     }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+
+    // deleted chunks are left in the chunk cache, the cache is keyed by byte range too, and
+    // garbage collected chunks are not referenced by any manifest
+    async fn delete_snapshots(&self, ids: Vec<SnapshotId>) -> StorageResult<usize> {
+        for id in ids.iter() {
+            self.snapshot_cache.remove(id);
+        }
+        self.backend.delete_snapshots(ids).await
+    }
+
+    async fn delete_manifests(&self, ids: Vec<ManifestId>) -> StorageResult<usize> {
+        for id in ids.iter() {
+            self.manifest_cache.remove(id);
+        }
+        self.backend.delete_manifests(ids).await
+    }
+
+    async fn delete_attributes(&self, ids: Vec<AttributesId>) -> StorageResult<usize> {
+        for id in ids.iter() {
+            self.attributes_cache.remove(id);
+        }
+        self.backend.delete_attributes(ids).await
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.stats())
+    }
 }
 
 #[cfg(test)]
@@ -179,6 +611,15 @@ mod test {
         storage::{logging::LoggingStorage, ObjectStorage, Storage},
     };
 
+    fn no_caching() -> CachingConfig {
+        CachingConfig {
+            snapshots_bytes: 0,
+            manifests_bytes: 0,
+            attributes_bytes: 0,
+            chunks_bytes: 0,
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_caching_storage_caches() -> Result<(), Box<dyn std::error::Error>> {
         let backend: Arc<dyn Storage + Send + Sync> =
@@ -202,7 +643,10 @@ mod test {
 
         let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
         let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
-        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 2, 0, 0);
+        let caching = MemCachingStorage::new(
+            Arc::clone(&logging_c),
+            &CachingConfig { manifests_bytes: 1024 * 1024, ..no_caching() },
+        );
 
         let manifest = Arc::new(vec![ci2].into_iter().collect());
         let id = ManifestId::random();
@@ -239,6 +683,11 @@ mod test {
             logging.fetch_operations(),
             vec![("fetch_manifests".to_string(), pre_existing_id.0.to_vec())]
         );
+
+        let stats = caching.stats();
+        assert_eq!(stats.manifests, CacheCounters { hits: 4, misses: 1 });
+        assert_eq!(stats.snapshots, CacheCounters::default());
+        assert_eq!(caching.cache_stats(), Some(stats));
         Ok(())
     }
 
@@ -273,13 +722,14 @@ mod test {
 
         let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
         let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let manifest_size = serialized_size(table1.as_ref());
+        assert_eq!(manifest_size, rmp_serde::to_vec(table1.as_ref())?.len() as u64);
+        assert_eq!(manifest_size, serialized_size(table2.as_ref()));
+        assert_eq!(manifest_size, serialized_size(table3.as_ref()));
         let caching = MemCachingStorage::new(
             Arc::clone(&logging_c),
             // the cache can only fit 2 manifests.
-            0,
-            2,
-            0,
-            0,
+            &CachingConfig { manifests_bytes: 2 * manifest_size, ..no_caching() },
         );
 
         // we keep asking for all 3 items, but the cache can only fit 2
@@ -293,4 +743,212 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_cache_weighs_inline_payloads() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+
+        let small = ChunkInfo {
+            node: NodeId::random(),
+            coord: ChunkIndices(vec![]),
+            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+        };
+        let large = ChunkInfo {
+            payload: ChunkPayload::Inline(Bytes::from(vec![0u8; 4096])),
+            ..small.clone()
+        };
+        let small_id = ManifestId::random();
+        let small_table: Arc<Manifest> = Arc::new(vec![small].into_iter().collect());
+        backend.write_manifests(small_id.clone(), Arc::clone(&small_table)).await?;
+        let large_id = ManifestId::random();
+        let large_table: Arc<Manifest> = Arc::new(vec![large].into_iter().collect());
+        backend.write_manifests(large_id.clone(), Arc::clone(&large_table)).await?;
+        assert!(serialized_size(large_table.as_ref()) > 4096);
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(
+            logging_c,
+            &CachingConfig { manifests_bytes: 1024, ..no_caching() },
+        );
+
+        // both manifests have one entry, but only the small one fits in the cache
+        for _ in 0..3 {
+            assert_eq!(caching.fetch_manifests(&small_id).await?, small_table);
+            assert_eq!(caching.fetch_manifests(&large_id).await?, large_table);
+        }
+        let ops = logging.fetch_operations();
+        assert_eq!(ops.iter().filter(|(_, id)| id == &small_id.0.to_vec()).count(), 1);
+        assert_eq!(ops.iter().filter(|(_, id)| id == &large_id.0.to_vec()).count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_cache_answers_sub_ranges(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let data: Bytes = (0..100u8).collect::<Vec<_>>().into();
+        let id = ChunkId::random();
+        backend.write_chunk(id.clone(), data.clone()).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = MemCachingStorage::new(
+            logging_c,
+            &CachingConfig { chunks_bytes: 1024, ..no_caching() },
+        );
+
+        // sub-ranges of a cached range don't go to the backend
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(10, 50)).await?,
+            data.slice(10..50)
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(20, 30)).await?,
+            data.slice(20..30)
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(10, 50)).await?,
+            data.slice(10..50)
+        );
+        assert_eq!(logging.fetch_operations().len(), 1);
+
+        // overlapping ranges are fetched, then they are all cached
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(40, 60)).await?,
+            data.slice(40..60)
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(0, 15)).await?,
+            data.slice(0..15)
+        );
+        assert_eq!(logging.fetch_operations().len(), 3);
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(5, 55)).await?,
+            data.slice(5..55)
+        );
+        assert_eq!(logging.fetch_operations().len(), 3);
+
+        // once the whole object is cached, any range is answered
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::ALL).await?, data);
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::from_offset(90)).await?,
+            data.slice(90..)
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::Last(5)).await?,
+            data.slice(95..)
+        );
+        assert_eq!(
+            caching.fetch_chunk(&id, &ByteRange::bounded(60, 99)).await?,
+            data.slice(60..99)
+        );
+        assert_eq!(logging.fetch_operations().len(), 4);
+        assert_eq!(caching.stats().chunks, CacheCounters { hits: 6, misses: 4 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_chunk_segments() {
+        let data: Bytes = (0..100u8).collect::<Vec<_>>().into();
+        let mut chunk = CachedChunk::default();
+        chunk.insert(&ByteRange::bounded(10, 20), data.slice(10..20));
+
+        // only the parts not cached are missing
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(15, 40)), Some(20..40));
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(0, 12)), Some(0..10));
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(30, 40)), None);
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(0, 40)), None);
+
+        chunk.insert(&ByteRange::bounded(20, 40), data.slice(20..40));
+        // adjacent segments answer the reads that span them
+        assert_eq!(chunk.segments.len(), 2);
+        assert_eq!(chunk.get(&ByteRange::bounded(12, 38)), Some(data.slice(12..38)));
+        assert_eq!(chunk.get(&ByteRange::bounded(22, 38)), Some(data.slice(22..38)));
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(15, 50)), Some(40..50));
+        assert_eq!(chunk.missing_part(&ByteRange::bounded(0, 30)), Some(0..10));
+        assert_eq!(chunk.get(&ByteRange::bounded(12, 41)), None);
+        // without the length we can't resolve suffixes or open ranges
+        assert_eq!(chunk.get(&ByteRange::from_offset(30)), None);
+
+        chunk.insert(&ByteRange::Last(10), data.slice(90..));
+        assert_eq!(chunk.get(&ByteRange::Last(4)), Some(data.slice(96..)));
+        assert_eq!(chunk.get(&ByteRange::Last(11)), None);
+
+        chunk.insert(&ByteRange::from_offset(30), data.slice(30..));
+        assert_eq!(chunk.length, Some(100));
+        // only the bytes not cached yet are added
+        assert_eq!(
+            chunk.segments.iter().map(|(s, b)| (*s, b.clone())).collect::<Vec<_>>(),
+            vec![
+                (10, data.slice(10..20)),
+                (20, data.slice(20..40)),
+                (40, data.slice(40..))
+            ]
+        );
+        assert_eq!(chunk.get(&ByteRange::Last(11)), Some(data.slice(89..)));
+        assert_eq!(chunk.get(&ByteRange::from_offset(10)), Some(data.slice(10..)));
+        assert_eq!(chunk.get(&ByteRange::from_offset(5)), None);
+        assert_eq!(chunk.weight(), 100);
+
+        // a segment that contains cached ones replaces them
+        chunk.insert(&ByteRange::bounded(0, 60), data.slice(0..60));
+        assert_eq!(
+            chunk.segments.iter().map(|(s, b)| (*s, b.clone())).collect::<Vec<_>>(),
+            vec![(0, data.slice(0..40)), (40, data.slice(40..))]
+        );
+        assert_eq!(chunk.weight(), 110);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_chunk_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let data: Bytes = (0..100u8).collect::<Vec<_>>().into();
+        let id = ChunkId::random();
+        backend.write_chunk(id.clone(), data.clone()).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let caching = Arc::new(MemCachingStorage::new(
+            logging_c,
+            &CachingConfig { chunks_bytes: 1024, ..no_caching() },
+        ));
+        let read_all = |ranges: Vec<Range<u64>>| {
+            let tasks: Vec<_> = ranges
+                .into_iter()
+                .map(|range| {
+                    let caching = Arc::clone(&caching);
+                    let id = id.clone();
+                    tokio::spawn(async move {
+                        let res = caching.fetch_chunk(&id, &range.clone().into()).await;
+                        (range, res)
+                    })
+                })
+                .collect();
+            futures::future::join_all(tasks)
+        };
+
+        // identical reads are fetched once
+        for res in read_all(vec![0..10; 10]).await {
+            let (range, bytes) = res?;
+            assert_eq!(bytes?, data.slice(range.start as usize..range.end as usize));
+        }
+        assert_eq!(logging.fetch_operations().len(), 1);
+
+        // disjoint reads all end up in the cache
+        let ranges: Vec<_> = (1..10).map(|i| i * 10..i * 10 + 10).collect();
+        for res in read_all(ranges).await {
+            let (range, bytes) = res?;
+            assert_eq!(bytes?, data.slice(range.start as usize..range.end as usize));
+        }
+        assert_eq!(logging.fetch_operations().len(), 10);
+        assert_eq!(caching.fetch_chunk(&id, &ByteRange::bounded(0, 100)).await?, data);
+        assert_eq!(logging.fetch_operations().len(), 10);
+        assert_eq!(caching.chunk_locks.lock().map(|locks| locks.len()).ok(), Some(0));
+        Ok(())
+    }
 }
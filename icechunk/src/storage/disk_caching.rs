@@ -0,0 +1,457 @@
+//! A persistent cache of immutable objects in a local directory.
+//!
+//! Snapshots, manifests, attribute files and chunk ranges are stored as files named after
+//! their ids, so the cache can be shared by every repository and process in the machine.
+//! Entries are written to a temporary file and atomically renamed into place, readers
+//! either find a complete entry or nothing. The cache is best effort: any filesystem error
+//! falls back to the backend storage.
+//!
+//! When the files in the cache grow over [`DiskCacheConfig::max_bytes`] the least recently
+//! used entries are deleted, using the modification time of the files, which is updated on
+//! every hit. Refs are mutable and never cached.
+use std::{
+    fs,
+    future::Future,
+    path::{Path as StdPath, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    private,
+};
+
+use super::{
+    ListInfo, Storage, StorageError, StorageResult, ATTRIBUTES_PREFIX, CHUNK_PREFIX,
+    MANIFEST_PREFIX, SNAPSHOT_PREFIX,
+};
+
+const TMP_PREFIX: &str = "tmp/";
+const ENTRY_PREFIXES: [&str; 4] =
+    [SNAPSHOT_PREFIX, MANIFEST_PREFIX, ATTRIBUTES_PREFIX, CHUNK_PREFIX];
+/// Temporary files older than this were left behind by a crashed writer
+const TMP_MAX_AGE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskCacheConfig {
+    pub directory: PathBuf,
+    /// Size of the cache files above which the least recently used are evicted
+    pub max_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct DiskCachingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    config: DiskCacheConfig,
+    /// Estimated size of the cache, other processes can also add entries
+    size_bytes: AtomicU64,
+    evicting: AtomicBool,
+}
+
+impl DiskCachingStorage {
+    pub fn new(
+        backend: Arc<dyn Storage + Send + Sync>,
+        config: DiskCacheConfig,
+    ) -> StorageResult<Self> {
+        for prefix in ENTRY_PREFIXES.iter().chain([&TMP_PREFIX]) {
+            fs::create_dir_all(config.directory.join(prefix)).map_err(|err| {
+                StorageError::Other(format!(
+                    "cannot create cache directory {}: {err}",
+                    config.directory.display()
+                ))
+            })?;
+        }
+        let size_bytes = scan_entries(&config.directory).iter().map(|e| e.size).sum();
+        Ok(Self {
+            backend,
+            config,
+            size_bytes: AtomicU64::new(size_bytes),
+            evicting: AtomicBool::new(false),
+        })
+    }
+
+    fn entry_path(&self, prefix: &str, name: &str) -> PathBuf {
+        self.config.directory.join(prefix).join(name)
+    }
+
+    async fn fetch_table<T, F, Fut>(
+        &self,
+        path: PathBuf,
+        fetch: F,
+    ) -> StorageResult<Arc<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = StorageResult<Arc<T>>>,
+    {
+        if let Some(bytes) = read_entry(path.clone()).await {
+            match rmp_serde::from_slice(bytes.as_ref()) {
+                Ok(table) => return Ok(Arc::new(table)),
+                Err(_) => remove_entry(path.clone()).await,
+            }
+        }
+        let table = fetch().await?;
+        self.insert_table(path, table.as_ref()).await;
+        Ok(table)
+    }
+
+    async fn insert_table<T: Serialize>(&self, path: PathBuf, table: &T) {
+        if let Ok(bytes) = rmp_serde::to_vec(table) {
+            self.insert(path, Bytes::from(bytes)).await;
+        }
+    }
+
+    async fn insert(&self, path: PathBuf, bytes: Bytes) {
+        let tmp = self.entry_path(TMP_PREFIX, &format!("{:016x}", rand::random::<u64>()));
+        let size = bytes.len() as u64;
+        if !write_entry(tmp, path, bytes).await {
+            return;
+        }
+        let total = self.size_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        if total > self.config.max_bytes {
+            self.evict().await;
+        }
+    }
+
+    /// Delete the least recently used entries until the cache is under 90% of its capacity
+    async fn evict(&self) {
+        if self.evicting.swap(true, Ordering::AcqRel) {
+            // another task is already evicting
+            return;
+        }
+        let directory = self.config.directory.clone();
+        let target = self.config.max_bytes / 10 * 9;
+        let size = tokio::task::spawn_blocking(move || {
+            let mut entries = scan_entries(&directory);
+            entries.sort_by_key(|entry| entry.modified);
+            let mut size: u64 = entries.iter().map(|e| e.size).sum();
+            for entry in entries {
+                if size <= target {
+                    break;
+                }
+                // the entry could have been evicted by another process
+                if fs::remove_file(&entry.path).is_ok() {
+                    size = size.saturating_sub(entry.size);
+                }
+            }
+            size
+        })
+        .await;
+        if let Ok(size) = size {
+            self.size_bytes.store(size, Ordering::Relaxed);
+        }
+        self.evicting.store(false, Ordering::Release);
+    }
+}
+
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// List the cache entries, deleting abandoned temporary files
+fn scan_entries(directory: &StdPath) -> Vec<CacheEntry> {
+    let now = SystemTime::now();
+    if let Ok(dir) = fs::read_dir(directory.join(TMP_PREFIX)) {
+        for entry in dir.flatten() {
+            let modified = entry.metadata().and_then(|m| m.modified()).unwrap_or(now);
+            if now.duration_since(modified).unwrap_or_default() > TMP_MAX_AGE {
+                let _ignored = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    ENTRY_PREFIXES
+        .iter()
+        .filter_map(|prefix| fs::read_dir(directory.join(prefix)).ok())
+        .flat_map(|dir| dir.flatten())
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            Some(CacheEntry {
+                path: entry.path(),
+                size: meta.len(),
+                modified: meta.modified().unwrap_or(now),
+            })
+        })
+        .collect()
+}
+
+async fn read_entry(path: PathBuf) -> Option<Bytes> {
+    tokio::task::spawn_blocking(move || {
+        let bytes = fs::read(&path).ok()?;
+        // mark the entry as recently used
+        if let Ok(file) = fs::File::options().append(true).open(&path) {
+            let _ignored = file.set_modified(SystemTime::now());
+        }
+        Some(Bytes::from(bytes))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+async fn write_entry(tmp: PathBuf, path: PathBuf, bytes: Bytes) -> bool {
+    tokio::task::spawn_blocking(move || {
+        let res = fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, &path));
+        if res.is_err() {
+            let _ignored = fs::remove_file(&tmp);
+        }
+        res.is_ok()
+    })
+    .await
+    .unwrap_or(false)
+}
+
+async fn remove_entry(path: PathBuf) {
+    let _ignored = tokio::task::spawn_blocking(move || fs::remove_file(path)).await;
+}
+
+fn chunk_entry_name(id: &ChunkId, range: &ByteRange) -> String {
+    match range {
+        ByteRange::Bounded(range) => format!("{id}.{}-{}", range.start, range.end),
+        ByteRange::From(offset) => format!("{id}.{offset}-"),
+        ByteRange::Last(n) => format!("{id}.-{n}"),
+    }
+}
+
+impl private::Sealed for DiskCachingStorage {}
+
+#[async_trait]
+impl Storage for DiskCachingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        let path = self.entry_path(SNAPSHOT_PREFIX, &id.to_string());
+        self.fetch_table(path, || self.backend.fetch_snapshot(id)).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        let path = self.entry_path(ATTRIBUTES_PREFIX, &id.to_string());
+        self.fetch_table(path, || self.backend.fetch_attributes(id)).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        let path = self.entry_path(MANIFEST_PREFIX, &id.to_string());
+        self.fetch_table(path, || self.backend.fetch_manifests(id)).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        let path = self.entry_path(CHUNK_PREFIX, &chunk_entry_name(id, range));
+        if let Some(bytes) = read_entry(path.clone()).await {
+            return Ok(bytes);
+        }
+        let bytes = self.backend.fetch_chunk(id, range).await?;
+        self.insert(path, bytes.clone()).await;
+        Ok(bytes)
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        let path = self.entry_path(SNAPSHOT_PREFIX, &id.to_string());
+        self.backend.write_snapshot(id, Arc::clone(&snapshot)).await?;
+        self.insert_table(path, snapshot.as_ref()).await;
+        Ok(())
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        let path = self.entry_path(ATTRIBUTES_PREFIX, &id.to_string());
+        self.backend.write_attributes(id, Arc::clone(&table)).await?;
+        self.insert_table(path, table.as_ref()).await;
+        Ok(())
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        let path = self.entry_path(MANIFEST_PREFIX, &id.to_string());
+        self.backend.write_manifests(id, Arc::clone(&manifest)).await?;
+        self.insert_table(path, manifest.as_ref()).await;
+        Ok(())
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        // like in MemCachingStorage, written chunks are not cached
+        self.backend.write_chunk(id, bytes).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.backend.get_ref(ref_key).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.backend.ref_names().await
+    }
+
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.backend.ref_versions(ref_name).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    // garbage collected objects are left in the cache, they are not referenced anymore and
+    // will eventually be evicted
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    use crate::{
+        format::{manifest::ChunkInfo, ChunkIndices, NodeId},
+        repository::ChunkPayload,
+        storage::logging::LoggingStorage,
+        ObjectStorage,
+    };
+
+    use super::*;
+
+    fn cache_size(directory: &StdPath) -> u64 {
+        scan_entries(directory).iter().map(|e| e.size).sum()
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_is_shared() -> Result<(), Box<dyn Error>> {
+        let dir = tempdir()?;
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let manifest: Arc<Manifest> = Arc::new(
+            vec![ChunkInfo {
+                node: NodeId::random(),
+                coord: ChunkIndices(vec![0]),
+                payload: ChunkPayload::Inline(Bytes::from_static(b"a")),
+            }]
+            .into_iter()
+            .collect(),
+        );
+        let manifest_id = ManifestId::random();
+        backend.write_manifests(manifest_id.clone(), Arc::clone(&manifest)).await?;
+        let chunk_id = ChunkId::random();
+        backend.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello")).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let config = DiskCacheConfig { directory: dir.path().into(), max_bytes: 1 << 20 };
+        let cache = DiskCachingStorage::new(logging.clone(), config.clone())?;
+        let range = ByteRange::from_offset_with_length(1, 3);
+        for _ in 0..2 {
+            assert_eq!(cache.fetch_manifests(&manifest_id).await?, manifest);
+            assert_eq!(
+                cache.fetch_chunk(&chunk_id, &range).await?,
+                Bytes::from_static(b"ell")
+            );
+        }
+        assert_eq!(logging.fetch_operations().len(), 2);
+
+        // another instance, like in a different process, uses the same files
+        let other_logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let other = DiskCachingStorage::new(other_logging.clone(), config)?;
+        assert_eq!(other.fetch_manifests(&manifest_id).await?, manifest);
+        assert_eq!(
+            other.fetch_chunk(&chunk_id, &range).await?,
+            Bytes::from_static(b"ell")
+        );
+        assert_eq!(
+            other.fetch_chunk(&chunk_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello")
+        );
+        assert_eq!(other_logging.fetch_operations().len(), 1);
+
+        // corrupted entries are fetched again
+        fs::write(
+            dir.path().join(MANIFEST_PREFIX).join(manifest_id.to_string()),
+            b"bad",
+        )?;
+        assert_eq!(other.fetch_manifests(&manifest_id).await?, manifest);
+        assert_eq!(other_logging.fetch_operations().len(), 2);
+        assert_eq!(other.fetch_manifests(&manifest_id).await?, manifest);
+        assert_eq!(other_logging.fetch_operations().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_evicts_least_recently_used() -> Result<(), Box<dyn Error>> {
+        let dir = tempdir()?;
+        let backend: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let ids: Vec<ChunkId> = (0..6).map(|_| ChunkId::random()).collect();
+        for id in ids.iter() {
+            backend.write_chunk(id.clone(), Bytes::from(vec![0; 100])).await?;
+        }
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let config = DiskCacheConfig { directory: dir.path().into(), max_bytes: 500 };
+        let cache = DiskCachingStorage::new(logging.clone(), config)?;
+        for id in ids[0..4].iter() {
+            cache.fetch_chunk(id, &ByteRange::ALL).await?;
+            // modification times need to be different for the eviction order
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // use the first one again, so it's the most recently used
+        cache.fetch_chunk(&ids[0], &ByteRange::ALL).await?;
+        assert_eq!(logging.fetch_operations().len(), 4);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        for id in ids[4..].iter() {
+            cache.fetch_chunk(id, &ByteRange::ALL).await?;
+            assert!(cache_size(dir.path()) <= 500);
+        }
+        assert_eq!(logging.fetch_operations().len(), 6);
+        cache.fetch_chunk(&ids[0], &ByteRange::ALL).await?;
+        cache.fetch_chunk(&ids[5], &ByteRange::ALL).await?;
+        assert_eq!(logging.fetch_operations().len(), 6);
+        // the two least recently used were evicted
+        cache.fetch_chunk(&ids[1], &ByteRange::ALL).await?;
+        assert_eq!(logging.fetch_operations().len(), 7);
+        Ok(())
+    }
+}
@@ -1,10 +1,13 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::BoxStream;
 
-use super::{Storage, StorageError, StorageResult};
+use super::{ListInfo, Storage, StorageError, StorageResult};
 use crate::{
     format::{
         attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
@@ -17,12 +20,22 @@ use crate::{
 pub struct LoggingStorage {
     backend: Arc<dyn Storage + Send + Sync>,
     fetch_log: Mutex<Vec<(String, Vec<u8>)>>,
+    fail_chunk_writes: AtomicBool,
 }
 
 #[cfg(test)]
 impl LoggingStorage {
     pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
-        Self { backend, fetch_log: Mutex::new(Vec::new()) }
+        Self {
+            backend,
+            fetch_log: Mutex::new(Vec::new()),
+            fail_chunk_writes: AtomicBool::new(false),
+        }
+    }
+
+    /// Make `write_chunk` fail until this is called again with `false`
+    pub fn set_fail_chunk_writes(&self, fail: bool) {
+        self.fail_chunk_writes.store(fail, Ordering::SeqCst);
     }
 
     #[allow(clippy::expect_used)] // this implementation is intended for tests only
@@ -106,6 +119,9 @@ impl Storage for LoggingStorage {
     }
 
     async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
+        if self.fail_chunk_writes.load(Ordering::SeqCst) {
+            return Err(StorageError::Other("chunk writes are failing".to_string()));
+        }
         self.backend.write_chunk(id, bytes).await
     }
 
@@ -132,4 +148,19 @@ impl Storage for LoggingStorage {
     ) -> StorageResult<BoxStream<StorageResult<String>>> {
         self.backend.ref_versions(ref_name).await
     }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.backend.list_objects(prefix).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        self.backend.delete_objects(prefix, ids).await
+    }
 }
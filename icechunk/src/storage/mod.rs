@@ -1,14 +1,19 @@
 use aws_sdk_s3::{
     config::http::HttpResponse,
-    error::SdkError,
+    error::{ProvideErrorMetadata, SdkError},
     operation::{
-        get_object::GetObjectError, list_objects_v2::ListObjectsV2Error,
-        put_object::PutObjectError,
+        complete_multipart_upload::CompleteMultipartUploadError,
+        create_multipart_upload::CreateMultipartUploadError,
+        delete_objects::DeleteObjectsError, get_object::GetObjectError,
+        list_objects_v2::ListObjectsV2Error, put_object::PutObjectError,
+        upload_part::UploadPartError,
     },
     primitives::ByteStreamError,
 };
+use chrono::{DateTime, Utc};
 use core::fmt;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use std::{ffi::OsString, sync::Arc};
 
 use async_trait::async_trait;
@@ -16,21 +21,25 @@ use bytes::Bytes;
 use thiserror::Error;
 
 pub mod caching;
+pub mod disk_caching;
 
 #[cfg(test)]
 pub mod logging;
 
 pub mod object_store;
+pub mod retry;
 pub mod s3;
 pub mod virtual_ref;
 
-pub use caching::MemCachingStorage;
+pub use caching::{CacheStats, CachingConfig, MemCachingStorage};
+pub use disk_caching::{DiskCacheConfig, DiskCachingStorage};
 pub use object_store::ObjectStorage;
+pub use retry::{RetryConfig, RetryingStorage};
 
 use crate::{
     format::{
         attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+        AttributesId, ByteRange, ChunkId, FileTypeTag, ManifestId, ObjectId, SnapshotId,
     },
     private,
 };
@@ -47,6 +56,18 @@ pub enum StorageError {
     S3PutObjectError(#[from] SdkError<PutObjectError, HttpResponse>),
     #[error("error listing objects in object store {0}")]
     S3ListObjectError(#[from] SdkError<ListObjectsV2Error, HttpResponse>),
+    #[error("error deleting objects in object store {0}")]
+    S3DeleteObjectError(#[from] SdkError<DeleteObjectsError, HttpResponse>),
+    #[error("error starting multipart upload {0}")]
+    S3CreateMultipartUploadError(
+        #[from] SdkError<CreateMultipartUploadError, HttpResponse>,
+    ),
+    #[error("error uploading object part {0}")]
+    S3UploadPartError(#[from] SdkError<UploadPartError, HttpResponse>),
+    #[error("error completing multipart upload {0}")]
+    S3CompleteMultipartUploadError(
+        #[from] SdkError<CompleteMultipartUploadError, HttpResponse>,
+    ),
     #[error("error streaming bytes from object store {0}")]
     S3StreamError(#[from] ByteStreamError),
     #[error("messagepack decode error: {0}")]
@@ -61,8 +82,138 @@ pub enum StorageError {
     Other(String),
 }
 
+impl StorageError {
+    /// Errors that could go away by repeating the request
+    ///
+    /// These are timeouts, connection failures, throttling and server errors.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StorageError::ObjectStore(err) => is_retryable_object_store_error(err),
+            StorageError::S3GetObjectError(err) => is_retryable_sdk_error(err),
+            StorageError::S3PutObjectError(err) => is_retryable_sdk_error(err),
+            StorageError::S3ListObjectError(err) => is_retryable_sdk_error(err),
+            StorageError::S3DeleteObjectError(err) => is_retryable_sdk_error(err),
+            StorageError::S3CreateMultipartUploadError(err) => {
+                is_retryable_sdk_error(err)
+            }
+            StorageError::S3UploadPartError(err) => is_retryable_sdk_error(err),
+            StorageError::S3CompleteMultipartUploadError(err) => {
+                is_retryable_sdk_error(err)
+            }
+            StorageError::S3StreamError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_retryable_sdk_error<E: ProvideErrorMetadata>(
+    err: &SdkError<E, HttpResponse>,
+) -> bool {
+    match err {
+        SdkError::TimeoutError(_)
+        | SdkError::DispatchFailure(_)
+        | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(err) => {
+            let status = err.raw().status().as_u16();
+            status >= 500
+                || status == 429
+                || matches!(
+                    err.err().code(),
+                    Some(
+                        "SlowDown"
+                            | "Throttling"
+                            | "ThrottlingException"
+                            | "RequestTimeout"
+                    )
+                )
+        }
+        _ => false,
+    }
+}
+
+/// `object_store` reports failed HTTP requests as generic errors, the response status or
+/// the connection failure is found in the chain of sources
+///
+/// Responses with a client error status, including 429, are reported with a private error
+/// type that doesn't expose the status, so they are never retried.
+fn is_retryable_object_store_error(err: &::object_store::Error) -> bool {
+    let ::object_store::Error::Generic { source, .. } = err else {
+        return false;
+    };
+    let mut next: Option<&(dyn std::error::Error + 'static)> = Some(source.as_ref());
+    while let Some(err) = next {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            return match err.status() {
+                Some(status) => status.is_server_error() || status.as_u16() == 429,
+                None => {
+                    err.is_timeout()
+                        || err.is_connect()
+                        || err.is_request()
+                        || err.is_body()
+                }
+            };
+        }
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return matches!(
+                err.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        next = err.source();
+    }
+    false
+}
+
 pub type StorageResult<A> = Result<A, StorageError>;
 
+pub const SNAPSHOT_PREFIX: &str = "snapshots/";
+pub const MANIFEST_PREFIX: &str = "manifests/";
+pub const ATTRIBUTES_PREFIX: &str = "attributes/";
+pub const CHUNK_PREFIX: &str = "chunks/";
+pub const REF_PREFIX: &str = "refs";
+
+/// How large objects are uploaded
+///
+/// Objects larger than `threshold_bytes` are uploaded in parts of `part_size_bytes`, with up
+/// to `max_concurrent_parts` parts in flight. If any part fails the upload is aborted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultipartConfig {
+    pub threshold_bytes: u64,
+    pub part_size_bytes: u64,
+    pub max_concurrent_parts: usize,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            threshold_bytes: 100 * 1024 * 1024,
+            part_size_bytes: 16 * 1024 * 1024,
+            max_concurrent_parts: 8,
+        }
+    }
+}
+
+impl MultipartConfig {
+    pub(crate) fn use_multipart(&self, size: usize) -> bool {
+        size as u64 > self.threshold_bytes
+    }
+
+    pub(crate) fn part_size(&self) -> usize {
+        usize::try_from(self.part_size_bytes).unwrap_or(usize::MAX).max(1)
+    }
+}
+
+/// An object found while listing one of the storage prefixes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListInfo<Id> {
+    pub id: Id,
+    pub created_at: DateTime<Utc>,
+    pub size_bytes: u64,
+}
+
 /// Fetch and write the parquet files that represent the repository in object store
 ///
 /// Different implementation can cache the files differently, or not at all.
@@ -106,4 +257,78 @@ pub trait Storage: fmt::Debug + private::Sealed {
         overwrite_refs: bool,
         bytes: Bytes,
     ) -> StorageResult<()>;
+
+    /// List the objects under one of the storage prefixes, for example [`CHUNK_PREFIX`]
+    ///
+    /// Returned ids are relative to the prefix.
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>>;
+
+    /// Delete the objects with the given ids under one of the storage prefixes
+    ///
+    /// Returns the number of objects deleted.
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize>;
+
+    async fn list_snapshots(
+        &self,
+    ) -> StorageResult<BoxStream<'_, StorageResult<ListInfo<SnapshotId>>>> {
+        Ok(translate_list_infos(self.list_objects(SNAPSHOT_PREFIX).await?))
+    }
+
+    async fn list_manifests(
+        &self,
+    ) -> StorageResult<BoxStream<'_, StorageResult<ListInfo<ManifestId>>>> {
+        Ok(translate_list_infos(self.list_objects(MANIFEST_PREFIX).await?))
+    }
+
+    async fn list_attributes(
+        &self,
+    ) -> StorageResult<BoxStream<'_, StorageResult<ListInfo<AttributesId>>>> {
+        Ok(translate_list_infos(self.list_objects(ATTRIBUTES_PREFIX).await?))
+    }
+
+    async fn list_chunks(
+        &self,
+    ) -> StorageResult<BoxStream<'_, StorageResult<ListInfo<ChunkId>>>> {
+        Ok(translate_list_infos(self.list_objects(CHUNK_PREFIX).await?))
+    }
+
+    async fn delete_snapshots(&self, ids: Vec<SnapshotId>) -> StorageResult<usize> {
+        self.delete_objects(SNAPSHOT_PREFIX, ids.iter().map(String::from).collect()).await
+    }
+
+    async fn delete_manifests(&self, ids: Vec<ManifestId>) -> StorageResult<usize> {
+        self.delete_objects(MANIFEST_PREFIX, ids.iter().map(String::from).collect()).await
+    }
+
+    async fn delete_attributes(&self, ids: Vec<AttributesId>) -> StorageResult<usize> {
+        self.delete_objects(ATTRIBUTES_PREFIX, ids.iter().map(String::from).collect())
+            .await
+    }
+
+    async fn delete_chunks(&self, ids: Vec<ChunkId>) -> StorageResult<usize> {
+        self.delete_objects(CHUNK_PREFIX, ids.iter().map(String::from).collect()).await
+    }
+
+    /// Hits and misses of the in memory caches, if this is a [`MemCachingStorage`]
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+fn translate_list_infos<'a, const SIZE: usize, T: FileTypeTag + Send + 'a>(
+    s: BoxStream<'a, StorageResult<ListInfo<String>>>,
+) -> BoxStream<'a, StorageResult<ListInfo<ObjectId<SIZE, T>>>> {
+    s.and_then(|info| async move {
+        let id = ObjectId::try_from(info.id.as_str())
+            .map_err(|e| StorageError::Other(format!("{e}: {}", info.id)))?;
+        Ok(ListInfo { id, created_at: info.created_at, size_bytes: info.size_bytes })
+    })
+    .boxed()
 }
@@ -10,15 +10,56 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use object_store::{
-    local::LocalFileSystem, memory::InMemory, path::Path as ObjectPath, Attribute,
-    AttributeValue, Attributes, GetOptions, GetRange, ObjectStore, PutMode, PutOptions,
-    PutPayload,
+    aws::{AmazonS3Builder, AmazonS3ConfigKey},
+    azure::{AzureConfigKey, MicrosoftAzureBuilder},
+    gcp::GoogleCloudStorageBuilder,
+    http::HttpBuilder,
+    local::LocalFileSystem,
+    memory::InMemory,
+    path::Path as ObjectPath,
+    Attribute, AttributeValue, Attributes, ClientOptions, GetOptions, GetRange,
+    ObjectStore, ObjectStoreScheme, PutMode, PutMultipartOpts, PutOptions, PutPayload,
+    RetryConfig, WriteMultipart,
 };
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::create_dir_all, future::ready, ops::Range, path::Path as StdPath, sync::Arc,
+    collections::HashMap,
+    fs::create_dir_all,
+    future::ready,
+    ops::Range,
+    path::{Path as StdPath, PathBuf},
+    sync::Arc,
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{
+    ListInfo, MultipartConfig, Storage, StorageError, StorageResult, ATTRIBUTES_PREFIX,
+    CHUNK_PREFIX, MANIFEST_PREFIX, REF_PREFIX, SNAPSHOT_PREFIX,
+};
+
+/// `object_store` retries failed requests on its own, we disable it for the network backends
+/// so requests are only retried by [`RetryingStorage`](super::RetryingStorage)
+fn no_internal_retries() -> RetryConfig {
+    RetryConfig { max_retries: 0, ..RetryConfig::default() }
+}
+
+/// Set the `options` on a builder, failing on the first key it doesn't understand
+fn with_options<B, K: std::str::FromStr>(
+    builder: B,
+    options: &HashMap<String, String>,
+    scheme: &ObjectStoreScheme,
+    with_config: impl Fn(B, K, &str) -> B,
+) -> Result<B, StorageError> {
+    options.iter().try_fold(builder, |builder, (key, value)| {
+        match key.to_ascii_lowercase().parse() {
+            Ok(parsed) => Ok(with_config(builder, parsed, value)),
+            Err(_) => Err(unknown_option(key, scheme)),
+        }
+    })
+}
+
+fn unknown_option(key: &str, scheme: &ObjectStoreScheme) -> StorageError {
+    StorageError::Other(format!("unknown option {key:?} for {scheme:?} storage"))
+}
 
 // Get Range is object_store specific, keep it with this module
 impl From<&ByteRange> for Option<GetRange> {
@@ -34,11 +75,60 @@ impl From<&ByteRange> for Option<GetRange> {
     }
 }
 
-const SNAPSHOT_PREFIX: &str = "snapshots/";
-const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
-const CHUNK_PREFIX: &str = "chunks/";
-const REF_PREFIX: &str = "refs";
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(tag = "type")]
+pub enum GcsCredentials {
+    /// Use the service account or application credentials set in the environment
+    #[default]
+    #[serde(rename = "from_env")]
+    FromEnv,
+    /// Send requests without authentication, for public buckets and emulators
+    #[serde(rename = "anonymous")]
+    Anonymous,
+    /// Path to a service account json file
+    #[serde(rename = "service_account")]
+    ServiceAccount { path: PathBuf },
+    /// Contents of a service account json file
+    #[serde(rename = "service_account_key")]
+    ServiceAccountKey { key: String },
+    /// Path to an application default credentials json file
+    #[serde(rename = "application_credentials")]
+    ApplicationCredentials { path: PathBuf },
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GcsConfig {
+    /// Base url of the GCS api, only supported with anonymous credentials. Used to point to
+    /// an emulator like `fake-gcs-server`.
+    pub endpoint: Option<String>,
+    pub credentials: GcsCredentials,
+    pub allow_http: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(tag = "type")]
+pub enum AzureCredentials {
+    /// Use the credentials set in the environment, like `AZURE_STORAGE_ACCOUNT_KEY`
+    #[default]
+    #[serde(rename = "from_env")]
+    FromEnv,
+    #[serde(rename = "account_key")]
+    AccountKey { key: String },
+    /// A shared access signature, in query string format
+    #[serde(rename = "sas_token")]
+    SasToken { token: String },
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AzureConfig {
+    pub endpoint: Option<String>,
+    pub credentials: AzureCredentials,
+    pub allow_http: bool,
+    /// Connect to a local Azurite emulator, using its development key if no other
+    /// credentials are given. The emulator url is read from `AZURITE_BLOB_STORAGE_URL`, and
+    /// defaults to `http://127.0.0.1:10000`.
+    pub use_emulator: bool,
+}
 
 #[derive(Debug)]
 pub struct ObjectStorage {
@@ -50,6 +140,7 @@ pub struct ObjectStorage {
 
     supports_create_if_not_exists: bool,
     supports_metadata: bool,
+    multipart: MultipartConfig,
 }
 
 impl ObjectStorage {
@@ -66,6 +157,7 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: false,
             supports_create_if_not_exists: true,
             supports_metadata: true,
+            multipart: MultipartConfig::default(),
         }
     }
 
@@ -82,9 +174,204 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: true,
             supports_create_if_not_exists: true,
             supports_metadata: false,
+            multipart: MultipartConfig::default(),
+        })
+    }
+
+    /// Create a Google Cloud Storage implementation
+    ///
+    /// Refs are written with a generation-match precondition, so concurrent writers can't
+    /// overwrite each other's refs.
+    pub fn new_gcs_store(
+        bucket: impl Into<String>,
+        prefix: impl Into<String>,
+        config: Option<&GcsConfig>,
+    ) -> Result<ObjectStorage, StorageError> {
+        let default_config = GcsConfig::default();
+        let config = config.unwrap_or(&default_config);
+        if config.endpoint.is_some() && config.credentials != GcsCredentials::Anonymous {
+            return Err(StorageError::Other(
+                "custom GCS endpoints are only supported with anonymous credentials"
+                    .to_string(),
+            ));
+        }
+        let mut builder = match &config.credentials {
+            GcsCredentials::FromEnv => GoogleCloudStorageBuilder::from_env(),
+            GcsCredentials::Anonymous => GoogleCloudStorageBuilder::new()
+                .with_service_account_key(
+                    serde_json::json!({
+                        "private_key": "",
+                        "private_key_id": "",
+                        "client_email": "",
+                        "gcs_base_url": config.endpoint,
+                        "disable_oauth": true,
+                    })
+                    .to_string(),
+                ),
+            GcsCredentials::ServiceAccount { path } => GoogleCloudStorageBuilder::new()
+                .with_service_account_path(path.display().to_string()),
+            GcsCredentials::ServiceAccountKey { key } => {
+                GoogleCloudStorageBuilder::new().with_service_account_key(key)
+            }
+            GcsCredentials::ApplicationCredentials { path } => {
+                GoogleCloudStorageBuilder::new()
+                    .with_application_credentials(path.display().to_string())
+            }
+        };
+        builder = builder
+            .with_bucket_name(bucket)
+            .with_client_options(ClientOptions::new().with_allow_http(config.allow_http))
+            .with_retry(no_internal_retries());
+        Ok(ObjectStorage {
+            store: Arc::new(builder.build()?),
+            prefix: prefix.into(),
+            artificially_sort_refs_in_mem: false,
+            supports_create_if_not_exists: true,
+            supports_metadata: true,
+            multipart: MultipartConfig::default(),
+        })
+    }
+
+    /// Create an Azure Blob Storage implementation
+    ///
+    /// Refs are written with an `If-None-Match: *` condition, so concurrent writers can't
+    /// overwrite each other's refs.
+    pub fn new_azure_blob_store(
+        account: impl Into<String>,
+        container: impl Into<String>,
+        prefix: impl Into<String>,
+        config: Option<&AzureConfig>,
+    ) -> Result<ObjectStorage, StorageError> {
+        let default_config = AzureConfig::default();
+        let config = config.unwrap_or(&default_config);
+        let mut builder = match &config.credentials {
+            AzureCredentials::FromEnv => MicrosoftAzureBuilder::from_env(),
+            AzureCredentials::AccountKey { key } => {
+                MicrosoftAzureBuilder::new().with_access_key(key)
+            }
+            AzureCredentials::SasToken { token } => {
+                MicrosoftAzureBuilder::new().with_config(AzureConfigKey::SasKey, token)
+            }
+        };
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint.clone());
+        }
+        builder = builder
+            .with_account(account)
+            .with_container_name(container)
+            .with_allow_http(config.allow_http)
+            .with_use_emulator(config.use_emulator)
+            .with_retry(no_internal_retries());
+        Ok(ObjectStorage {
+            store: Arc::new(builder.build()?),
+            prefix: prefix.into(),
+            artificially_sort_refs_in_mem: false,
+            supports_create_if_not_exists: true,
+            supports_metadata: true,
+            multipart: MultipartConfig::default(),
+        })
+    }
+
+    /// Create a Storage implementation for any `object_store` backend from its url
+    ///
+    /// Supported schemes are `s3://`, `gs://`, `az://` (and the other Azure and Amazon url
+    /// formats), `http(s)://`, `file://` and `memory://`. `options` are passed to the
+    /// backend builder, for example `aws_region` or `google_service_account`. Refs on S3 are
+    /// only protected against concurrent writers if the `aws_conditional_put` option is set,
+    /// and HTTP stores can't protect them at all.
+    pub fn new_from_url(
+        url: &str,
+        options: &HashMap<String, String>,
+    ) -> Result<ObjectStorage, StorageError> {
+        let url = url::Url::parse(url)
+            .map_err(|e| StorageError::Other(format!("invalid url {url}: {e}")))?;
+        let (scheme, path) =
+            ObjectStoreScheme::parse(&url).map_err(object_store::Error::from)?;
+        let store: Arc<dyn ObjectStore> = match scheme {
+            ObjectStoreScheme::Local | ObjectStoreScheme::Memory => {
+                // these backends have no options
+                if let Some(key) = options.keys().next() {
+                    return Err(unknown_option(key, &scheme));
+                }
+                if scheme == ObjectStoreScheme::Local {
+                    Arc::new(LocalFileSystem::new())
+                } else {
+                    Arc::new(InMemory::new())
+                }
+            }
+            ObjectStoreScheme::AmazonS3 => Arc::new(
+                with_options(
+                    AmazonS3Builder::new().with_url(url.as_str()),
+                    options,
+                    &scheme,
+                    |b, k, v| b.with_config(k, v),
+                )?
+                .with_retry(no_internal_retries())
+                .build()?,
+            ),
+            ObjectStoreScheme::GoogleCloudStorage => Arc::new(
+                with_options(
+                    GoogleCloudStorageBuilder::new().with_url(url.as_str()),
+                    options,
+                    &scheme,
+                    |b, k, v| b.with_config(k, v),
+                )?
+                .with_retry(no_internal_retries())
+                .build()?,
+            ),
+            ObjectStoreScheme::MicrosoftAzure => Arc::new(
+                with_options(
+                    MicrosoftAzureBuilder::new().with_url(url.as_str()),
+                    options,
+                    &scheme,
+                    |b, k, v| b.with_config(k, v),
+                )?
+                .with_retry(no_internal_retries())
+                .build()?,
+            ),
+            ObjectStoreScheme::Http => Arc::new(
+                with_options(
+                    HttpBuilder::new().with_url(&url[..url::Position::BeforePath]),
+                    options,
+                    &scheme,
+                    |b, k, v| b.with_config(k, v),
+                )?
+                .with_retry(no_internal_retries())
+                .build()?,
+            ),
+            scheme => {
+                return Err(StorageError::Other(format!(
+                    "unsupported url scheme {scheme:?}"
+                )))
+            }
+        };
+        let (supports_create_if_not_exists, supports_metadata) = match scheme {
+            ObjectStoreScheme::AmazonS3 => (
+                options.keys().any(|key| {
+                    key.to_ascii_lowercase().parse::<AmazonS3ConfigKey>().ok()
+                        == Some(AmazonS3ConfigKey::ConditionalPut)
+                }),
+                true,
+            ),
+            ObjectStoreScheme::Local => (true, false),
+            ObjectStoreScheme::Http => (false, false),
+            _ => (true, true),
+        };
+        Ok(ObjectStorage {
+            store,
+            prefix: path.to_string(),
+            artificially_sort_refs_in_mem: scheme == ObjectStoreScheme::Local,
+            supports_create_if_not_exists,
+            supports_metadata,
+            multipart: MultipartConfig::default(),
         })
     }
 
+    pub fn with_multipart_config(mut self, config: MultipartConfig) -> Self {
+        self.multipart = config;
+        self
+    }
+
     /// Return all keys in the store
     ///
     /// Intended for testing and debugging purposes only.
@@ -112,6 +399,10 @@ impl ObjectStorage {
         self.get_path(SNAPSHOT_PREFIX, id)
     }
 
+    fn get_attributes_path(&self, id: &AttributesId) -> ObjectPath {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_manifest_path(&self, id: &ManifestId) -> ObjectPath {
         self.get_path(MANIFEST_PREFIX, id)
     }
@@ -120,6 +411,10 @@ impl ObjectStorage {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    fn get_prefix_path(&self, file_prefix: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}/{}", self.prefix, file_prefix))
+    }
+
     fn drop_prefix(&self, prefix: &ObjectPath, path: &ObjectPath) -> Option<ObjectPath> {
         path.prefix_match(&ObjectPath::from(format!("{}", prefix))).map(|it| it.collect())
     }
@@ -129,6 +424,46 @@ impl ObjectStorage {
         ObjectPath::from(format!("{}/{}/{}", self.prefix.as_str(), REF_PREFIX, ref_key))
     }
 
+    /// Write an object, using a multipart upload if it's larger than the configured
+    /// threshold
+    async fn put_object(
+        &self,
+        path: &ObjectPath,
+        bytes: Bytes,
+        attributes: Attributes,
+    ) -> StorageResult<()> {
+        if !self.multipart.use_multipart(bytes.len()) {
+            let options = PutOptions { attributes, ..PutOptions::default() };
+            self.store.put_opts(path, bytes.into(), options).await?;
+            return Ok(());
+        }
+
+        let options = PutMultipartOpts { attributes, ..PutMultipartOpts::default() };
+        let upload = self.store.put_multipart_opts(path, options).await?;
+        let part_size = self.multipart.part_size();
+        let mut write = WriteMultipart::new_with_chunk_size(upload, part_size);
+        let max_concurrent_parts = self.multipart.max_concurrent_parts.max(1);
+        let mut remaining = bytes;
+        let res = async {
+            while !remaining.is_empty() {
+                write.wait_for_capacity(max_concurrent_parts).await?;
+                write.put(remaining.split_to(part_size.min(remaining.len())));
+            }
+            write.wait_for_capacity(0).await
+        }
+        .await;
+        match res {
+            Ok(()) => {
+                write.finish().await?;
+                Ok(())
+            }
+            Err(err) => {
+                let _ignored = write.abort().await;
+                Err(err.into())
+            }
+        }
+    }
+
     async fn do_ref_versions(&self, ref_name: &str) -> BoxStream<StorageResult<String>> {
         let prefix = self.ref_key(ref_name);
         self.store
@@ -164,9 +499,13 @@ impl Storage for ObjectStorage {
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> Result<Arc<AttributesTable>, StorageError> {
-        todo!();
+        let path = self.get_attributes_path(id);
+        let bytes = self.store.get(&path).await?.bytes().await?;
+        // TODO: optimize using from_read
+        let res = rmp_serde::from_slice(bytes.as_ref())?;
+        Ok(Arc::new(res))
     }
 
     async fn fetch_manifests(
@@ -207,18 +546,37 @@ impl Storage for ObjectStorage {
         } else {
             Attributes::new()
         };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+        self.put_object(&path, bytes.into(), attributes).await
     }
 
     async fn write_attributes(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
     ) -> Result<(), StorageError> {
-        todo!()
+        let path = self.get_attributes_path(&id);
+        let bytes = rmp_serde::to_vec(table.as_ref())?;
+        let attributes = if self.supports_metadata {
+            Attributes::from_iter(vec![
+                (
+                    Attribute::ContentType,
+                    AttributeValue::from(
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE,
+                    ),
+                ),
+                (
+                    Attribute::Metadata(std::borrow::Cow::Borrowed(
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
+                    )),
+                    AttributeValue::from(
+                        table.icechunk_attributes_format_version.to_string(),
+                    ),
+                ),
+            ])
+        } else {
+            Attributes::new()
+        };
+        self.put_object(&path, bytes.into(), attributes).await
     }
 
     async fn write_manifests(
@@ -248,10 +606,7 @@ impl Storage for ObjectStorage {
         } else {
             Attributes::new()
         };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+        self.put_object(&path, bytes.into(), attributes).await
     }
 
     async fn fetch_chunk(
@@ -274,12 +629,7 @@ impl Storage for ObjectStorage {
         bytes: bytes::Bytes,
     ) -> Result<(), StorageError> {
         let path = self.get_chunk_path(&id);
-        let upload = self.store.put_multipart(&path).await?;
-        // TODO: new_with_chunk_size?
-        let mut write = object_store::WriteMultipart::new(upload);
-        write.write(&bytes);
-        write.finish().await?;
-        Ok(())
+        self.put_object(&path, bytes, Attributes::new()).await
     }
 
     async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
@@ -354,4 +704,220 @@ impl Storage for ObjectStorage {
             })
             .map(|_| ())
     }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let prefix = self.get_prefix_path(prefix);
+        let stream =
+            self.store.list(Some(&prefix)).map_err(|e| e.into()).and_then(move |meta| {
+                ready(
+                    self.drop_prefix(&prefix, &meta.location)
+                        .map(|path| ListInfo {
+                            id: path.to_string(),
+                            created_at: meta.last_modified,
+                            size_bytes: meta.size as u64,
+                        })
+                        .ok_or(StorageError::Other(
+                            "Bug in list prefix logic".to_string(),
+                        )),
+                )
+            });
+        Ok(stream.boxed())
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        let prefix = self.get_prefix_path(prefix);
+        let paths = ids
+            .into_iter()
+            .map(move |id| Ok(ObjectPath::from(format!("{}/{}", prefix, id))));
+        let deleted = self
+            .store
+            .delete_stream(futures::stream::iter(paths).boxed())
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(deleted.len())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use tempfile::tempdir;
+
+    use crate::refs::{create_tag, fetch_tag, list_refs, Ref, RefError};
+
+    use super::*;
+
+    fn capabilities(url: &str, options: &[(&str, &str)]) -> (bool, bool, String) {
+        let options =
+            options.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let storage = ObjectStorage::new_from_url(url, &options).unwrap();
+        (storage.supports_create_if_not_exists, storage.supports_metadata, storage.prefix)
+    }
+
+    #[test]
+    fn test_url_capabilities() {
+        assert_eq!(capabilities("memory:///", &[]), (true, true, "".to_string()));
+        assert_eq!(
+            capabilities("file:///tmp/repo", &[]),
+            (true, false, "tmp/repo".to_string())
+        );
+        assert_eq!(
+            capabilities("s3://bucket/repo", &[("aws_region", "us-east-1")]),
+            (false, true, "repo".to_string())
+        );
+        assert_eq!(
+            capabilities("s3://bucket/repo", &[("aws_conditional_put", "etag")]),
+            (true, true, "repo".to_string())
+        );
+        assert_eq!(
+            capabilities(
+                "gs://bucket/repo",
+                &[(
+                    "google_service_account_key",
+                    r#"{"private_key": "", "private_key_id": "", "client_email": "",
+                        "disable_oauth": true}"#
+                )]
+            ),
+            (true, true, "repo".to_string())
+        );
+        assert_eq!(
+            capabilities("az://container/repo", &[("azure_storage_account_name", "a")]),
+            (true, true, "repo".to_string())
+        );
+        assert_eq!(
+            capabilities("https://example.com/repo", &[]),
+            (false, false, "repo".to_string())
+        );
+        assert!(ObjectStorage::new_from_url("not a url", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_url_unknown_options() {
+        let options = |key: &str| [(key.to_string(), "value".to_string())].into();
+        for (url, key) in [
+            ("s3://bucket/repo", "aws_regoin"),
+            ("gs://bucket/repo", "aws_region"),
+            ("az://container/repo", "azure_storage_acount_name"),
+            ("https://example.com/repo", "unknown"),
+            ("memory:///", "aws_region"),
+        ] {
+            match ObjectStorage::new_from_url(url, &options(key)) {
+                Err(StorageError::Other(message)) => {
+                    assert!(message.contains(key), "{message}")
+                }
+                res => panic!("{url} accepted option {key}: {res:?}"),
+            }
+        }
+        // keys are not case sensitive
+        assert!(ObjectStorage::new_from_url("s3://bucket/repo", &options("AWS_REGION"))
+            .is_ok());
+    }
+
+    /// Answer every request with `status`, or refuse connections if it's `None`
+    async fn serve_status(status: Option<&'static str>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let Some(status) = status else {
+            return format!("http://{addr}/repo");
+        };
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let _ = socket.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n");
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{addr}/repo")
+    }
+
+    #[tokio::test]
+    async fn test_retryable_http_errors() -> Result<(), Box<dyn Error>> {
+        for (status, retryable) in [
+            (Some("503 Service Unavailable"), true),
+            (Some("500 Internal Server Error"), true),
+            // object_store hides the status of client errors, throttling is not detected
+            (Some("429 Too Many Requests"), false),
+            (Some("400 Bad Request"), false),
+            (Some("403 Forbidden"), false),
+            (None, true),
+        ] {
+            let url = serve_status(status).await;
+            let options = [("allow_http".to_string(), "true".to_string())].into();
+            let storage = ObjectStorage::new_from_url(&url, &options)?;
+            let err = storage.fetch_chunk(&ChunkId::random(), &ByteRange::ALL).await;
+            assert_eq!(err.unwrap_err().is_retryable(), retryable, "{status:?}");
+        }
+        // other generic errors are not transient
+        let err = StorageError::ObjectStore(object_store::Error::Generic {
+            store: "test",
+            source: "invalid configuration".into(),
+        });
+        assert!(!err.is_retryable());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_url_refs() -> Result<(), Box<dyn Error>> {
+        let dir = tempdir()?;
+        let url = url::Url::from_directory_path(dir.path()).unwrap();
+        let storage = ObjectStorage::new_from_url(url.as_str(), &HashMap::new())?;
+
+        let id = crate::format::SnapshotId::random();
+        create_tag(&storage, "tag", id.clone(), false).await?;
+        let res = create_tag(&storage, "tag", id.clone(), false).await;
+        assert!(matches!(res, Err(RefError::TagAlreadyExists(name)) if name == "tag"));
+        assert_eq!(fetch_tag(&storage, "tag").await?.snapshot, id);
+        assert_eq!(list_refs(&storage).await?, vec![Ref::Tag("tag".to_string())]);
+
+        // objects are written under the url path
+        let chunk_id = ChunkId::random();
+        storage.write_chunk(chunk_id.clone(), Bytes::from_static(b"hello")).await?;
+        assert!(dir.path().join("chunks").join(chunk_id.to_string()).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multipart_write_read() -> Result<(), Box<dyn Error>> {
+        let dir = tempdir()?;
+        let storage = ObjectStorage::new_local_store(dir.path())?.with_multipart_config(
+            MultipartConfig {
+                threshold_bytes: 10,
+                part_size_bytes: 4,
+                max_concurrent_parts: 2,
+            },
+        );
+
+        let id = ChunkId::random();
+        let data: Bytes = (0..30u8).collect::<Vec<_>>().into();
+        storage.write_chunk(id.clone(), data.clone()).await?;
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, data);
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::from_offset_with_length(3, 6)).await?,
+            data.slice(3..9)
+        );
+
+        let small_id = ChunkId::random();
+        storage.write_chunk(small_id.clone(), Bytes::from_static(b"small")).await?;
+        assert_eq!(
+            storage.fetch_chunk(&small_id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"small")
+        );
+
+        let snapshot_id = crate::format::SnapshotId::random();
+        let snapshot = Arc::new(Snapshot::empty());
+        storage.write_snapshot(snapshot_id.clone(), Arc::clone(&snapshot)).await?;
+        assert_eq!(storage.fetch_snapshot(&snapshot_id).await?, snapshot);
+        Ok(())
+    }
 }
@@ -0,0 +1,414 @@
+//! Retries of transient storage errors.
+//!
+//! [`RetryingStorage`] wraps another [`Storage`] and retries every operation that fails with
+//! an error classified as transient by [`StorageError::is_retryable`], waiting an
+//! exponential backoff with full jitter between attempts.
+//!
+//! Conditional ref writes are never repeated blindly: after an error the write may have
+//! succeeded anyway, so the ref is read back first. If it holds the bytes we wrote the write
+//! is considered done, if it holds anything else the write lost the race.
+use std::{future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    private,
+};
+
+use super::{ListInfo, Storage, StorageError, StorageResult};
+
+/// How transient storage errors are retried
+///
+/// Before attempt `n + 1` we wait a random time between zero and
+/// `min(max_backoff_ms, initial_backoff_ms * 2^(n-1))` milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one. Use 1 to disable retries.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self { max_attempts: 5, initial_backoff_ms: 100, max_backoff_ms: 10_000 }
+    }
+}
+
+impl RetryConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff_ms
+            .saturating_mul(
+                1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX),
+            )
+            .min(self.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=exp))
+    }
+}
+
+#[derive(Debug)]
+pub struct RetryingStorage {
+    backend: Arc<dyn Storage + Send + Sync>,
+    config: RetryConfig,
+}
+
+impl RetryingStorage {
+    pub fn new(backend: Arc<dyn Storage + Send + Sync>, config: RetryConfig) -> Self {
+        Self { backend, config }
+    }
+
+    async fn retry<A, F, Fut>(&self, mut op: F) -> StorageResult<A>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = StorageResult<A>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Err(err) if err.is_retryable() && attempt < self.config.max_attempts => {
+                    tokio::time::sleep(self.config.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl private::Sealed for RetryingStorage {}
+
+#[async_trait]
+impl Storage for RetryingStorage {
+    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+        self.retry(|| self.backend.fetch_snapshot(id)).await
+    }
+
+    async fn fetch_attributes(
+        &self,
+        id: &AttributesId,
+    ) -> StorageResult<Arc<AttributesTable>> {
+        self.retry(|| self.backend.fetch_attributes(id)).await
+    }
+
+    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+        self.retry(|| self.backend.fetch_manifests(id)).await
+    }
+
+    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
+        self.retry(|| self.backend.fetch_chunk(id, range)).await
+    }
+
+    async fn write_snapshot(
+        &self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)))
+            .await
+    }
+
+    async fn write_attributes(
+        &self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_attributes(id.clone(), Arc::clone(&table))).await
+    }
+
+    async fn write_manifests(
+        &self,
+        id: ManifestId,
+        manifest: Arc<Manifest>,
+    ) -> StorageResult<()> {
+        self.retry(|| self.backend.write_manifests(id.clone(), Arc::clone(&manifest)))
+            .await
+    }
+
+    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+        self.retry(|| self.backend.write_chunk(id.clone(), bytes.clone())).await
+    }
+
+    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+        self.retry(|| self.backend.get_ref(ref_key)).await
+    }
+
+    async fn ref_names(&self) -> StorageResult<Vec<String>> {
+        self.retry(|| self.backend.ref_names()).await
+    }
+
+    // only the request that starts the listing is retried, not the following pages
+    async fn ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> StorageResult<BoxStream<StorageResult<String>>> {
+        self.retry(|| self.backend.ref_versions(ref_name)).await
+    }
+
+    async fn write_ref(
+        &self,
+        ref_key: &str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        if overwrite_refs {
+            // unconditional writes can be repeated safely
+            return self
+                .retry(|| self.backend.write_ref(ref_key, true, bytes.clone()))
+                .await;
+        }
+
+        let mut attempt = 1;
+        loop {
+            let err = match self.backend.write_ref(ref_key, false, bytes.clone()).await {
+                Err(err) if err.is_retryable() => err,
+                res => return res,
+            };
+            // the outcome of the write is unknown, find out by reading the ref back
+            match self.get_ref(ref_key).await {
+                Ok(current) if current == bytes => return Ok(()),
+                Ok(_) => return Err(StorageError::RefAlreadyExists(ref_key.to_string())),
+                Err(StorageError::RefNotFound(_))
+                    if attempt < self.config.max_attempts =>
+                {
+                    tokio::time::sleep(self.config.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(StorageError::RefNotFound(_)) => return Err(err),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        self.retry(|| self.backend.list_objects(prefix)).await
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        self.retry(|| self.backend.delete_objects(prefix, ids.clone())).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        error::Error,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        refs::{create_tag, fetch_tag, RefError},
+        ObjectStorage,
+    };
+
+    use super::*;
+
+    /// Fails the next `failures` operations with a transient error
+    ///
+    /// Failed ref writes are applied to the backend before failing, like a request that
+    /// times out after the server processed it.
+    #[derive(Debug)]
+    struct FlakyStorage {
+        backend: ObjectStorage,
+        failures: AtomicU32,
+        calls: AtomicU32,
+    }
+
+    impl FlakyStorage {
+        fn new(failures: u32) -> Self {
+            Self {
+                backend: ObjectStorage::new_in_memory_store(None),
+                failures: AtomicU32::new(failures),
+                calls: AtomicU32::new(0),
+            }
+        }
+
+        fn fail(&self) -> StorageResult<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let remaining = self.failures.load(Ordering::SeqCst);
+            if remaining == 0 {
+                return Ok(());
+            }
+            self.failures.store(remaining - 1, Ordering::SeqCst);
+            Err(StorageError::ObjectStore(object_store::Error::Generic {
+                store: "flaky",
+                source: Box::new(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                )),
+            }))
+        }
+    }
+
+    impl private::Sealed for FlakyStorage {}
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
+            self.fail()?;
+            self.backend.fetch_snapshot(id).await
+        }
+
+        async fn fetch_attributes(
+            &self,
+            id: &AttributesId,
+        ) -> StorageResult<Arc<AttributesTable>> {
+            self.fail()?;
+            self.backend.fetch_attributes(id).await
+        }
+
+        async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
+            self.fail()?;
+            self.backend.fetch_manifests(id).await
+        }
+
+        async fn fetch_chunk(
+            &self,
+            id: &ChunkId,
+            range: &ByteRange,
+        ) -> StorageResult<Bytes> {
+            self.fail()?;
+            self.backend.fetch_chunk(id, range).await
+        }
+
+        async fn write_snapshot(
+            &self,
+            id: SnapshotId,
+            snapshot: Arc<Snapshot>,
+        ) -> StorageResult<()> {
+            self.fail()?;
+            self.backend.write_snapshot(id, snapshot).await
+        }
+
+        async fn write_attributes(
+            &self,
+            id: AttributesId,
+            table: Arc<AttributesTable>,
+        ) -> StorageResult<()> {
+            self.fail()?;
+            self.backend.write_attributes(id, table).await
+        }
+
+        async fn write_manifests(
+            &self,
+            id: ManifestId,
+            manifest: Arc<Manifest>,
+        ) -> StorageResult<()> {
+            self.fail()?;
+            self.backend.write_manifests(id, manifest).await
+        }
+
+        async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()> {
+            self.fail()?;
+            self.backend.write_chunk(id, bytes).await
+        }
+
+        async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
+            self.fail()?;
+            self.backend.get_ref(ref_key).await
+        }
+
+        async fn ref_names(&self) -> StorageResult<Vec<String>> {
+            self.fail()?;
+            self.backend.ref_names().await
+        }
+
+        async fn ref_versions(
+            &self,
+            ref_name: &str,
+        ) -> StorageResult<BoxStream<StorageResult<String>>> {
+            self.fail()?;
+            self.backend.ref_versions(ref_name).await
+        }
+
+        async fn write_ref(
+            &self,
+            ref_key: &str,
+            overwrite_refs: bool,
+            bytes: Bytes,
+        ) -> StorageResult<()> {
+            let res = self.backend.write_ref(ref_key, overwrite_refs, bytes).await;
+            self.fail()?;
+            res
+        }
+
+        async fn list_objects<'a>(
+            &'a self,
+            prefix: &str,
+        ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+            self.fail()?;
+            self.backend.list_objects(prefix).await
+        }
+
+        async fn delete_objects(
+            &self,
+            prefix: &str,
+            ids: Vec<String>,
+        ) -> StorageResult<usize> {
+            self.fail()?;
+            self.backend.delete_objects(prefix, ids).await
+        }
+    }
+
+    fn config(max_attempts: u32) -> RetryConfig {
+        RetryConfig { max_attempts, initial_backoff_ms: 1, max_backoff_ms: 4 }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() -> Result<(), Box<dyn Error>> {
+        let flaky = Arc::new(FlakyStorage::new(2));
+        let storage = RetryingStorage::new(flaky.clone(), config(3));
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            storage.fetch_chunk(&id, &ByteRange::ALL).await?,
+            Bytes::from_static(b"hello")
+        );
+
+        // attempts are limited
+        flaky.failures.store(3, Ordering::SeqCst);
+        let res = storage.fetch_chunk(&id, &ByteRange::ALL).await;
+        assert!(matches!(res, Err(StorageError::ObjectStore(_))));
+
+        // other errors are not retried
+        flaky.calls.store(0, Ordering::SeqCst);
+        assert!(storage.fetch_snapshot(&SnapshotId::random()).await.is_err());
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_ref_writes() -> Result<(), Box<dyn Error>> {
+        // the first write succeeds but reports an error, reading it back finds our bytes
+        let flaky = Arc::new(FlakyStorage::new(1));
+        let storage = RetryingStorage::new(flaky.clone(), config(3));
+        let id = SnapshotId::random();
+        create_tag(&storage, "tag", id.clone(), false).await?;
+        assert_eq!(fetch_tag(&storage, "tag").await?.snapshot, id);
+
+        // a ref written by somebody else is not overwritten by the retry
+        flaky.failures.store(1, Ordering::SeqCst);
+        let res = create_tag(&storage, "tag", SnapshotId::random(), false).await;
+        assert!(matches!(res, Err(RefError::TagAlreadyExists(name)) if name == "tag"));
+        assert_eq!(fetch_tag(&storage, "tag").await?.snapshot, id);
+        Ok(())
+    }
+}
@@ -5,13 +5,15 @@ use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, AppName, BehaviorVersion};
 use aws_credential_types::Credentials;
 use aws_sdk_s3::{
-    config::{Builder, Region},
+    config::{retry::RetryConfig, Builder, Region},
     error::ProvideErrorMetadata,
     primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier},
     Client,
 };
 use bytes::Bytes;
-use futures::StreamExt;
+use chrono::DateTime;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -25,13 +27,17 @@ use crate::{
     Storage, StorageError,
 };
 
-use super::StorageResult;
+use super::{
+    ListInfo, MultipartConfig, StorageResult, ATTRIBUTES_PREFIX, CHUNK_PREFIX,
+    MANIFEST_PREFIX, REF_PREFIX, SNAPSHOT_PREFIX,
+};
 
 #[derive(Debug)]
 pub struct S3Storage {
     client: Arc<Client>,
     prefix: String,
     bucket: String,
+    multipart: MultipartConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -62,6 +68,10 @@ pub struct S3Config {
 }
 
 pub async fn mk_client(config: Option<&S3Config>) -> Client {
+    Client::from_conf(mk_client_builder(config).await.build())
+}
+
+async fn mk_client_builder(config: Option<&S3Config>) -> Builder {
     let region = config
         .and_then(|c| c.region.as_ref())
         .map(|r| RegionProviderChain::first_try(Some(Region::new(r.clone()))))
@@ -101,25 +111,31 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
         s3_builder = s3_builder.force_path_style(true);
     }
 
-    let config = s3_builder.build();
-
-    Client::from_conf(config)
+    s3_builder
 }
 
-const SNAPSHOT_PREFIX: &str = "snapshots/";
-const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
-const CHUNK_PREFIX: &str = "chunks/";
-const REF_PREFIX: &str = "refs";
-
 impl S3Storage {
     pub async fn new_s3_store(
         bucket_name: impl Into<String>,
         prefix: impl Into<String>,
         config: Option<&S3Config>,
     ) -> Result<S3Storage, StorageError> {
-        let client = Arc::new(mk_client(config).await);
-        Ok(S3Storage { client, prefix: prefix.into(), bucket: bucket_name.into() })
+        // requests are retried by `RetryingStorage`, which knows which ones can be repeated,
+        // the SDK would resend conditional ref writes that may have succeeded already
+        let client_config =
+            mk_client_builder(config).await.retry_config(RetryConfig::disabled()).build();
+        let client = Arc::new(Client::from_conf(client_config));
+        Ok(S3Storage {
+            client,
+            prefix: prefix.into(),
+            bucket: bucket_name.into(),
+            multipart: MultipartConfig::default(),
+        })
+    }
+
+    pub fn with_multipart_config(mut self, config: MultipartConfig) -> Self {
+        self.multipart = config;
+        self
     }
 
     fn get_path<const SIZE: usize, T: FileTypeTag>(
@@ -140,6 +156,10 @@ impl S3Storage {
         self.get_path(SNAPSHOT_PREFIX, id)
     }
 
+    fn get_attributes_path(&self, id: &AttributesId) -> StorageResult<String> {
+        self.get_path(ATTRIBUTES_PREFIX, id)
+    }
+
     fn get_manifest_path(&self, id: &ManifestId) -> StorageResult<String> {
         self.get_path(MANIFEST_PREFIX, id)
     }
@@ -148,6 +168,11 @@ impl S3Storage {
         self.get_path(CHUNK_PREFIX, id)
     }
 
+    fn get_prefix_path(&self, file_prefix: &str) -> StorageResult<String> {
+        let path = PathBuf::from_iter([self.prefix.as_str(), file_prefix]);
+        path.into_os_string().into_string().map_err(StorageError::BadPrefix)
+    }
+
     fn ref_key(&self, ref_key: &str) -> StorageResult<String> {
         let path = PathBuf::from_iter([self.prefix.as_str(), REF_PREFIX, ref_key]);
         path.into_os_string().into_string().map_err(StorageError::BadPrefix)
@@ -188,8 +213,13 @@ impl S3Storage {
         key: &str,
         content_type: Option<impl Into<String>>,
         metadata: I,
-        bytes: impl Into<ByteStream>,
+        bytes: impl Into<Bytes>,
     ) -> StorageResult<()> {
+        let bytes = bytes.into();
+        if self.multipart.use_multipart(bytes.len()) {
+            return self.put_object_multipart(key, content_type, metadata, bytes).await;
+        }
+
         let mut b = self.client.put_object().bucket(self.bucket.clone()).key(key);
 
         if let Some(ct) = content_type {
@@ -203,6 +233,96 @@ impl S3Storage {
         b.body(bytes.into()).send().await?;
         Ok(())
     }
+
+    async fn put_object_multipart<
+        I: IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    >(
+        &self,
+        key: &str,
+        content_type: Option<impl Into<String>>,
+        metadata: I,
+        bytes: Bytes,
+    ) -> StorageResult<()> {
+        let mut b =
+            self.client.create_multipart_upload().bucket(self.bucket.clone()).key(key);
+
+        if let Some(ct) = content_type {
+            b = b.content_type(ct)
+        };
+
+        for (k, v) in metadata {
+            b = b.metadata(k, v);
+        }
+
+        let upload = b.send().await?;
+        let upload_id = upload.upload_id().ok_or_else(|| {
+            StorageError::Other(format!("no upload id for multipart upload of {key}"))
+        })?;
+
+        match self.upload_parts(key, upload_id, bytes).await {
+            Ok(parts) => {
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await?;
+                Ok(())
+            }
+            Err(err) => {
+                // parts already uploaded are discarded, the error of the upload is the
+                // interesting one
+                let _ignored = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        bytes: Bytes,
+    ) -> StorageResult<Vec<CompletedPart>> {
+        let part_size = self.multipart.part_size();
+        let parts = (0..bytes.len())
+            .step_by(part_size)
+            .map(|start| bytes.slice(start..(start + part_size).min(bytes.len())));
+        futures::stream::iter(parts.enumerate())
+            .map(|(index, part)| async move {
+                let part_number = index as i32 + 1;
+                let res = self
+                    .client
+                    .upload_part()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(part))
+                    .send()
+                    .await?;
+                Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(res.e_tag)
+                    .build())
+            })
+            .buffered(self.multipart.max_concurrent_parts.max(1))
+            .try_collect()
+            .await
+    }
 }
 
 pub fn range_to_header(range: &ByteRange) -> Option<String> {
@@ -230,9 +350,13 @@ impl Storage for S3Storage {
 
     async fn fetch_attributes(
         &self,
-        _id: &AttributesId,
+        id: &AttributesId,
     ) -> StorageResult<Arc<AttributesTable>> {
-        todo!()
+        let key = self.get_attributes_path(id)?;
+        let bytes = self.get_object(key.as_str()).await?;
+        // TODO: optimize using from_read
+        let res = rmp_serde::from_slice(bytes.as_ref())?;
+        Ok(Arc::new(res))
     }
 
     async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
@@ -271,10 +395,22 @@ impl Storage for S3Storage {
 
     async fn write_attributes(
         &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
     ) -> StorageResult<()> {
-        todo!()
+        let key = self.get_attributes_path(&id)?;
+        let bytes = rmp_serde::to_vec(table.as_ref())?;
+        let metadata = [(
+            format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
+            table.icechunk_attributes_format_version.to_string(),
+        )];
+        self.put_object(
+            key.as_str(),
+            Some(format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE),
+            metadata,
+            bytes,
+        )
+        .await
     }
 
     async fn write_manifests(
@@ -303,7 +439,6 @@ impl Storage for S3Storage {
         bytes: bytes::Bytes,
     ) -> Result<(), StorageError> {
         let key = self.get_chunk_path(&id)?;
-        //FIXME: use multipart upload
         let metadata: [(String, String); 0] = [];
         self.put_object(key.as_str(), None::<String>, metadata, bytes).await
     }
@@ -417,4 +552,81 @@ impl Storage for S3Storage {
             }
         }
     }
+
+    async fn list_objects<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> StorageResult<BoxStream<'a, StorageResult<ListInfo<String>>>> {
+        let prefix = self.get_prefix_path(prefix)?;
+        let mut paginator = self
+            .client
+            .list_objects_v2()
+            .bucket(self.bucket.clone())
+            .prefix(prefix.clone())
+            .into_paginator()
+            .send();
+
+        let stream = try_stream! {
+            while let Some(page) = paginator.try_next().await? {
+                for object in page.contents() {
+                    let id = object.key.as_ref().and_then(|key| key.strip_prefix(prefix.as_str()));
+                    let created_at = object
+                        .last_modified
+                        .as_ref()
+                        .and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos()));
+                    if let (Some(id), Some(created_at)) = (id, created_at) {
+                        yield ListInfo {
+                            id: id.to_string(),
+                            created_at,
+                            size_bytes: object.size.unwrap_or(0) as u64,
+                        }
+                    }
+                }
+            }
+        };
+        Ok(stream.boxed())
+    }
+
+    async fn delete_objects(
+        &self,
+        prefix: &str,
+        ids: Vec<String>,
+    ) -> StorageResult<usize> {
+        let prefix = self.get_prefix_path(prefix)?;
+        let mut deleted = 0;
+        // S3 accepts at most 1000 keys per delete request
+        for batch in ids.chunks(1_000) {
+            let objects = batch
+                .iter()
+                .map(|id| {
+                    let key = format!("{}{}", prefix, id);
+                    ObjectIdentifier::builder().key(key).build()
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| StorageError::Other(e.to_string()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(false)
+                .build()
+                .map_err(|e| StorageError::Other(e.to_string()))?;
+
+            let res = self
+                .client
+                .delete_objects()
+                .bucket(self.bucket.clone())
+                .delete(delete)
+                .send()
+                .await?;
+
+            if let Some(err) = res.errors().first() {
+                return Err(StorageError::Other(format!(
+                    "cannot delete object {}: {}",
+                    err.key().unwrap_or_default(),
+                    err.message().unwrap_or_default()
+                )));
+            }
+            deleted += res.deleted().len();
+        }
+        Ok(deleted)
+    }
 }
@@ -1,14 +1,23 @@
-use crate::format::manifest::{VirtualChunkLocation, VirtualReferenceError};
+use crate::format::manifest::{
+    VirtualChunkLocation, VirtualChunkPin, VirtualReferenceError,
+};
 use crate::format::ByteRange;
 use crate::private;
 use async_trait::async_trait;
-use aws_sdk_s3::Client;
+use aws_sdk_s3::{primitives::DateTime, Client};
 use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use object_store::http::{HttpBuilder, HttpStore};
 use object_store::local::LocalFileSystem;
-use object_store::{path::Path as ObjectPath, GetOptions, GetRange, ObjectStore};
+use object_store::{
+    path::Path as ObjectPath, ClientOptions, GetOptions, GetRange, ObjectStore,
+};
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 use tokio::sync::OnceCell;
 use url::{self, Url};
 
@@ -16,30 +25,100 @@ use super::s3::{mk_client, range_to_header, S3Config};
 
 #[async_trait]
 pub trait VirtualChunkResolver: Debug + private::Sealed {
+    /// Fetch the `range` of the object at `location`
+    ///
+    /// Fails with [`VirtualReferenceError::ObjectModified`] if the object doesn't match the
+    /// `pin`, an empty pin fetches the object as it is.
     async fn fetch_chunk(
         &self,
         location: &VirtualChunkLocation,
         range: &ByteRange,
+        pin: &VirtualChunkPin,
     ) -> Result<Bytes, VirtualReferenceError>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ObjectStoreVirtualChunkResolverConfig {
     S3(S3Config),
+    /// A different config for the `s3` virtual chunks under each url prefix, like
+    /// `s3://bucket/path/`. A key without a scheme is a bucket name, so `bucket` is
+    /// the same as `s3://bucket/`. The longest matching prefix is used. An empty prefix
+    /// matches every location, locations without a match use the environment config.
+    S3PerPrefix(BTreeMap<String, S3Config>),
+}
+
+/// Options for virtual chunks fetched over `http` and `https`
+///
+/// Redirects are followed, up to 10 of them. Sensitive headers like `Authorization` are
+/// not sent after a redirect to a different host.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Headers sent with the requests to the urls under each prefix, like
+    /// `https://example.com/data/`, for example an `Authorization` token. The longest
+    /// matching prefix is used, urls without a match are requested without extra headers.
+    pub headers: BTreeMap<String, BTreeMap<String, String>>,
 }
 
 #[derive(Debug)]
 pub struct ObjectStoreVirtualChunkResolver {
     s3: OnceCell<Client>,
+    // clients for the prefixes with their own config, longest prefix first
+    s3_prefixes: Vec<(String, S3Config, OnceCell<Client>)>,
     config: Box<Option<ObjectStoreVirtualChunkResolverConfig>>,
+    // the headers for each http prefix, longest prefix first
+    http_prefixes: Vec<(String, BTreeMap<String, String>)>,
+    // one client per origin, query and prefix, so connections are reused
+    http: Mutex<HashMap<HttpStoreKey, Arc<HttpStore>>>,
 }
 
+/// The origin with the query, and the matching [`HttpConfig::headers`] prefix of an http
+/// client
+type HttpStoreKey = (String, Option<String>);
+
 impl ObjectStoreVirtualChunkResolver {
     pub fn new(config: Option<ObjectStoreVirtualChunkResolverConfig>) -> Self {
-        Self { s3: Default::default(), config: Box::new(config) }
+        let mut s3_prefixes = match &config {
+            Some(ObjectStoreVirtualChunkResolverConfig::S3PerPrefix(prefixes)) => {
+                prefixes
+                    .iter()
+                    .map(|(prefix, config)| {
+                        (s3_prefix_url(prefix), config.clone(), OnceCell::new())
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        s3_prefixes.sort_by_key(|(prefix, _, _)| std::cmp::Reverse(prefix.len()));
+        Self {
+            s3: Default::default(),
+            s3_prefixes,
+            config: Box::new(config),
+            http_prefixes: Vec::new(),
+            http: Default::default(),
+        }
+    }
+
+    pub fn with_http_config(mut self, config: HttpConfig) -> Self {
+        let mut prefixes: Vec<_> = config
+            .headers
+            .into_iter()
+            .map(|(prefix, headers)| (http_prefix_url(&prefix), headers))
+            .collect();
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self.http_prefixes = prefixes;
+        self
     }
 
-    async fn s3(&self) -> &Client {
+    async fn s3(&self, url: &Url) -> &Client {
+        let prefix = self
+            .s3_prefixes
+            .iter()
+            .find(|(prefix, _, _)| url.as_str().starts_with(prefix.as_str()));
+        if let Some((_, config, client)) = prefix {
+            return client.get_or_init(|| mk_client(Some(config))).await;
+        }
+
         let config = self.config.clone();
         self.s3
             .get_or_init(|| async move {
@@ -47,7 +126,8 @@ impl ObjectStoreVirtualChunkResolver {
                     Some(ObjectStoreVirtualChunkResolverConfig::S3(config)) => {
                         mk_client(Some(config)).await
                     }
-                    None => mk_client(None).await,
+                    Some(ObjectStoreVirtualChunkResolverConfig::S3PerPrefix(_))
+                    | None => mk_client(None).await,
                 }
             })
             .await
@@ -57,26 +137,72 @@ impl ObjectStoreVirtualChunkResolver {
         &self,
         url: &Url,
         range: &ByteRange,
+        pin: &VirtualChunkPin,
     ) -> Result<Bytes, VirtualReferenceError> {
         let store = LocalFileSystem::new();
-        let options =
-            GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
         let path = ObjectPath::parse(url.path())
             .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+        fetch_object_store(&store, url, &path, range, pin).await
+    }
 
-        store
-            .get_opts(&path, options)
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
-            .bytes()
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))
+    fn http_store(&self, url: &Url) -> Result<Arc<HttpStore>, VirtualReferenceError> {
+        // the client sends the query of its base url with every request
+        let origin = match url.query() {
+            Some(query) => format!("{}/?{query}", url.origin().ascii_serialization()),
+            None => url.origin().ascii_serialization(),
+        };
+        let prefix = self
+            .http_prefixes
+            .iter()
+            .find(|(prefix, _)| url.as_str().starts_with(prefix.as_str()));
+        let key = (origin, prefix.map(|(prefix, _)| prefix.clone()));
+        let mut stores = self
+            .http
+            .lock()
+            .map_err(|_| VirtualReferenceError::OtherError("poisoned lock".into()))?;
+        if let Some(store) = stores.get(&key) {
+            return Ok(Arc::clone(store));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in prefix.iter().flat_map(|(_, headers)| headers) {
+            let name = HeaderName::try_from(name)
+                .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+            let mut value = HeaderValue::try_from(value)
+                .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let options = ClientOptions::new()
+            .with_allow_http(url.scheme() == "http")
+            .with_default_headers(headers);
+        let store = HttpBuilder::new()
+            .with_url(key.0.as_str())
+            .with_client_options(options)
+            .build()
+            .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+        let store = Arc::new(store);
+        stores.insert(key, Arc::clone(&store));
+        Ok(store)
+    }
+
+    async fn fetch_http(
+        &self,
+        url: &Url,
+        range: &ByteRange,
+        pin: &VirtualChunkPin,
+    ) -> Result<Bytes, VirtualReferenceError> {
+        let store = self.http_store(url)?;
+        let path = ObjectPath::from_url_path(url.path())
+            .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+        fetch_object_store(store.as_ref(), url, &path, range, pin).await
     }
 
     async fn fetch_s3(
         &self,
         url: &Url,
         range: &ByteRange,
+        pin: &VirtualChunkPin,
     ) -> Result<Bytes, VirtualReferenceError> {
         let bucket_name = if let Some(host) = url.host_str() {
             host.to_string()
@@ -88,7 +214,16 @@ impl ObjectStoreVirtualChunkResolver {
 
         let key = url.path();
         let key = key.strip_prefix('/').unwrap_or(key);
-        let mut b = self.s3().await.get_object().bucket(bucket_name).key(key);
+        let mut b = self
+            .s3(url)
+            .await
+            .get_object()
+            .bucket(bucket_name)
+            .key(key)
+            .set_if_match(pin.etag.clone())
+            .set_if_unmodified_since(
+                pin.last_modified.map(|t| DateTime::from_secs(t.timestamp())),
+            );
 
         if let Some(header) = range_to_header(range) {
             b = b.range(header)
@@ -96,7 +231,13 @@ impl ObjectStoreVirtualChunkResolver {
 
         Ok(b.send()
             .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
+            .map_err(|e| {
+                if e.raw_response().map(|r| r.status().as_u16()) == Some(412) {
+                    VirtualReferenceError::ObjectModified(url.to_string())
+                } else {
+                    VirtualReferenceError::FetchError(Box::new(e))
+                }
+            })?
             .body
             .collect()
             .await
@@ -105,6 +246,51 @@ impl ObjectStoreVirtualChunkResolver {
     }
 }
 
+/// Fetch from an `object_store` backend, using conditional requests to check the `pin`
+async fn fetch_object_store(
+    store: &dyn ObjectStore,
+    url: &Url,
+    path: &ObjectPath,
+    range: &ByteRange,
+    pin: &VirtualChunkPin,
+) -> Result<Bytes, VirtualReferenceError> {
+    let options = GetOptions {
+        range: Option::<GetRange>::from(range),
+        if_match: pin.etag.clone(),
+        if_unmodified_since: pin.last_modified,
+        ..Default::default()
+    };
+    store
+        .get_opts(path, options)
+        .await
+        .map_err(|e| match e {
+            object_store::Error::Precondition { .. } => {
+                VirtualReferenceError::ObjectModified(url.to_string())
+            }
+            e => VirtualReferenceError::FetchError(Box::new(e)),
+        })?
+        .bytes()
+        .await
+        .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))
+}
+
+/// The url prefix for a key of [`ObjectStoreVirtualChunkResolverConfig::S3PerPrefix`]
+fn s3_prefix_url(prefix: &str) -> String {
+    if prefix.is_empty() || prefix.contains("://") {
+        prefix.to_string()
+    } else {
+        format!("s3://{}/", prefix.trim_end_matches('/'))
+    }
+}
+
+/// The url prefix for a key of [`HttpConfig::headers`]
+///
+/// Prefixes are normalized like urls, so `https://example.com` becomes
+/// `https://example.com/` and doesn't match `https://example.com.other.org`.
+fn http_prefix_url(prefix: &str) -> String {
+    Url::parse(prefix).map(String::from).unwrap_or_else(|_| prefix.to_string())
+}
+
 // Converts the requested ByteRange to a valid ByteRange appropriate
 // to the chunk reference of known `offset` and `length`.
 pub fn construct_valid_byte_range(
@@ -112,23 +298,32 @@ pub fn construct_valid_byte_range(
     chunk_offset: u64,
     chunk_length: u64,
 ) -> ByteRange {
+    ByteRange::Bounded(construct_valid_range(request, chunk_offset, chunk_length))
+}
+
+/// The absolute range of the object that holds the `request`ed bytes of a chunk
+pub fn construct_valid_range(
+    request: &ByteRange,
+    chunk_offset: u64,
+    chunk_length: u64,
+) -> Range<u64> {
     // TODO: error for offset<0
     // TODO: error if request.start > offset + length
     match request {
-        ByteRange::Bounded(std::ops::Range { start: req_start, end: req_end }) => {
+        ByteRange::Bounded(Range { start: req_start, end: req_end }) => {
             let new_start =
                 min(chunk_offset + req_start, chunk_offset + chunk_length - 1);
             let new_end = min(chunk_offset + req_end, chunk_offset + chunk_length);
-            ByteRange::Bounded(new_start..new_end)
+            new_start..new_end
         }
         ByteRange::From(n) => {
             let new_start = min(chunk_offset + n, chunk_offset + chunk_length - 1);
-            ByteRange::Bounded(new_start..chunk_offset + chunk_length)
+            new_start..chunk_offset + chunk_length
         }
         ByteRange::Last(n) => {
             let new_end = chunk_offset + chunk_length;
             let new_start = new_end - n;
-            ByteRange::Bounded(new_start..new_end)
+            new_start..new_end
         }
     }
 }
@@ -141,26 +336,36 @@ impl VirtualChunkResolver for ObjectStoreVirtualChunkResolver {
         &self,
         location: &VirtualChunkLocation,
         range: &ByteRange,
+        pin: &VirtualChunkPin,
     ) -> Result<Bytes, VirtualReferenceError> {
-        let VirtualChunkLocation::Absolute(location) = location;
+        // relative locations are resolved by the repository, that knows the prefixes
+        let location = match location {
+            VirtualChunkLocation::Absolute(location) => location,
+            VirtualChunkLocation::Relative(name, _) => {
+                return Err(VirtualReferenceError::UnknownPrefix(name.clone()))
+            }
+        };
         let parsed =
             url::Url::parse(location).map_err(VirtualReferenceError::CannotParseUrl)?;
         let scheme = parsed.scheme();
 
         match scheme {
-            "file" => self.fetch_file(&parsed, range).await,
-            "s3" => self.fetch_s3(&parsed, range).await,
+            "file" => self.fetch_file(&parsed, range, pin).await,
+            "s3" => self.fetch_s3(&parsed, range, pin).await,
+            "http" | "https" => self.fetch_http(&parsed, range, pin).await,
             _ => Err(VirtualReferenceError::UnsupportedScheme(scheme.to_string())),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
     use proptest::prop_assert_eq;
     use test_strategy::proptest;
 
     use super::*;
+    use crate::storage::s3::S3Credentials;
 
     #[test]
     fn test_virtual_chunk_location_bad() {
@@ -176,6 +381,109 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_virtual_chunk_location_keeps_port() {
+        assert_eq!(
+            VirtualChunkLocation::from_absolute_path("http://localhost:8080//data/a.nc")
+                .ok(),
+            Some(VirtualChunkLocation::Absolute(
+                "http://localhost:8080/data/a.nc".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_virtual_chunk_location_keeps_http_query() {
+        assert_eq!(
+            VirtualChunkLocation::from_absolute_path(
+                "https://example.com/data/a.nc?sig=abc%2Fd&expires=10"
+            )
+            .ok(),
+            Some(VirtualChunkLocation::Absolute(
+                "https://example.com/data/a.nc?sig=abc%2Fd&expires=10".to_string()
+            ))
+        );
+        assert_eq!(
+            VirtualChunkLocation::from_absolute_path("s3://bucket/data/a.nc?versionId=1")
+                .ok(),
+            Some(VirtualChunkLocation::Absolute("s3://bucket/data/a.nc".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_config_per_prefix() {
+        let config = |region: &str| S3Config {
+            region: Some(region.to_string()),
+            endpoint: None,
+            credentials: S3Credentials::Anonymous,
+            allow_http: false,
+        };
+        let resolver = ObjectStoreVirtualChunkResolver::new(Some(
+            ObjectStoreVirtualChunkResolverConfig::S3PerPrefix(
+                [
+                    ("public".to_string(), config("public")),
+                    ("s3://private/".to_string(), config("private")),
+                    ("s3://private/other/".to_string(), config("other")),
+                    ("".to_string(), config("default")),
+                ]
+                .into(),
+            ),
+        ));
+        let region = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            let resolver = &resolver;
+            async move { resolver.s3(&url).await.config().region().map(|r| r.to_string()) }
+        };
+        assert_eq!(region("s3://public/a/b.nc").await.as_deref(), Some("public"));
+        assert_eq!(region("s3://private/a/b.nc").await.as_deref(), Some("private"));
+        assert_eq!(region("s3://private/other/b.nc").await.as_deref(), Some("other"));
+        assert_eq!(region("s3://public-2/a.nc").await.as_deref(), Some("default"));
+        assert_eq!(region("s3://another/a.nc").await.as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn test_http_config_per_prefix() -> Result<(), VirtualReferenceError> {
+        let headers =
+            |token: &str| [("Authorization".to_string(), token.to_string())].into();
+        let resolver =
+            ObjectStoreVirtualChunkResolver::new(None).with_http_config(HttpConfig {
+                headers: [
+                    ("https://example.com".to_string(), headers("site")),
+                    ("https://example.com/private/".to_string(), headers("private")),
+                ]
+                .into(),
+            });
+        let prefix = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            resolver
+                .http_prefixes
+                .iter()
+                .find(|(prefix, _)| url.as_str().starts_with(prefix.as_str()))
+                .map(|(prefix, _)| prefix.clone())
+        };
+        assert_eq!(
+            prefix("https://example.com/a.nc").as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(
+            prefix("https://example.com/private/a.nc").as_deref(),
+            Some("https://example.com/private/")
+        );
+        assert_eq!(prefix("https://example.com.other.org/a.nc"), None);
+        assert_eq!(prefix("http://example.com/a.nc"), None);
+
+        // urls of the same origin with different headers don't share a client
+        let site =
+            resolver.http_store(&Url::parse("https://example.com/a.nc").unwrap())?;
+        let private = resolver
+            .http_store(&Url::parse("https://example.com/private/a.nc").unwrap())?;
+        assert!(!Arc::ptr_eq(&site, &private));
+        let other =
+            resolver.http_store(&Url::parse("https://example.com/b.nc").unwrap())?;
+        assert!(Arc::ptr_eq(&site, &other));
+        Ok(())
+    }
+
     #[proptest]
     fn test_properties_construct_valid_byte_range(
         #[strategy(0..10u64)] offset: u64,
@@ -1,14 +1,11 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::Display,
     iter,
     num::NonZeroU64,
     ops::{Deref, DerefMut},
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
-    },
+    sync::Arc,
 };
 
 use async_stream::try_stream;
@@ -23,20 +20,22 @@ use tokio::sync::RwLock;
 use crate::{
     change_set::ChangeSet,
     format::{
-        manifest::VirtualChunkRef,
-        snapshot::{NodeData, UserAttributesSnapshot},
-        ByteRange, ChunkOffset, IcechunkFormatError, SnapshotId,
+        manifest::VirtualChunkRef, snapshot::NodeData, ByteRange, ChunkOffset,
+        IcechunkFormatError, SnapshotId,
     },
     refs::{update_branch, BranchVersion, Ref, RefError},
     repository::{
         get_chunk, raise_if_invalid_snapshot_id, ArrayShape, ChunkIndices,
         ChunkKeyEncoding, ChunkPayload, ChunkShape, Codec, DataType, DimensionNames,
         FillValue, Path, RepositoryError, RepositoryResult, StorageTransformer,
-        UserAttributes, ZarrArrayMetadata,
+        UserAttributes, VirtualRefIntegrity, ZarrArrayMetadata,
     },
     storage::{
+        object_store::{AzureConfig, GcsConfig},
         s3::{S3Config, S3Storage},
-        virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+        virtual_ref::{HttpConfig, ObjectStoreVirtualChunkResolverConfig},
+        CacheStats, CachingConfig, DiskCacheConfig, DiskCachingStorage, RetryConfig,
+        RetryingStorage,
     },
     ObjectStorage, Repository, RepositoryBuilder, SnapshotMetadata, Storage,
 };
@@ -60,6 +59,31 @@ pub enum StorageConfig {
         #[serde(flatten)]
         config: Option<S3Config>,
     },
+
+    #[serde(rename = "gcs")]
+    GcsObjectStore {
+        bucket: String,
+        prefix: String,
+        #[serde(flatten)]
+        config: Option<GcsConfig>,
+    },
+
+    #[serde(rename = "azure")]
+    AzureObjectStore {
+        account: String,
+        container: String,
+        prefix: String,
+        #[serde(flatten)]
+        config: Option<AzureConfig>,
+    },
+
+    /// Any `object_store` backend, configured with its url and builder options
+    #[serde(rename = "object_store_url")]
+    ObjectStoreUrl {
+        url: String,
+        #[serde(default)]
+        options: HashMap<String, String>,
+    },
 }
 
 impl StorageConfig {
@@ -79,14 +103,55 @@ impl StorageConfig {
                     .map_err(|e| format!("Error creating storage: {e}"))?;
                 Ok(Arc::new(storage))
             }
+            StorageConfig::GcsObjectStore { bucket, prefix, config } => {
+                let storage =
+                    ObjectStorage::new_gcs_store(bucket, prefix, config.as_ref())
+                        .map_err(|e| format!("Error creating storage: {e}"))?;
+                Ok(Arc::new(storage))
+            }
+            StorageConfig::AzureObjectStore { account, container, prefix, config } => {
+                let storage = ObjectStorage::new_azure_blob_store(
+                    account,
+                    container,
+                    prefix,
+                    config.as_ref(),
+                )
+                .map_err(|e| format!("Error creating storage: {e}"))?;
+                Ok(Arc::new(storage))
+            }
+            StorageConfig::ObjectStoreUrl { url, options } => {
+                let storage = ObjectStorage::new_from_url(url, options)
+                    .map_err(|e| format!("Error creating storage: {e}"))?;
+                Ok(Arc::new(storage))
+            }
         }
     }
 
     pub async fn make_cached_storage(
         &self,
+    ) -> Result<Arc<dyn Storage + Send + Sync>, String> {
+        self.make_cached_storage_with_options(&StoreOptions::default()).await
+    }
+
+    /// Like [`Self::make_cached_storage`], with the retry policy, disk cache and in memory
+    /// cache sizes of `options`
+    pub async fn make_cached_storage_with_options(
+        &self,
+        options: &StoreOptions,
     ) -> Result<Arc<dyn Storage + Send + Sync>, String> {
         let storage = self.make_storage().await?;
-        let cached_storage = Repository::add_in_mem_asset_caching(storage);
+        let retry = options.retry.clone().unwrap_or_default();
+        let mut storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(RetryingStorage::new(storage, retry));
+        if let Some(disk_cache) = &options.disk_cache {
+            let disk_caching = DiskCachingStorage::new(storage, disk_cache.clone())
+                .map_err(|e| format!("Error creating disk cache: {e}"))?;
+            storage = Arc::new(disk_caching);
+        }
+        let cached_storage = Repository::add_in_mem_asset_caching_with_config(
+            storage,
+            options.caching.as_ref().unwrap_or(&CachingConfig::default()),
+        );
         Ok(cached_storage)
     }
 }
@@ -110,6 +175,16 @@ pub struct RepositoryConfig {
     pub unsafe_overwrite_refs: Option<bool>,
     pub change_set_bytes: Option<Vec<u8>>,
     pub virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    /// Headers for virtual chunks fetched over `http` and `https`, per url prefix
+    pub virtual_http_config: Option<HttpConfig>,
+    pub commit_rebase_attempts: Option<u16>,
+    /// Sizes of the in memory caches, [`StoreOptions::caching`] takes precedence if both
+    /// are set
+    pub caching: Option<CachingConfig>,
+    /// Partial reads of chunks up to this size fetch and cache the whole chunk
+    pub promote_partial_reads_max_bytes: Option<u64>,
+    /// How reads check the ETag and modification time of virtual chunk objects
+    pub virtual_ref_integrity: Option<VirtualRefIntegrity>,
 }
 
 impl RepositoryConfig {
@@ -144,11 +219,36 @@ impl RepositoryConfig {
         self
     }
 
+    pub fn with_virtual_http_config(mut self, config: HttpConfig) -> Self {
+        self.virtual_http_config = Some(config);
+        self
+    }
+
     pub fn with_change_set_bytes(mut self, change_set_bytes: Vec<u8>) -> Self {
         self.change_set_bytes = Some(change_set_bytes);
         self
     }
 
+    pub fn with_commit_rebase_attempts(mut self, attempts: u16) -> Self {
+        self.commit_rebase_attempts = Some(attempts);
+        self
+    }
+
+    pub fn with_caching(mut self, caching: CachingConfig) -> Self {
+        self.caching = Some(caching);
+        self
+    }
+
+    pub fn with_promote_partial_reads_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.promote_partial_reads_max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_virtual_ref_integrity(mut self, integrity: VirtualRefIntegrity) -> Self {
+        self.virtual_ref_integrity = Some(integrity);
+        self
+    }
+
     pub async fn make_repository(
         &self,
         storage: Arc<dyn Storage + Send + Sync>,
@@ -191,6 +291,18 @@ impl RepositoryConfig {
         if let Some(config) = &self.virtual_ref_config {
             builder.with_virtual_ref_config(config.clone());
         }
+        if let Some(config) = &self.virtual_http_config {
+            builder.with_virtual_http_config(config.clone());
+        }
+        if let Some(attempts) = self.commit_rebase_attempts {
+            builder.with_commit_rebase_attempts(attempts);
+        }
+        if let Some(max_bytes) = self.promote_partial_reads_max_bytes {
+            builder.with_promote_partial_reads_max_bytes(max_bytes);
+        }
+        if let Some(integrity) = self.virtual_ref_integrity {
+            builder.with_virtual_ref_integrity(integrity);
+        }
         if let Some(change_set_bytes) = &self.change_set_bytes {
             let change_set = ChangeSet::import_from_bytes(change_set_bytes)
                 .map_err(|err| format!("Error parsing change set: {err}"))?;
@@ -206,11 +318,34 @@ impl RepositoryConfig {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct StoreOptions {
     pub get_partial_values_concurrency: u16,
+    /// How storage operations are retried, [`RetryConfig::default`] if not set
+    pub retry: Option<RetryConfig>,
+    /// Cache snapshots, manifests and chunks in a local directory
+    pub disk_cache: Option<DiskCacheConfig>,
+    /// Sizes of the in memory caches, overriding [`RepositoryConfig::caching`],
+    /// [`CachingConfig::default`] if neither is set
+    pub caching: Option<CachingConfig>,
+    /// `get_partial_values` merges reads of the same object at most this many bytes
+    /// apart into a single request, [`DEFAULT_COALESCE_GAP_BYTES`] if not set
+    pub get_partial_values_coalesce_gap_bytes: Option<u64>,
+    /// `get_partial_values` never merges reads into a request larger than this many
+    /// bytes, [`DEFAULT_COALESCE_MAX_BYTES`] if not set
+    pub get_partial_values_coalesce_max_bytes: Option<u64>,
 }
 
+pub const DEFAULT_COALESCE_GAP_BYTES: u64 = 1024 * 1024;
+pub const DEFAULT_COALESCE_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
 impl Default for StoreOptions {
     fn default() -> Self {
-        Self { get_partial_values_concurrency: 10 }
+        Self {
+            get_partial_values_concurrency: 10,
+            retry: None,
+            disk_cache: None,
+            caching: None,
+            get_partial_values_coalesce_gap_bytes: None,
+            get_partial_values_coalesce_max_bytes: None,
+        }
     }
 }
 
@@ -223,6 +358,16 @@ pub struct ConsolidatedStore {
 }
 
 impl ConsolidatedStore {
+    /// The options of the store, with the cache sizes of the repository config if the
+    /// options don't set them
+    pub fn store_options(&self) -> StoreOptions {
+        let mut options = self.config.clone().unwrap_or_default();
+        if options.caching.is_none() {
+            options.caching.clone_from(&self.repository.caching);
+        }
+        options
+    }
+
     pub fn with_version(mut self, version: VersionInfo) -> Self {
         self.repository.version = Some(version);
         self
@@ -313,7 +458,10 @@ impl Store {
         consolidated: &ConsolidatedStore,
         mode: AccessMode,
     ) -> Result<Self, String> {
-        let storage = consolidated.storage.make_cached_storage().await?;
+        let storage = consolidated
+            .storage
+            .make_cached_storage_with_options(&consolidated.store_options())
+            .await?;
         let (repository, branch) =
             consolidated.repository.make_repository(storage).await?;
         Ok(Self::from_repository(repository, mode, branch, consolidated.config.clone()))
@@ -385,6 +533,11 @@ impl Store {
         self.repository.read().await.has_uncommitted_changes()
     }
 
+    /// Hits and misses of the in memory caches of the store
+    pub async fn cache_stats(&self) -> Option<CacheStats> {
+        self.repository.read().await.cache_stats()
+    }
+
     /// Resets the store to the head commit state. If there are any uncommitted changes, they will
     /// be lost.
     pub async fn reset(&mut self) -> StoreResult<()> {
@@ -531,14 +684,13 @@ impl Store {
     pub async fn ancestry(
         &self,
     ) -> StoreResult<impl Stream<Item = StoreResult<SnapshotMetadata>> + Send> {
-        let repository = Arc::clone(&self.repository).read_owned().await;
-        // FIXME: in memory realization to avoid maintaining a lock on the repository
-        let all = repository.ancestry().await?.err_into().collect::<Vec<_>>().await;
-        Ok(futures::stream::iter(all))
+        // the stream doesn't hold a lock on the repository
+        let ancestry = self.repository.read().await.ancestry().await?;
+        Ok(ancestry.err_into())
     }
 
     pub async fn change_set_bytes(&self) -> StoreResult<Vec<u8>> {
-        Ok(self.repository.read().await.change_set_bytes()?)
+        Ok(self.repository.read().await.change_set_bytes().await?)
     }
 
     pub async fn empty(&self) -> StoreResult<bool> {
@@ -563,6 +715,10 @@ impl Store {
     ///
     /// The outer [`StoreResult`] is used to flag a global failure and it could be [`StoreError::PartialValuesPanic`].
     ///
+    /// Reads of chunks stored in the same object, or in the same virtual file, are merged into
+    /// fewer requests when they are at most `get_partial_values_coalesce_gap_bytes` apart.
+    /// Merged requests are at most `get_partial_values_coalesce_max_bytes` long.
+    ///
     /// Currently this function is using concurrency but not parallelism. To limit the number of
     /// concurrent tasks use the Store config value `get_partial_values_concurrency`.
     pub async fn get_partial_values(
@@ -580,39 +736,71 @@ impl Store {
         // This [excellent post](https://without.boats/blog/the-scoped-task-trilemma/) explains why something like this is not currently achievable:
         // [Here](https://github.com/tokio-rs/tokio/issues/3162) is a a tokio thread explaining this cannot be done with current Rust.
         //
-        // The compromise we found is using [`Stream::for_each_concurrent`]. This achieves the
+        // The compromise we found is using [`StreamExt::buffer_unordered`]. This achieves the
         // borrowing and the concurrency but not the parallelism. So all the concurrent tasks will
         // execute on the same thread. This is not as bad as it sounds, since most of this will be
         // IO bound.
 
-        let stream = futures::stream::iter(key_ranges);
-        let results = Arc::new(Mutex::new(Vec::new()));
-        let num_keys = AtomicUsize::new(0);
-        stream
-            .for_each_concurrent(
-                self.config.get_partial_values_concurrency as usize,
-                |(key, range)| {
-                    let index = num_keys.fetch_add(1, Ordering::Release);
-                    let results = Arc::clone(&results);
-                    async move {
-                        let value = self.get(&key, &range).await;
-                        if let Ok(mut results) = results.lock() {
-                            if index >= results.len() {
-                                results.resize_with(index + 1, || None);
-                            }
-                            results[index] = Some(value);
-                        }
-                    }
-                },
-            )
+        let concurrency = self.config.get_partial_values_concurrency as usize;
+        let max_gap_bytes = self
+            .config
+            .get_partial_values_coalesce_gap_bytes
+            .unwrap_or(DEFAULT_COALESCE_GAP_BYTES);
+        let max_coalesced_bytes = self
+            .config
+            .get_partial_values_coalesce_max_bytes
+            .unwrap_or(DEFAULT_COALESCE_MAX_BYTES);
+        let repo = self.repository.read().await;
+
+        // chunk reads go to the repository, that merges nearby reads of the same object
+        let mut chunk_keys = Vec::new();
+        let mut chunk_reads = Vec::new();
+        let mut other_reads = Vec::new();
+        for (index, (key, range)) in key_ranges.into_iter().enumerate() {
+            match Key::parse(&key) {
+                Ok(Key::Chunk { node_path, coords }) => {
+                    chunk_keys.push((index, key));
+                    chunk_reads.push((node_path, coords, range));
+                }
+                _ => other_reads.push((index, key, range)),
+            }
+        }
+
+        let mut results: Vec<Option<StoreResult<Bytes>>> =
+            (0..chunk_keys.len() + other_reads.len()).map(|_| None).collect();
+        let planned = repo
+            .plan_chunks_coalesced(&chunk_reads, max_gap_bytes, max_coalesced_bytes)
             .await;
 
-        let results = Arc::into_inner(results)
-            .ok_or(StoreError::PartialValuesPanic)?
-            .into_inner()
-            .map_err(|_| StoreError::PartialValuesPanic)?;
+        let others: Vec<_> = futures::stream::iter(other_reads)
+            .map(|(index, key, range)| {
+                let repo = repo.deref();
+                async move { (index, get_key(&key, &range, repo).await) }
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        for (index, value) in others {
+            results[index] = Some(value);
+        }
+
+        // the chunk references are resolved, don't block writers while fetching
+        drop(repo);
+        let chunks = planned.fetch(concurrency).await;
+        for (((index, key), (path, coords, _)), chunk) in
+            chunk_keys.into_iter().zip(chunk_reads).zip(chunks)
+        {
+            results[index] = Some(match chunk {
+                Ok(Some(bytes)) => Ok(bytes),
+                Ok(None) => Err(StoreError::NotFound(KeyNotFoundError::ChunkNotFound {
+                    key,
+                    path,
+                    coords,
+                })),
+                Err(err) => Err(err.into()),
+            });
+        }
 
-        debug_assert!(results.len() == num_keys.into_inner());
         let res: Option<Vec<_>> = results.into_iter().collect();
         res.ok_or(StoreError::PartialValuesPanic)
     }
@@ -723,6 +911,39 @@ impl Store {
         }
     }
 
+    /// Set many virtual chunk references of the array at `array_path`, like `group/array`
+    ///
+    /// All references are validated before any is set, and the repository is locked
+    /// only once. Returns the number of references set.
+    pub async fn set_virtual_refs(
+        &mut self,
+        array_path: &str,
+        refs: impl IntoIterator<Item = (ChunkIndices, VirtualChunkRef)>,
+    ) -> StoreResult<usize> {
+        if self.mode == AccessMode::ReadOnly {
+            return Err(StoreError::ReadOnly);
+        }
+
+        let path = format!("/{}", array_path.trim_matches('/'))
+            .try_into()
+            .map_err(|_| StoreError::InvalidKey { key: array_path.to_string() })?;
+        Ok(self.repository.write().await.set_virtual_refs(path, refs).await?)
+    }
+
+    /// Set the url prefix for the relative virtual chunk locations named `name`
+    pub async fn set_virtual_prefix(
+        &mut self,
+        name: &str,
+        prefix: &str,
+    ) -> StoreResult<()> {
+        if self.mode == AccessMode::ReadOnly {
+            return Err(StoreError::ReadOnly);
+        }
+
+        self.repository.write().await.set_virtual_prefix(name, prefix)?;
+        Ok(())
+    }
+
     pub async fn delete(&self, key: &str) -> StoreResult<()> {
         if self.mode == AccessMode::ReadOnly {
             return Err(StoreError::ReadOnly);
@@ -986,12 +1207,7 @@ async fn get_metadata(
     let node = repo.get_node(path).await.map_err(|_| {
         StoreError::NotFound(KeyNotFoundError::NodeNotFound { path: path.clone() })
     })?;
-    let user_attributes = match node.user_attributes {
-        None => None,
-        Some(UserAttributesSnapshot::Inline(atts)) => Some(atts),
-        // FIXME: implement
-        Some(UserAttributesSnapshot::Ref(_)) => todo!(),
-    };
+    let user_attributes = repo.load_user_attributes(node.user_attributes).await?;
     let full_metadata = match node.node_data {
         NodeData::Group => {
             Ok::<Bytes, StoreError>(GroupMetadata::new(user_attributes).to_bytes())
@@ -1456,9 +1672,13 @@ mod tests {
 
     use std::borrow::BorrowMut;
 
-    use crate::storage::s3::{S3Credentials, StaticS3Credentials};
+    use crate::storage::{
+        object_store::{AzureCredentials, GcsCredentials},
+        s3::{S3Credentials, StaticS3Credentials},
+    };
 
     use super::*;
+    use crate::format::manifest::VirtualChunkLocation;
     use pretty_assertions::assert_eq;
 
     async fn all_keys(store: &Store) -> Result<Vec<String>, Box<dyn std::error::Error>> {
@@ -2312,6 +2532,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_set_virtual_refs() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::TempDir::new()?;
+        let file = dir.path().join("data.bin");
+        std::fs::write(&file, (0u8..40).collect::<Vec<_>>())?;
+        let location = VirtualChunkLocation::from_absolute_path(&format!(
+            "file://{}",
+            file.display()
+        ))?;
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let mut store = Store::from_repository(
+            ds,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            None,
+        );
+        store
+            .set(
+                "zarr.json",
+                Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#),
+            )
+            .await?;
+        let zarr_meta = Bytes::copy_from_slice(br#"{"zarr_format":3,"node_type":"array","attributes":{"foo":42},"shape":[20],"data_type":"int32","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[1]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[{"name":"mycodec","configuration":{"foo":42}}],"storage_transformers":[{"name":"mytransformer","configuration":{"bar":43}}],"dimension_names":["x"]}"#);
+        store.set("array/zarr.json", zarr_meta).await?;
+
+        let refs = (0..10).map(|idx| {
+            (
+                ChunkIndices(vec![idx]),
+                VirtualChunkRef {
+                    location: location.clone(),
+                    offset: 4 * idx as u64,
+                    length: 4,
+                    etag: None,
+                    last_modified: None,
+                },
+            )
+        });
+        assert_eq!(store.set_virtual_refs("array", refs).await?, 10);
+        for idx in 0..10u8 {
+            assert_eq!(
+                store.get(&format!("array/c/{idx}"), &ByteRange::ALL).await?,
+                Bytes::from((4 * idx..4 * idx + 4).collect::<Vec<_>>())
+            );
+        }
+
+        assert!(matches!(
+            store.set_virtual_refs("missing", iter::empty()).await,
+            Err(StoreError::RepositoryError(RepositoryError::NodeNotFound { .. }))
+        ));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_commit_and_checkout() -> Result<(), Box<dyn std::error::Error>> {
         let storage: Arc<dyn Storage + Send + Sync> =
@@ -2581,8 +2856,20 @@ mod tests {
                 unsafe_overwrite_refs: Some(true),
                 change_set_bytes: None,
                 virtual_ref_config: None,
+                virtual_http_config: None,
+                commit_rebase_attempts: None,
+                caching: None,
+                promote_partial_reads_max_bytes: None,
+                virtual_ref_integrity: None,
             },
-            config: Some(StoreOptions { get_partial_values_concurrency: 100 }),
+            config: Some(StoreOptions {
+                get_partial_values_concurrency: 100,
+                retry: None,
+                disk_cache: None,
+                caching: None,
+                get_partial_values_coalesce_gap_bytes: None,
+                get_partial_values_coalesce_max_bytes: None,
+            }),
         };
 
         let json = r#"
@@ -2599,6 +2886,49 @@ mod tests {
         "#;
         assert_eq!(expected, serde_json::from_str(json)?);
 
+        let json = r#"
+            {"storage": {"type": "local_filesystem", "root":"/tmp/test"},
+             "repository": {
+                "version": {"snapshot_id":"000G40R40M30E209185G"},
+                "inline_chunk_threshold_bytes":128,
+                "unsafe_overwrite_refs":true
+             },
+             "config": {
+                "get_partial_values_concurrency": 100,
+                "retry": {"max_attempts": 3, "initial_backoff_ms": 50, "max_backoff_ms": 1000},
+                "disk_cache": {"directory": "/tmp/cache", "max_bytes": 1000000},
+                "caching": {"manifests_bytes": 1000, "chunks_bytes": 2000},
+                "get_partial_values_coalesce_gap_bytes": 4096,
+                "get_partial_values_coalesce_max_bytes": 65536
+             }
+            }
+        "#;
+        assert_eq!(
+            ConsolidatedStore {
+                config: Some(StoreOptions {
+                    get_partial_values_concurrency: 100,
+                    retry: Some(RetryConfig {
+                        max_attempts: 3,
+                        initial_backoff_ms: 50,
+                        max_backoff_ms: 1000
+                    }),
+                    disk_cache: Some(DiskCacheConfig {
+                        directory: "/tmp/cache".into(),
+                        max_bytes: 1_000_000,
+                    }),
+                    caching: Some(CachingConfig {
+                        manifests_bytes: 1000,
+                        chunks_bytes: 2000,
+                        ..CachingConfig::default()
+                    }),
+                    get_partial_values_coalesce_gap_bytes: Some(4096),
+                    get_partial_values_coalesce_max_bytes: Some(65536),
+                }),
+                ..expected.clone()
+            },
+            serde_json::from_str(json)?
+        );
+
         let json = r#"
             {"storage":
                 {"type": "local_filesystem", "root":"/tmp/test"},
@@ -2616,6 +2946,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 config: None,
                 ..expected.clone()
@@ -2637,6 +2972,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 config: None,
                 ..expected.clone()
@@ -2657,6 +2997,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 storage: StorageConfig::InMemory { prefix: Some("prefix".to_string()) },
                 config: None,
@@ -2677,6 +3022,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 storage: StorageConfig::InMemory { prefix: None },
                 config: None,
@@ -2697,6 +3047,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 storage: StorageConfig::S3ObjectStore {
                     bucket: String::from("test"),
@@ -2732,6 +3087,11 @@ mod tests {
                     unsafe_overwrite_refs: None,
                     change_set_bytes: None,
                     virtual_ref_config: None,
+                    virtual_http_config: None,
+                    commit_rebase_attempts: None,
+                    caching: None,
+                    promote_partial_reads_max_bytes: None,
+                    virtual_ref_integrity: None,
                 },
                 storage: StorageConfig::S3ObjectStore {
                     bucket: String::from("test"),
@@ -2752,6 +3112,124 @@ mod tests {
             serde_json::from_str(json)?
         );
 
+        let json = r#"
+        {"storage":{
+             "type": "gcs",
+             "bucket":"test",
+             "prefix":"root",
+             "credentials":{"type":"anonymous"},
+             "endpoint":"http://localhost:4443",
+             "allow_http": true
+         },
+         "repository": {}
+        }
+    "#;
+        assert_eq!(
+            ConsolidatedStore {
+                repository: RepositoryConfig::default(),
+                storage: StorageConfig::GcsObjectStore {
+                    bucket: String::from("test"),
+                    prefix: String::from("root"),
+                    config: Some(GcsConfig {
+                        endpoint: Some(String::from("http://localhost:4443")),
+                        credentials: GcsCredentials::Anonymous,
+                        allow_http: true,
+                    })
+                },
+                config: None,
+            },
+            serde_json::from_str(json)?
+        );
+
+        let json = r#"
+        {"storage":{
+             "type": "azure",
+             "account":"myaccount",
+             "container":"test",
+             "prefix":"root",
+             "credentials":{"type":"sas_token", "token":"sv=2022-11-02&sig=abc"},
+             "allow_http": false,
+             "use_emulator": false
+         },
+         "repository": {}
+        }
+    "#;
+        assert_eq!(
+            ConsolidatedStore {
+                repository: RepositoryConfig::default(),
+                storage: StorageConfig::AzureObjectStore {
+                    account: String::from("myaccount"),
+                    container: String::from("test"),
+                    prefix: String::from("root"),
+                    config: Some(AzureConfig {
+                        endpoint: None,
+                        credentials: AzureCredentials::SasToken {
+                            token: String::from("sv=2022-11-02&sig=abc")
+                        },
+                        allow_http: false,
+                        use_emulator: false,
+                    })
+                },
+                config: None,
+            },
+            serde_json::from_str(json)?
+        );
+
+        let json = r#"
+        {"storage":{
+             "type": "object_store_url",
+             "url":"s3://bucket/root",
+             "options":{"aws_region":"us-west-2", "aws_conditional_put":"etag"}
+         },
+         "repository": {}
+        }
+    "#;
+        assert_eq!(
+            ConsolidatedStore {
+                repository: RepositoryConfig::default(),
+                storage: StorageConfig::ObjectStoreUrl {
+                    url: String::from("s3://bucket/root"),
+                    options: HashMap::from([
+                        ("aws_region".to_string(), "us-west-2".to_string()),
+                        ("aws_conditional_put".to_string(), "etag".to_string()),
+                    ]),
+                },
+                config: None,
+            },
+            serde_json::from_str(json)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_options_caching_precedence() -> Result<(), Box<dyn std::error::Error>> {
+        let json = r#"
+            {"storage": {"type": "in_memory"},
+             "repository": {"caching": {"manifests_bytes": 1000}}
+            }
+        "#;
+        let consolidated: ConsolidatedStore = serde_json::from_str(json)?;
+        let repo_caching =
+            CachingConfig { manifests_bytes: 1000, ..CachingConfig::default() };
+        assert_eq!(consolidated.repository.caching, Some(repo_caching.clone()));
+        assert_eq!(consolidated.store_options().caching, Some(repo_caching));
+
+        // the store options take precedence over the repository config
+        let json = r#"
+            {"storage": {"type": "in_memory"},
+             "repository": {"caching": {"manifests_bytes": 1000}},
+             "config": {
+                "get_partial_values_concurrency": 10,
+                "caching": {"chunks_bytes": 2000}
+             }
+            }
+        "#;
+        let consolidated: ConsolidatedStore = serde_json::from_str(json)?;
+        assert_eq!(
+            consolidated.store_options().caching,
+            Some(CachingConfig { chunks_bytes: 2000, ..CachingConfig::default() })
+        );
         Ok(())
     }
 }
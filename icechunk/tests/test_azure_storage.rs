@@ -0,0 +1,111 @@
+//! Tests against an Azurite emulator listening on localhost:10000, see compose.yaml
+use std::{collections::HashSet, sync::Arc};
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures::TryStreamExt;
+use icechunk::{
+    format::{snapshot::Snapshot, ByteRange, ChunkId, SnapshotId},
+    refs::{create_tag, fetch_branch_tip, fetch_tag, update_branch, RefError},
+    storage::{
+        object_store::{AzureConfig, AzureCredentials},
+        StorageResult,
+    },
+    ObjectStorage, Storage,
+};
+use pretty_assertions::assert_eq;
+
+fn mk_storage() -> StorageResult<ObjectStorage> {
+    ObjectStorage::new_azure_blob_store(
+        "devstoreaccount1",
+        "testcontainer",
+        "test_azure_storage__".to_string() + Utc::now().to_rfc3339().as_str(),
+        Some(&AzureConfig {
+            endpoint: None,
+            credentials: AzureCredentials::FromEnv,
+            allow_http: true,
+            use_emulator: true,
+        }),
+    )
+}
+
+#[tokio::test]
+pub async fn test_snapshot_write_read() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage()?;
+    let id = SnapshotId::random();
+    let snapshot = Arc::new(Snapshot::empty());
+    storage.write_snapshot(id.clone(), snapshot.clone()).await?;
+    let back = storage.fetch_snapshot(&id).await?;
+    assert_eq!(snapshot, back);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_chunk_write_read() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage()?;
+    let id = ChunkId::random();
+    let bytes = Bytes::from_static(b"hello");
+    storage.write_chunk(id.clone(), bytes.clone()).await?;
+    let back = storage.fetch_chunk(&id, &ByteRange::ALL).await?;
+    assert_eq!(bytes, back);
+
+    let back =
+        storage.fetch_chunk(&id, &ByteRange::from_offset_with_length(1, 2)).await?;
+    assert_eq!(Bytes::from_static(b"el"), back);
+
+    let back = storage.fetch_chunk(&id, &ByteRange::from_offset(1)).await?;
+    assert_eq!(Bytes::from_static(b"ello"), back);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_create_existing_tag() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage()?;
+    let id = SnapshotId::random();
+    create_tag(&storage, "mytag", id.clone(), false).await?;
+    assert_eq!(fetch_tag(&storage, "mytag").await?.snapshot, id);
+
+    let res = create_tag(&storage, "mytag", SnapshotId::random(), false).await;
+    assert!(matches!(res, Err(RefError::TagAlreadyExists(r)) if r == "mytag"));
+    assert_eq!(fetch_tag(&storage, "mytag").await?.snapshot, id);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_branch_update_conflict() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage()?;
+    let id1 = SnapshotId::random();
+    let id2 = SnapshotId::random();
+    let id3 = SnapshotId::random();
+
+    update_branch(&storage, "some-branch", id1.clone(), None, false).await?;
+    let res =
+        update_branch(&storage, "some-branch", id2.clone(), Some(&id1), false).await?;
+    assert_eq!(res.0, 1);
+
+    // a writer that didn't see the previous update can't overwrite it
+    let res = update_branch(&storage, "some-branch", id3, Some(&id1), false).await;
+    assert!(matches!(res, Err(RefError::Conflict { .. })));
+    assert_eq!(fetch_branch_tip(&storage, "some-branch").await?.snapshot, id2);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_list_and_delete_chunks() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage()?;
+    let id1 = ChunkId::random();
+    let id2 = ChunkId::random();
+    storage.write_chunk(id1.clone(), Bytes::from_static(b"first")).await?;
+    storage.write_chunk(id2.clone(), Bytes::from_static(b"second")).await?;
+
+    let listed: Vec<_> = storage.list_chunks().await?.try_collect().await?;
+    assert_eq!(
+        listed.iter().map(|info| info.id.clone()).collect::<HashSet<_>>(),
+        HashSet::from_iter([id1.clone(), id2.clone()])
+    );
+
+    assert_eq!(storage.delete_chunks(vec![id1]).await?, 1);
+    let listed: Vec<_> = storage.list_chunks().await?.try_collect().await?;
+    assert_eq!(listed.into_iter().map(|info| info.id).collect::<Vec<_>>(), vec![id2]);
+    Ok(())
+}
@@ -1,7 +1,14 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use bytes::Bytes;
 use chrono::Utc;
+use futures::TryStreamExt;
 use icechunk::{
     format::{
         manifest::Manifest, snapshot::Snapshot, ByteRange, ChunkId, ManifestId,
@@ -12,11 +19,12 @@ use icechunk::{
     },
     storage::{
         s3::{S3Config, S3Credentials, S3Storage, StaticS3Credentials},
-        StorageResult,
+        MultipartConfig, StorageResult,
     },
     Storage,
 };
 use pretty_assertions::assert_eq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 async fn mk_storage() -> StorageResult<S3Storage> {
     S3Storage::new_s3_store(
@@ -82,6 +90,29 @@ pub async fn test_chunk_write_read() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+pub async fn test_multipart_chunk_write_read() -> Result<(), Box<dyn std::error::Error>> {
+    // S3 requires all parts but the last one to be at least 5MiB
+    let part_size = 5 * 1024 * 1024;
+    let storage = mk_storage().await?.with_multipart_config(MultipartConfig {
+        threshold_bytes: part_size,
+        part_size_bytes: part_size,
+        max_concurrent_parts: 2,
+    });
+    let id = ChunkId::random();
+    let bytes: Bytes =
+        (0..(2 * part_size + 10)).map(|i| (i % 251) as u8).collect::<Vec<_>>().into();
+    storage.write_chunk(id.clone(), bytes.clone()).await?;
+    let back = storage.fetch_chunk(&id, &ByteRange::ALL).await?;
+    assert_eq!(bytes, back);
+
+    let start = part_size - 5;
+    let back =
+        storage.fetch_chunk(&id, &ByteRange::from_offset_with_length(start, 10)).await?;
+    assert_eq!(bytes.slice(start as usize..start as usize + 10), back);
+    Ok(())
+}
+
 #[tokio::test]
 pub async fn test_tag_write_get() -> Result<(), Box<dyn std::error::Error>> {
     let storage = mk_storage().await?;
@@ -188,3 +219,66 @@ pub async fn test_ref_names() -> Result<(), Box<dyn std::error::Error>> {
     );
     Ok(())
 }
+
+#[tokio::test]
+pub async fn test_list_and_delete_chunks() -> Result<(), Box<dyn std::error::Error>> {
+    let storage = mk_storage().await?;
+    let id1 = ChunkId::random();
+    let id2 = ChunkId::random();
+    let id3 = ChunkId::random();
+    storage.write_chunk(id1.clone(), Bytes::from_static(b"first")).await?;
+    storage.write_chunk(id2.clone(), Bytes::from_static(b"second")).await?;
+    storage.write_chunk(id3.clone(), Bytes::from_static(b"third")).await?;
+
+    let listed: Vec<_> = storage.list_chunks().await?.try_collect().await?;
+    assert_eq!(
+        listed.iter().map(|info| info.id.clone()).collect::<HashSet<_>>(),
+        HashSet::from_iter([id1.clone(), id2.clone(), id3.clone()])
+    );
+    assert_eq!(listed.iter().map(|info| info.size_bytes).sum::<u64>(), 16);
+
+    assert_eq!(storage.delete_chunks(vec![id1, id3]).await?, 2);
+    let listed: Vec<_> = storage.list_chunks().await?.try_collect().await?;
+    assert_eq!(listed.into_iter().map(|info| info.id).collect::<Vec<_>>(), vec![id2]);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_sdk_does_not_retry() -> Result<(), Box<dyn std::error::Error>> {
+    // a server that fails every request, counting them
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests_c = Arc::clone(&requests);
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            let _ = socket.read(&mut buf).await;
+            requests_c.fetch_add(1, Ordering::SeqCst);
+            let response = "HTTP/1.1 503 Service Unavailable\r\n\
+                            content-length: 0\r\nconnection: close\r\n\r\n";
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let storage = S3Storage::new_s3_store(
+        "testbucket",
+        "prefix",
+        Some(&S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(format!("http://{addr}")),
+            credentials: S3Credentials::Static(StaticS3Credentials {
+                access_key_id: "minio123".into(),
+                secret_access_key: "minio123".into(),
+                session_token: None,
+            }),
+            allow_http: true,
+        }),
+    )
+    .await?;
+    // conditional writes are retried by `RetryingStorage` only, which checks if they happened
+    let res = storage.write_ref("branch.main/0", false, Bytes::from_static(b"{}")).await;
+    assert!(matches!(res, Err(err) if err.is_retryable()));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    Ok(())
+}
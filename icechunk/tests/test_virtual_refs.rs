@@ -7,18 +7,26 @@ mod tests {
             ByteRange, ChunkId, ChunkIndices, Path,
         },
         metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::Ref,
         repository::{get_chunk, ChunkPayload, ZarrArrayMetadata},
         storage::{
             s3::{mk_client, S3Config, S3Credentials, S3Storage, StaticS3Credentials},
-            virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+            virtual_ref::{HttpConfig, ObjectStoreVirtualChunkResolverConfig},
             ObjectStorage,
         },
         zarr::AccessMode,
         Repository, Storage, Store,
     };
-    use std::{error::Error, num::NonZeroU64};
-    use std::{path::Path as StdPath, sync::Arc};
+    use std::{collections::HashMap, error::Error, net::SocketAddr, num::NonZeroU64};
+    use std::{
+        path::Path as StdPath,
+        sync::{Arc, Mutex},
+    };
     use tempfile::TempDir;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
 
     use bytes::Bytes;
     use object_store::{
@@ -158,6 +166,8 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            etag: None,
+            last_modified: None,
         });
         let payload2 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -166,6 +176,8 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            etag: None,
+            last_modified: None,
         });
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -268,6 +280,8 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            etag: None,
+            last_modified: None,
         });
         let payload2 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -276,6 +290,8 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            etag: None,
+            last_modified: None,
         });
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -388,6 +404,8 @@ mod tests {
             ))?,
             offset: 0,
             length: 5,
+            etag: None,
+            last_modified: None,
         };
         let ref2 = VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -396,6 +414,8 @@ mod tests {
             ))?,
             offset: 1,
             length: 5,
+            etag: None,
+            last_modified: None,
         };
         store.set_virtual_ref("array/c/0/0/0", ref1).await?;
         store.set_virtual_ref("array/c/0/0/1", ref2).await?;
@@ -438,6 +458,8 @@ mod tests {
             )?,
             offset: 119339,
             length: 80,
+            etag: None,
+            last_modified: None,
         };
 
         store.set_virtual_ref("depth/c/0", ref2).await?;
@@ -453,4 +475,182 @@ mod tests {
 
         Ok(())
     }
+
+    /// Serve `data` at `/data/file.nc` over HTTP, answering range requests. Requests need
+    /// an `Authorization: Bearer secret` header, and `/old/file.nc` redirects to the data.
+    /// Returns the address and the `Authorization` headers received so far.
+    async fn serve_http(data: Bytes) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let authorizations = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&authorizations);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle_http(socket, data.clone(), Arc::clone(&received)));
+            }
+        });
+        (addr, authorizations)
+    }
+
+    async fn handle_http(
+        socket: TcpStream,
+        data: Bytes,
+        authorizations: Arc<Mutex<Vec<String>>>,
+    ) {
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(request_line)) = lines.next_line().await {
+            let path = request_line.split(' ').nth(1).unwrap_or_default().to_string();
+            let mut headers = HashMap::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                }
+            }
+            if let Some(authorization) = headers.get("authorization") {
+                authorizations.lock().unwrap().push(authorization.clone());
+            }
+
+            let (status, extra_headers, body) = if path == "/old/file.nc" {
+                ("302 Found", "location: /data/file.nc\r\n".to_string(), Bytes::new())
+            } else if path.starts_with("/signed/")
+                && path != "/signed/file.nc?sig=abc%2Fd&expires=10"
+            {
+                ("403 Forbidden", String::new(), Bytes::new())
+            } else if !path.starts_with("/signed/")
+                && headers.get("authorization").map(String::as_str)
+                    != Some("Bearer secret")
+            {
+                // signed urls are authorized by their query instead of the headers
+                ("401 Unauthorized", String::new(), Bytes::new())
+            } else if path != "/data/file.nc" && !path.starts_with("/signed/") {
+                ("404 Not Found", String::new(), Bytes::new())
+            } else if let Some(range) = headers.get("range") {
+                let (start, end) =
+                    range.trim_start_matches("bytes=").split_once('-').unwrap();
+                let start: usize = start.parse().unwrap();
+                let end: usize =
+                    end.parse().map(|end: usize| end + 1).unwrap_or(data.len());
+                let content_range = format!(
+                    "content-range: bytes {}-{}/{}\r\n",
+                    start,
+                    end - 1,
+                    data.len()
+                );
+                ("206 Partial Content", content_range, data.slice(start..end))
+            } else {
+                ("200 OK", String::new(), data.clone())
+            };
+
+            let head = format!(
+                "HTTP/1.1 {status}\r\n{extra_headers}content-length: {}\r\n\r\n",
+                body.len()
+            );
+            if write.write_all(head.as_bytes()).await.is_err()
+                || write.write_all(&body).await.is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repository_with_http_virtual_refs() -> Result<(), Box<dyn Error>> {
+        let data = Bytes::copy_from_slice(b"0123456789abcdefghij");
+        let (addr, _) = serve_http(data.clone()).await;
+        // a second origin, without configured headers
+        let (other_addr, other_authorizations) = serve_http(data.clone()).await;
+
+        let storage: Arc<dyn Storage + Send + Sync> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let http_config = HttpConfig {
+            headers: [(
+                format!("http://{addr}"),
+                [("Authorization".to_string(), "Bearer secret".to_string())].into(),
+            )]
+            .into(),
+        };
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_virtual_http_config(http_config)
+            .build();
+
+        let zarr_meta = ZarrArrayMetadata {
+            shape: vec![5],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+        };
+        let array_path: Path = "/array".try_into().unwrap();
+        ds.add_array(array_path.clone(), zarr_meta).await?;
+        for (i, (addr, file, offset)) in [
+            (addr, "data/file.nc", 2),
+            (addr, "old/file.nc", 10),
+            (addr, "data/missing.nc", 0),
+            (other_addr, "data/file.nc", 2),
+            (other_addr, "signed/file.nc?sig=abc%2Fd&expires=10", 4),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let location = VirtualChunkLocation::from_absolute_path(&format!(
+                "http://{addr}/{file}"
+            ))?;
+            let payload = ChunkPayload::Virtual(VirtualChunkRef {
+                location,
+                offset,
+                length: 5,
+                etag: None,
+                last_modified: None,
+            });
+            ds.set_chunk_ref(
+                array_path.clone(),
+                ChunkIndices(vec![i as u32]),
+                Some(payload),
+            )
+            .await?;
+        }
+
+        let read = |i: u32, range: ByteRange| {
+            let ds = &ds;
+            let array_path = &array_path;
+            async move {
+                get_chunk(
+                    ds.get_chunk_reader(array_path, &ChunkIndices(vec![i]), &range)
+                        .await?,
+                )
+                .await
+            }
+        };
+        assert_eq!(read(0, ByteRange::ALL).await?, Some(Bytes::from("23456")));
+        assert_eq!(read(0, ByteRange::bounded(1, 3)).await?, Some(Bytes::from("34")));
+        // redirects are followed
+        assert_eq!(read(1, ByteRange::ALL).await?, Some(Bytes::from("abcde")));
+        assert_eq!(read(1, ByteRange::Last(2)).await?, Some(Bytes::from("de")));
+        assert!(read(2, ByteRange::ALL).await.is_err());
+        // headers are only sent to the configured origin
+        assert!(read(3, ByteRange::ALL).await.is_err());
+        assert!(other_authorizations.lock().unwrap().is_empty());
+        // the query of the url is sent with the request
+        assert_eq!(read(4, ByteRange::ALL).await?, Some(Bytes::from("45678")));
+
+        // without the configured headers the server rejects the requests
+        let snapshot = ds.commit(Ref::DEFAULT_BRANCH, "virtual refs", None).await?;
+        let ds = Repository::update(Arc::clone(&storage), snapshot).build();
+        let ds = &ds;
+        assert!(get_chunk(
+            ds.get_chunk_reader(&array_path, &ChunkIndices(vec![0]), &ByteRange::ALL)
+                .await?
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
 }